@@ -1,7 +1,8 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use log::{debug, error, info, trace, warn};
 
 use std::{
+    collections::HashMap,
     env,
     error::Error,
     future::Future,
@@ -19,7 +20,7 @@ pub mod development {
     tonic::include_proto!("development");
 }
 use development::development_client::DevelopmentClient;
-use development::{DeployReply, DeployRequest, EchoReply, EchoRequest, Empty};
+use development::{DeployReply, DeployRequest, EchoReply, EchoRequest, Empty, Route};
 
 #[derive(Debug, Error)]
 enum StartError {
@@ -64,6 +65,8 @@ enum DeploymentError {
     ClientError { cause: String },
     #[error("deployment error. Cause: {cause:?}")]
     DeploymentError { cause: String },
+    #[error("config file error. Path: {path:?} Cause: {cause:?}")]
+    ConfigFileError { path: String, cause: String },
     #[error("server error")]
     ServerError,
 }
@@ -82,6 +85,117 @@ struct DevelopmentServerClient {
     process: Child,
 }
 
+/// Deploy time settings for a component
+#[derive(Debug, Args)]
+struct DeployOptions {
+    /// Name the component's key value store, database, containers and logs are kept under,
+    /// made of `a-z`, `0-9`, `_` and `-`. Redeploying with the same name keeps its storage.
+    /// Default: derived from the route and host
+    #[clap(long)]
+    name: Option<String>,
+
+    /// The path prefix the component is served on. e.g. `/api/*`
+    /// Default: /
+    #[clap(long, default_value = "/")]
+    route: String,
+
+    /// Only serve the component for requests with this Host header.
+    /// Default: any host
+    #[clap(long)]
+    host: Option<String>,
+
+    /// Time limit in milliseconds for a single invocation of the component.
+    /// Default: the development server's limit
+    #[clap(long)]
+    timeout_ms: Option<u32>,
+
+    /// Max linear memory in bytes the component may grow to.
+    /// Default: the development server's limit
+    #[clap(long)]
+    max_memory_bytes: Option<u64>,
+
+    /// Hosts the component may reach, e.g. `api.example.com:443` or `*.example.com`.
+    /// Narrows the development server's allowlist, it can't widen it. Default: the server's allowlist
+    #[clap(long)]
+    egress_allow: Vec<String>,
+
+    /// Hosts the component may never reach, in addition to the development server's denylist
+    #[clap(long)]
+    egress_deny: Vec<String>,
+
+    /// Largest outbound response body in bytes buffered for the component.
+    /// Default: the development server's limit
+    #[clap(long)]
+    max_response_bytes: Option<u64>,
+
+    /// Environment variable the component sees, as `NAME=VALUE`. May be repeated
+    #[clap(long, value_parser = parse_key_value)]
+    env: Vec<(String, String)>,
+
+    /// Configuration value the component reads with `mycelia-alpha:config`, as `KEY=VALUE`.
+    /// Takes precedence over `--config-file`. May be repeated
+    #[clap(long, value_parser = parse_key_value)]
+    config: Vec<(String, String)>,
+
+    /// TOML file holding configuration values, each component reads the table named after it.
+    /// e.g. `[game]` followed by `difficulty = "hard"`
+    #[clap(long)]
+    config_file: Option<PathBuf>,
+
+    /// Name of a secret in the development server's secrets file the component may read.
+    /// May be repeated
+    #[clap(long = "secret")]
+    secrets: Vec<String>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", s)),
+    }
+}
+
+/// Reads the values in `component`'s table of a config file.
+/// A file without a table for the component gives it no values
+fn load_config_file(
+    path: &Path,
+    component: &str,
+) -> Result<HashMap<String, String>, DeploymentError> {
+    let error = |cause: String| DeploymentError::ConfigFileError {
+        path: path.display().to_string(),
+        cause,
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+    let file = contents
+        .parse::<toml::Table>()
+        .map_err(|e| error(e.to_string()))?;
+    let Some(section) = file.get(component) else {
+        return Ok(HashMap::new());
+    };
+    let section = section
+        .as_table()
+        .ok_or_else(|| error(format!("`{}` should be a table", component)))?;
+    section
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(_)
+                | toml::Value::Float(_)
+                | toml::Value::Boolean(_)
+                | toml::Value::Datetime(_) => value.to_string(),
+                toml::Value::Array(_) | toml::Value::Table(_) => {
+                    return Err(error(format!(
+                        "`{}.{}` should be a string, number or boolean",
+                        component, key
+                    )))
+                }
+            };
+            Ok((key.clone(), value))
+        })
+        .collect()
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Build the entire Mycelia project
@@ -134,6 +248,9 @@ enum Commands {
         #[clap(long)]
         component: String,
 
+        #[command(flatten)]
+        options: DeployOptions,
+
         /// The ip to listen on.
         /// Default: localhost
         #[clap(short, long, default_value = "127.0.0.1")]
@@ -192,8 +309,9 @@ async fn try_main() -> Result<(), DynError> {
             http_port,
             rpc_port,
             component,
+            options,
         } => {
-            deploy(ip, http_port, rpc_port, component).await;
+            deploy(ip, http_port, rpc_port, component, options).await;
         }
     }
 
@@ -521,9 +639,20 @@ async fn try_stop(ip: &str, rpc_port: &u16) -> Result<(), StopError> {
  * cargo run deploy --component=game
  *
  * This will take the file "./components/game.wasm" and deploy it.
+ *
+ * cargo run deploy --component=auth --route=/auth
+ *
+ * This deploys "./components/auth.wasm" alongside any other deployed components.
+ * Requests with a path starting with `/auth` will be handled by it.
  */
-async fn deploy(ip: &String, http_port: &u16, rpc_port: &u16, component: &String) {
-    if let Err(e) = try_deploy(ip, http_port, rpc_port, component).await {
+async fn deploy(
+    ip: &String,
+    http_port: &u16,
+    rpc_port: &u16,
+    component: &String,
+    options: &DeployOptions,
+) {
+    if let Err(e) = try_deploy(ip, http_port, rpc_port, component, options).await {
         error!("{}", e);
 
         std::process::exit(-1);
@@ -535,6 +664,7 @@ async fn try_deploy(
     http_port: &u16,
     rpc_port: &u16,
     component: &String,
+    options: &DeployOptions,
 ) -> Result<(), DeploymentError> {
     let path = project_root().join(format!("components/{}.wasm", component));
     if !path.exists() {
@@ -544,6 +674,12 @@ async fn try_deploy(
         });
     }
 
+    let mut config = match &options.config_file {
+        Some(config_file) => load_config_file(config_file, component)?,
+        None => HashMap::new(),
+    };
+    config.extend(options.config.iter().cloned());
+
     let server_state = poll_server_state(ip, rpc_port, &false).await;
     if server_state
         .as_ref()
@@ -560,6 +696,20 @@ async fn try_deploy(
             Ok(mut client) => {
                 let message = DeployRequest {
                     component_path: path.clone().display().to_string(),
+                    route: Some(Route {
+                        path_prefix: options.route.clone(),
+                        host: options.host.clone().unwrap_or_default(),
+                    }),
+                    timeout_ms: options.timeout_ms.unwrap_or_default(),
+                    max_memory_bytes: options.max_memory_bytes.unwrap_or_default(),
+                    egress_allow: options.egress_allow.clone(),
+                    egress_deny: options.egress_deny.clone(),
+                    max_response_bytes: options.max_response_bytes.unwrap_or_default(),
+                    env: options.env.iter().cloned().collect(),
+                    config,
+                    secrets: options.secrets.clone(),
+                    name: options.name.clone().unwrap_or_default(),
+                    ..Default::default()
                 };
                 let request = tonic::Request::new(message);
                 let response = client
@@ -572,7 +722,12 @@ async fn try_deploy(
                             stop(ip, rpc_port).await;
                         }
                         if message == "Ok".to_string() {
-                            info!("Deployed component to path: {}", path.display());
+                            info!(
+                                "Deployed component to path: {} on route: {}{}",
+                                path.display(),
+                                options.host.as_deref().unwrap_or(""),
+                                options.route
+                            );
                             return Ok(());
                         } else {
                             return Err(DeploymentError::DeploymentError { cause: message });
@@ -601,3 +756,89 @@ fn project_root() -> PathBuf {
         .expect("CARGO_MANIFEST_DIR not found")
         .to_path_buf()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn it_parses_key_values() {
+        assert_eq!(
+            parse_key_value("greeting=hello=world").unwrap(),
+            ("greeting".to_string(), "hello=world".to_string())
+        );
+        assert_eq!(
+            parse_key_value("empty=").unwrap(),
+            ("empty".to_string(), String::new())
+        );
+        for s in ["", "greeting", "=hello"] {
+            assert!(parse_key_value(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn it_loads_the_component_section() {
+        let file = config_file(
+            r#"
+            [game]
+            difficulty = "hard"
+            lives = 3
+            speed = 1.5
+            hardcore = false
+
+            [other]
+            difficulty = "easy"
+            "#,
+        );
+
+        let config = load_config_file(file.path(), "game").unwrap();
+        let expected = HashMap::from([
+            ("difficulty".to_string(), "hard".to_string()),
+            ("lives".to_string(), "3".to_string()),
+            ("speed".to_string(), "1.5".to_string()),
+            ("hardcore".to_string(), "false".to_string()),
+        ]);
+        assert_eq!(config, expected);
+
+        // A component without a section has no values
+        let config = load_config_file(file.path(), "missing").unwrap();
+        assert!(config.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_values_which_are_not_scalars() {
+        let file = config_file(
+            r#"
+            game = "not a table"
+
+            [levels]
+            order = ["easy", "hard"]
+
+            [nested]
+            inner = { difficulty = "hard" }
+            "#,
+        );
+
+        for component in ["game", "levels", "nested"] {
+            let result = load_config_file(file.path(), component);
+            assert!(
+                matches!(result, Err(DeploymentError::ConfigFileError { .. })),
+                "{}",
+                component
+            );
+        }
+
+        let missing = file.path().with_extension("missing");
+        let result = load_config_file(&missing, "game");
+        assert!(matches!(
+            result,
+            Err(DeploymentError::ConfigFileError { .. })
+        ));
+    }
+}
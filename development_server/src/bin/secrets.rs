@@ -0,0 +1,64 @@
+//! Manages the encrypted secrets file read by the development server.
+//! The passphrase is read from `MYCELIA_SECRETS_KEY` and secret values from stdin,
+//! so neither ends up in shell history.
+
+use std::io::Read;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use resource_providers::providers::secrets_file::{SecretsFile, SECRETS_KEY_ENV};
+use resource_providers::secrets::Secret;
+
+/// Mycelia Secrets
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// the secrets file, created when a secret is first set
+    #[arg(long, default_value = "mycelia.secrets.json")]
+    file: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// add or replace a secret, its value is read from stdin
+    Set { name: String },
+    /// remove a secret
+    Remove { name: String },
+    /// list the names of every secret
+    List,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let passphrase = std::env::var(SECRETS_KEY_ENV)
+        .map_err(|_| anyhow::anyhow!("{} must be set", SECRETS_KEY_ENV))?;
+
+    match args.command {
+        Command::Set { name } => {
+            let file = SecretsFile::open_or_create(&args.file, &passphrase)?;
+            let mut value = String::new();
+            std::io::stdin().read_to_string(&mut value)?;
+            let value = value.strip_suffix('\n').unwrap_or(&value);
+            let value = value.strip_suffix('\r').unwrap_or(value);
+            file.set(&name, &Secret::new(value.to_string()))?;
+            eprintln!("set secret {}", name);
+        }
+        Command::Remove { name } => {
+            let file = SecretsFile::open(&args.file, &passphrase)?;
+            if !file.remove(&name)? {
+                anyhow::bail!("no secret named {}", name);
+            }
+            eprintln!("removed secret {}", name);
+        }
+        Command::List => {
+            let file = SecretsFile::open(&args.file, &passphrase)?;
+            for name in file.names()? {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,18 +1,49 @@
-use std::{net::SocketAddr, path::Path, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use anyhow::anyhow;
 
 use function_service::{
-    service::{new_function_service_maker, FunctionComponentService},
-    types::{HttpRequest, HttpResponse},
+    headers::{request_id, to_header_map, REQUEST_ID_HEADER},
+    pool::PoolConfig,
+    service::{
+        new_function_service_maker, FunctionComponentService, InvocationError, InvocationLimits,
+        WasmComponent,
+    },
+    streams::body_channel,
+    types::{FunctionRequest, FunctionResponse, RequestHead},
 };
 use hyper::service::Service as HyperService;
 use hyper::{
-    body::HttpBody, server::conn::AddrStream, service::make_service_fn, Body, Request, Response,
-    Server,
+    body::HttpBody, header::HOST, server::conn::AddrStream, service::make_service_fn, Body,
+    Request, Response, Server, StatusCode,
 };
 use log::{info, trace, warn};
 
+use crate::router::{Route, RoutingTable};
+use resource_providers::http::{
+    map_headers, EgressPolicy, HostPattern, OutboundLimiter, OutboundLimits, Redacted,
+};
+use resource_providers::providers::blob_store_fs::FsBlobStore;
+use resource_providers::providers::kv_store_sled::SledKv;
+use resource_providers::providers::secrets_file::SecretsFile;
+use resource_providers::providers::sql_sqlite::SqliteDatabases;
+use wasmtime_components::limits::GuestLimits;
+use wasmtime_components::runtime::{GuestStorage, OutboundHttp};
+use wasmtime_components::runtime_view::GuestEnvironment;
+
 use tokio::{
     sync::{oneshot, Mutex},
     task::JoinHandle,
@@ -22,9 +53,27 @@ use tower::{
     BoxError, ServiceExt,
 };
 
-/// Map a hyper request to the mycelia::execution::HttpRequest type
-async fn map_request(req: Request<Body>) -> HttpRequest {
-    trace!("mapping incoming request {:#?}", &req);
+/// Storage name of the empty component served until something is deployed to the root route.
+/// Deployed names can't start with `_`, so no deploy shares it
+const BASE_STORAGE_NAME: &str = "_base";
+
+/// Longest storage name, leaving room in a file name for the database extension
+const MAX_STORAGE_NAME_BYTES: usize = 200;
+
+/// Ids given to requests which arrive without a usable `x-request-id`
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+fn new_request_id() -> String {
+    format!(
+        "{:x}-{}",
+        std::process::id(),
+        NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Map a hyper request to the mycelia::execution::FunctionRequest type.
+/// The body is forwarded to the component as it arrives
+fn map_request(req: Request<Body>) -> FunctionRequest {
     use function_service::types::Method;
 
     let method = match req.method().as_str() {
@@ -40,62 +89,95 @@ async fn map_request(req: Request<Body>) -> HttpRequest {
         v => Method::Other(v.into()),
     };
 
-    let headers: Vec<(String, String)> = req
-        .headers()
-        .iter()
-        .map(|(k, v)| {
-            (
-                k.to_string(),
-                v.to_str().unwrap_or("not supported").to_string(),
-            )
-        })
-        .collect();
+    let mut headers = map_headers(req.headers());
+    // Tags the guest's logs with the request, guests can read the id too
+    if request_id(&headers).is_none() {
+        headers.retain(|(name, _)| name != REQUEST_ID_HEADER);
+        headers.push((REQUEST_ID_HEADER.to_string(), new_request_id().into_bytes()));
+    }
     let uri = req.uri().to_string();
-
-    // body::to_bytes is unsafe to use alone, don't do this in prod.
-    let body = hyper::body::to_bytes(req.boxed()).await;
-    let body = body.map(|v| v.to_vec()).unwrap_or(vec![]);
-
-    HttpRequest {
-        method,
-        headers,
-        body,
+    trace!(
+        "mapping incoming request {} {} {:#?}",
+        req.method(),
         uri,
-    }
-}
-
-/// Map a mycelia::execution::HttpResponse type
-pub(crate) fn map_response(response: HttpResponse) -> Response<Body> {
-    trace!("mapping outgoing response {:#?}", response);
-    let mut builder = Response::builder().status(response.status);
+        Redacted(&headers)
+    );
 
-    let body = Body::from(response.body);
+    let (tx, rx) = body_channel();
+    let mut body = req.into_body();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map(|v| v.to_vec()).map_err(BoxError::from);
+            // The component stopped reading the body
+            if tx.send(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
 
-    for (k, v) in response.headers.into_iter() {
-        builder = builder.header(k, v);
+    FunctionRequest {
+        head: RequestHead {
+            method,
+            headers,
+            uri,
+        },
+        body: rx,
     }
-
-    builder.body(body).expect("Failed to create a response")
 }
 
-/// helper function to faciliate reading the
-/// async request body stream from sync context
+/// Map a mycelia::execution::FunctionResponse type.
+/// The body is streamed to the client as the component produces it
+pub(crate) fn map_response(response: FunctionResponse) -> Response<Body> {
+    trace!(
+        "mapping outgoing response {} {:#?}",
+        response.status,
+        Redacted(&response.headers)
+    );
+    let headers = match to_header_map(response.headers) {
+        Ok(headers) => headers,
+        Err(e) => {
+            warn!("component returned an invalid response. {}", e);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "function returned invalid response headers",
+            );
+        }
+    };
 
-// this is clearly abusing `map_request`
-// tower should have a way to "place a service"
-// in front of another one
-fn map_http_request(req: Request<Body>) -> HttpRequest {
-    tokio::task::block_in_place(|| {
-        let rt = tokio::runtime::Handle::current();
-        rt.block_on(map_request(req))
-    })
+    let (mut sender, body) = Body::channel();
+    let mut chunks = response.body;
+    tokio::spawn(async move {
+        while let Some(chunk) = chunks.recv().await {
+            match chunk {
+                Ok(chunk) => {
+                    // The client went away
+                    if sender.send_data(chunk.into()).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    // Don't let a cut short body look complete to the client
+                    warn!("aborting response body. {}", e);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut response = Response::builder()
+        .status(response.status)
+        .body(body)
+        .expect("Failed to create a response");
+    *response.headers_mut() = headers;
+    response
 }
 
 /// Decorates a FunctionComponentService with request response
 /// mappers to allow it to handle incoming hyper http Request / Response
 fn map_component_response(service: FunctionComponentService) -> HttpFunctionComponent {
     let binding = service
-        .map_request(map_http_request)
+        .map_request(map_request)
         .map_response(|resp| map_response(resp));
     binding.boxed()
 }
@@ -104,60 +186,248 @@ type HttpFunctionComponent = BoxService<Request<Body>, Response<Body>, BoxError>
 
 type HttpFunctionComponentMaker = BoxCloneService<(), HttpFunctionComponent, BoxError>;
 
+/// Server wide defaults applied to every deployed component
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConfig {
+    pub(crate) pool_config: PoolConfig,
+    pub(crate) invocation_limits: InvocationLimits,
+    pub(crate) guest_limits: GuestLimits,
+    /// Shared by every component for outbound requests
+    pub(crate) outbound_http: OutboundHttp,
+    /// Outbound requests every component is allowed to make
+    pub(crate) egress_policy: EgressPolicy,
+    /// Rate and concurrency limits on each component's outbound requests
+    pub(crate) outbound_limits: OutboundLimits,
+    /// Key value stores, each component is given its own namespace
+    pub(crate) kv: Option<SledKv>,
+    /// Sql databases, each component is given its own database file
+    pub(crate) sql: Option<SqliteDatabases>,
+    /// Object storage, each component is given its own root directory
+    pub(crate) blob: Option<FsBlobStore>,
+    /// Secrets components may be granted at deploy time
+    pub(crate) secrets: Option<SecretsFile>,
+}
+
+/// Per component settings provided at deploy time.
+/// Unset values fall back to the `ServerConfig`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ComponentOptions {
+    /// Identifies the component's storage and logs. Defaults to a name derived from its route
+    pub(crate) name: Option<String>,
+    pub(crate) invocation_timeout: Option<Duration>,
+    pub(crate) max_memory_bytes: Option<usize>,
+    pub(crate) max_table_elements: Option<u32>,
+    pub(crate) max_instances: Option<usize>,
+    /// Narrows the server's egress allowlist when not empty
+    pub(crate) egress_allow: Vec<HostPattern>,
+    /// Denied in addition to the server's egress denylist
+    pub(crate) egress_deny: Vec<HostPattern>,
+    pub(crate) max_response_bytes: Option<u64>,
+    /// Environment variables, sorted by name
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) config: BTreeMap<String, String>,
+    /// Names of the secrets the component may read
+    pub(crate) secrets: BTreeSet<String>,
+}
+
+impl ComponentOptions {
+    /// The name a component deployed to `route` keeps its key value store, database and containers under.
+    ///
+    /// Redeploying with the same name, or to the same route without one, keeps its storage.
+    fn storage_name(&self, route: &Route) -> anyhow::Result<String> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => route.storage_name(),
+        };
+        if name.len() > MAX_STORAGE_NAME_BYTES {
+            return Err(anyhow!(
+                "route {} is too long to name the component's storage, deploy it with a name",
+                route
+            ));
+        }
+        Ok(name)
+    }
+
+    fn invocation_limits(&self, config: &ServerConfig) -> InvocationLimits {
+        InvocationLimits {
+            timeout: self.invocation_timeout.or(config.invocation_limits.timeout),
+            ..config.invocation_limits.clone()
+        }
+    }
+
+    fn guest_limits(&self, config: &ServerConfig) -> GuestLimits {
+        let defaults = config.guest_limits.clone();
+        GuestLimits {
+            max_memory_bytes: self.max_memory_bytes.unwrap_or(defaults.max_memory_bytes),
+            max_table_elements: self
+                .max_table_elements
+                .unwrap_or(defaults.max_table_elements),
+            max_instances: self.max_instances.unwrap_or(defaults.max_instances),
+            ..defaults
+        }
+    }
+
+    fn outbound_http(&self, config: &ServerConfig) -> OutboundHttp {
+        match self.max_response_bytes {
+            Some(limit) => config.outbound_http.with_max_response_bytes(limit),
+            None => config.outbound_http.clone(),
+        }
+    }
+
+    fn environment(&self, name: &str, config: &ServerConfig) -> GuestEnvironment {
+        GuestEnvironment {
+            name: name.to_string(),
+            env: self.env.clone(),
+            config: Arc::new(self.config.clone()),
+            secrets: config.secrets.clone(),
+            granted_secrets: Arc::new(self.secrets.clone()),
+        }
+    }
+
+    /// Components may only reach hosts allowed by both their own and the server's allowlist
+    fn egress_policy(&self, config: &ServerConfig) -> anyhow::Result<EgressPolicy> {
+        let Some(mut policy) = config.egress_policy.restrict_allow(&self.egress_allow) else {
+            return Err(anyhow!(
+                "none of the component's egress allowlist is allowed by the server"
+            ));
+        };
+        policy.deny.extend(self.egress_deny.iter().cloned());
+        Ok(policy)
+    }
+}
+
 /// Helper to produce new HttpFunctionComponentMakers
 /// take note that this is where we're actually apply `map_component_response`
 /// to map to and from the wasm component types and the hyper service types
 ///
 /// # Arguments
 /// * `component_maybe` - An optional component the maker should produce
+/// * `name` - Storage name of the component, see `ComponentOptions::storage_name`.
+///   Also tags its logs and is the component's first argument
+/// * `config` - Server wide defaults for the component
+/// * `options` - Deploy time settings for this specific component
 fn new_http_component_maker(
-    component_maybe: Option<function_service::service::WasmComponent>,
-) -> HttpFunctionComponentMaker {
-    let store_producer = wasmtime_components::runtime::make_store_producer();
+    component_maybe: Option<WasmComponent>,
+    name: &str,
+    config: &ServerConfig,
+    options: &ComponentOptions,
+) -> anyhow::Result<HttpFunctionComponentMaker> {
+    let storage = GuestStorage {
+        kv: match &config.kv {
+            Some(kv) => Some(kv.namespace(name)?),
+            None => None,
+        },
+        sql: match &config.sql {
+            Some(sql) => Some(sql.database(name)?),
+            None => None,
+        },
+        blob: match &config.blob {
+            Some(blob) => Some(blob.component(name)?),
+            None => None,
+        },
+    };
+    let store_producer = wasmtime_components::runtime::make_store_producer(
+        options.guest_limits(config),
+        options.outbound_http(config),
+        options.egress_policy(config)?,
+        OutboundLimiter::new(&config.outbound_limits),
+        storage,
+        options.environment(name, config),
+    );
     let base_component =
         component_maybe.unwrap_or(function_service::service::empty_base_function_component());
 
-    new_function_service_maker(base_component, store_producer)
-        .map_response(map_component_response)
-        .boxed_clone()
+    let maker = new_function_service_maker(
+        base_component,
+        store_producer,
+        config.pool_config.clone(),
+        options.invocation_limits(config),
+    )?
+    .map_response(map_component_response)
+    .boxed_clone();
+    Ok(maker)
+}
+
+/// Loads the component at `component_path` and produces a maker for it to serve on `route`
+fn load_http_component_maker(
+    component_path: &str,
+    route: &Route,
+    config: &ServerConfig,
+    options: &ComponentOptions,
+) -> anyhow::Result<HttpFunctionComponentMaker> {
+    let component_path = Path::new(component_path);
+    if !component_path.exists() || !component_path.is_file() {
+        return Err(anyhow!(
+            "Component path doesn't exist or isn't a file. Did you specify the correct path?"
+        ));
+    }
+
+    let name = options.storage_name(route)?;
+
+    match WasmComponent::from_path(component_path) {
+        Ok(function_component) => {
+            info!(
+                "loaded component {} targeting {}",
+                component_path.display(),
+                function_component.world()
+            );
+            new_http_component_maker(Some(function_component), &name, config, options)
+                .map_err(|e| anyhow!("Failed to link component. Does it only import interfaces provided by mycelia?, Error {:#?}", e))
+        }
+        Err(e) => Err(anyhow!("Failed to create a component from path. Did you specify a valid wasm32-wasi component exporting a supported world?, Error {:#?}", e)),
+    }
 }
 
+type SharedRoutingTable = Arc<Mutex<RoutingTable<HttpFunctionComponentMaker>>>;
+
 /// Spawn a new tokio task to listen for incoming ServiceCommands.
 /// This task runs alongside the http server acting as a manager of sorts.
 fn run_server_command_loop(
     mut command_stream: crate::rpc::ServiceCommandSource,
     shutdown_tx: oneshot::Sender<()>,
-    function_service_maker: Arc<Mutex<HttpFunctionComponentMaker>>,
+    routing_table: SharedRoutingTable,
+    config: ServerConfig,
 ) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let cloned_maker = function_service_maker.clone();
         // Command loop
         while let Some(command) = command_stream.recv().await {
             let _ = match command {
                 crate::rpc::ServiceCommand::SwapFunctionComponent {
                     component_path,
+                    route,
+                    options,
                     reply,
-                } => {
-                    let component_path = Path::new(&component_path);
-                    if !component_path.exists() || !component_path.is_file() {
-                        let _ = reply.send(Err(anyhow!("Component path doesn't exist or isn't a file. Did you specify the correct path?")));
+                } => match load_http_component_maker(&component_path, &route, &config, &options) {
+                    Ok(new_http_component_maker) => {
+                        info!("attempting to take lock on routing table");
+                        let mut locked_table = routing_table.lock().await;
+                        info!(
+                            "received lock on routing table. Deploying {} to route {}",
+                            component_path, route
+                        );
+                        locked_table.insert(route, Some(component_path), new_http_component_maker);
+                        let _ = reply.send(Ok(()));
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                },
+                crate::rpc::ServiceCommand::ListRoutes { reply } => {
+                    let locked_table = routing_table.lock().await;
+                    let routes = locked_table
+                        .entries()
+                        .map(|entry| (entry.route.clone(), entry.component_path.clone()))
+                        .collect();
+                    let _ = reply.send(Ok(routes));
+                }
+                crate::rpc::ServiceCommand::RemoveRoute { route, reply } => {
+                    let mut locked_table = routing_table.lock().await;
+                    if locked_table.remove(&route) {
+                        info!("removed route {}", route);
+                        let _ = reply.send(Ok(()));
                     } else {
-                        match wasmtime_components::runtime::new_component_from_path(
-                            component_path.into(),
-                        ) {
-                            Ok(function_component) => {
-                                info!("attempting to take lock on maker");
-                                let mut locked_maker = cloned_maker.lock().await;
-                                info!("received lock on maker. Attempting to swap with new function component maker");
-                                let new_http_component_maker =
-                                    new_http_component_maker(Some(function_component));
-                                *locked_maker = new_http_component_maker;
-                                let _ = reply.send(Ok(()));
-                            }
-                            Err(e) => {
-                                let _ = reply.send(Err(anyhow!("Failed to create a component from path. Did you specify a valid wasm32-wasi component?, Error {:#?}", e)));
-                            }
-                        }
+                        let _ =
+                            reply.send(Err(anyhow!("No component is deployed to route {}", route)));
                     }
                 }
                 crate::rpc::ServiceCommand::StopServer { reply } => {
@@ -170,28 +440,191 @@ fn run_server_command_loop(
     })
 }
 
+type SharedComponentInstance = Arc<Mutex<HttpFunctionComponent>>;
+
+/// Per connection service routing requests to the component deployed on the matching route.
+///
+/// Component instances are created lazily the first time a connection hits a route
+/// and are reused for the remainder of the connection.
+struct RoutedConnection {
+    routing_table: SharedRoutingTable,
+    /// Instances keyed by the id of the route entry which produced them
+    instances: Arc<Mutex<HashMap<u64, SharedComponentInstance>>>,
+}
+
+impl RoutedConnection {
+    fn new(routing_table: SharedRoutingTable) -> Self {
+        Self {
+            routing_table,
+            instances: Default::default(),
+        }
+    }
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(message))
+        .expect("Failed to create a response")
+}
+
+fn not_found() -> Response<Body> {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "no function component is deployed on this route",
+    )
+}
+
+/// Turns host side invocation failures into responses.
+/// Anything we don't recognize is returned to hyper which drops the connection
+///
+/// # Arguments
+/// * `component` - name of the component which failed, used for logging
+/// * `e` - the error returned by the component service
+fn map_invocation_error(component: &str, e: BoxError) -> Result<Response<Body>, BoxError> {
+    match e.downcast_ref::<InvocationError>() {
+        Some(InvocationError::Timeout { limit }) => {
+            warn!(
+                "component {} invocation timed out after {:?}",
+                component, limit
+            );
+            Ok(error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                "function invocation exceeded its time limit",
+            ))
+        }
+        Some(InvocationError::ResourceLimit(limit)) => {
+            warn!(
+                "component {} exceeded a resource limit. {}",
+                component, limit
+            );
+            Ok(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "function invocation exceeded its resource limits",
+            ))
+        }
+        Some(InvocationError::BodyTooLarge { limit }) => {
+            info!(
+                "component {} rejected a request body over {} bytes",
+                component, limit
+            );
+            Ok(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request body exceeded the buffered body limit",
+            ))
+        }
+        Some(InvocationError::MissingResponse) => {
+            warn!("component {} returned without a response", component);
+            Ok(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "function returned without a response",
+            ))
+        }
+        None => Err(e),
+    }
+}
+
+impl HyperService<Request<Body>> for RoutedConnection {
+    type Response = Response<Body>;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let routing_table = self.routing_table.clone();
+        let instances = self.instances.clone();
+
+        Box::pin(async move {
+            let host = req
+                .headers()
+                .get(HOST)
+                .and_then(|v| v.to_str().ok())
+                .or(req.uri().host())
+                .map(str::to_string);
+
+            let (id, component_name, mut maker) = {
+                let locked_table = routing_table.lock().await;
+                let Some(entry) = locked_table.find(host.as_deref(), req.uri().path()) else {
+                    trace!(
+                        "no route found for host {:?} path {}",
+                        host,
+                        req.uri().path()
+                    );
+                    return Ok(not_found());
+                };
+                let found = (entry.id, entry.component_name(), entry.maker.clone());
+
+                // drop instances of components which have since been redeployed or removed
+                instances
+                    .lock()
+                    .await
+                    .retain(|id, _| locked_table.contains_id(*id));
+
+                found
+            };
+
+            let instance = {
+                let mut locked_instances = instances.lock().await;
+                match locked_instances.get(&id) {
+                    Some(instance) => instance.clone(),
+                    None => {
+                        let instance = maker.ready().await?.call(()).await?;
+                        let instance = Arc::new(Mutex::new(instance));
+                        locked_instances.insert(id, instance.clone());
+                        instance
+                    }
+                }
+            };
+
+            // Only hold the lock while dispatching, requests are handled concurrently by the instance pool
+            let response = {
+                let mut instance = instance.lock().await;
+                instance.ready().await?.call(req)
+            };
+            response
+                .await
+                .or_else(|e| map_invocation_error(&component_name, e))
+        })
+    }
+}
+
 pub(crate) async fn start_development_server(
     command_stream: crate::rpc::ServiceCommandSource,
     socket_addr: SocketAddr,
+    config: ServerConfig,
 ) {
     let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
-    let function_service_maker = Arc::new(Mutex::new(new_http_component_maker(None)));
+    let mut routing_table = RoutingTable::new();
+    routing_table.insert(
+        Route::root(),
+        None,
+        new_http_component_maker(
+            None,
+            BASE_STORAGE_NAME,
+            &config,
+            &ComponentOptions::default(),
+        )
+        .expect("Failed to link the base function component"),
+    );
+    let routing_table = Arc::new(Mutex::new(routing_table));
 
-    // Notice we pass a ref to the maker.
-    // This allows us to "hot swap" the maker
+    // Notice we pass a ref to the routing table.
+    // This allows us to "hot swap" the makers for each route
     let command_loop_handle =
-        run_server_command_loop(command_stream, shutdown_tx, function_service_maker.clone());
+        run_server_command_loop(command_stream, shutdown_tx, routing_table.clone(), config);
 
-    // Create the server future using the http_commonent_maker to handle all incoming connections
+    // Create the server future routing all incoming connections through the routing table
     let component_host_server = Server::bind(&socket_addr)
         .serve(make_service_fn(move |v: &AddrStream| {
             trace!("http connection {:#?}", v);
-            let cloned_maker = function_service_maker.clone();
-            async move {
-                let mut maker = cloned_maker.lock().await;
-                maker.call(()).await
-            }
+            let connection = RoutedConnection::new(routing_table.clone());
+            async move { Ok::<_, Infallible>(connection) }
         }))
         .with_graceful_shutdown(async move {
             let _ = shutdown_rx.await;
@@ -206,3 +639,47 @@ pub(crate) async fn start_development_server(
       }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use function_service::service::InvocationError;
+    use hyper::StatusCode;
+    use tower::BoxError;
+    use wasmtime_components::limits::LimitExceeded;
+
+    use super::map_invocation_error;
+
+    fn status_of(e: InvocationError) -> StatusCode {
+        map_invocation_error("test", BoxError::from(e))
+            .unwrap()
+            .status()
+    }
+
+    #[test]
+    fn it_maps_timeouts_to_gateway_timeout() {
+        let limit = Duration::from_millis(100);
+        assert_eq!(
+            status_of(InvocationError::Timeout { limit }),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn it_maps_resource_limits_to_internal_server_error() {
+        let limit = LimitExceeded::Table {
+            desired: 200,
+            limit: 100,
+        };
+        assert_eq!(
+            status_of(InvocationError::ResourceLimit(limit)),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn it_returns_unrecognized_errors() {
+        assert!(map_invocation_error("test", "guest trapped".into()).is_err());
+    }
+}
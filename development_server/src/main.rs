@@ -1,17 +1,31 @@
 mod http_function_component;
+mod router;
 mod rpc;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use clap::Parser;
 
+use function_service::{pool::PoolConfig, service::InvocationLimits};
 use log::{info, warn};
+use resource_providers::http::{EgressPolicy, OutboundLimits, RateLimit, RequestLimits};
+use resource_providers::providers::blob_store_fs::FsBlobStore;
+use resource_providers::providers::http_client_hyper::{new_shared_client, HyperClientConfig};
+use resource_providers::providers::http_client_mock::{HttpRecorder, MockHttp};
+use resource_providers::providers::kv_store_sled::SledKv;
+use resource_providers::providers::secrets_file::{SecretsFile, SECRETS_KEY_ENV};
+use resource_providers::providers::sql_sqlite::SqliteDatabases;
+use wasmtime_components::limits::GuestLimits;
+use wasmtime_components::runtime::OutboundHttp;
 
 use http_function_component::*;
 use rpc::*;
 
 mod cmd {
+    use std::path::PathBuf;
+
     use clap::Parser;
+    use resource_providers::http::HostPattern;
 
     /// Mycelia Development Server
     #[derive(Parser, Debug)]
@@ -28,9 +42,147 @@ mod cmd {
         /// port http server should bind to
         #[arg(long)]
         pub http_port: Option<u16>,
+
+        /// instances kept warm per deployed component
+        #[arg(long)]
+        pub min_instances: Option<usize>,
+
+        /// max concurrent instances per deployed component
+        #[arg(long)]
+        pub max_instances: Option<usize>,
+
+        /// seconds an idle instance is kept before eviction
+        #[arg(long)]
+        pub idle_timeout_secs: Option<u64>,
+
+        /// requests an instance serves before being recycled
+        #[arg(long)]
+        pub max_requests_per_instance: Option<u64>,
+
+        /// default time limit for a single function invocation in milliseconds. 0 disables the limit
+        #[arg(long)]
+        pub invocation_timeout_ms: Option<u64>,
+
+        /// largest request body buffered for components which don't stream bodies
+        #[arg(long)]
+        pub max_buffered_body_bytes: Option<usize>,
+
+        /// default max linear memory size in bytes for a single guest memory
+        #[arg(long)]
+        pub max_memory_bytes: Option<usize>,
+
+        /// default max elements for a single guest table
+        #[arg(long)]
+        pub max_table_elements: Option<u32>,
+
+        /// idle keep-alive connections kept per host for guest outbound requests
+        #[arg(long)]
+        pub http_client_max_idle_per_host: Option<usize>,
+
+        /// seconds resolved addresses are cached for guest outbound requests
+        #[arg(long)]
+        pub http_client_dns_cache_ttl_secs: Option<u64>,
+
+        /// only use HTTP/1.1 for guest outbound requests
+        #[arg(long)]
+        pub http_client_disable_http2: bool,
+
+        /// ceiling in milliseconds for any timeout a guest sets on outbound requests
+        #[arg(long)]
+        pub http_client_max_timeout_ms: Option<u64>,
+
+        /// largest outbound response body in bytes buffered for a guest
+        #[arg(long)]
+        pub http_client_max_response_bytes: Option<u64>,
+
+        /// hosts guests may reach, e.g. `api.example.com:443` or `*.example.com`. Default: any public host
+        #[arg(long)]
+        pub egress_allow: Vec<HostPattern>,
+
+        /// hosts guests may never reach, takes precedence over `--egress-allow`
+        #[arg(long)]
+        pub egress_deny: Vec<HostPattern>,
+
+        /// let guests reach loopback, private and link-local addresses
+        #[arg(long)]
+        pub egress_allow_private_ips: bool,
+
+        /// outbound requests per second allowed for each component. Default: unlimited
+        #[arg(long)]
+        pub outbound_requests_per_sec: Option<u32>,
+
+        /// outbound requests a component may burst above `--outbound-requests-per-sec`
+        #[arg(long)]
+        pub outbound_burst: Option<u32>,
+
+        /// concurrent outbound requests allowed for each component. Default: unlimited
+        #[arg(long)]
+        pub outbound_max_in_flight: Option<usize>,
+
+        /// outbound request body bytes per second allowed for each component, also the largest body. Default: unlimited
+        #[arg(long)]
+        pub outbound_bytes_per_sec: Option<u32>,
+
+        /// outbound requests per second allowed for each guest http client. Default: unlimited
+        #[arg(long)]
+        pub outbound_client_requests_per_sec: Option<u32>,
+
+        /// concurrent outbound requests allowed for each guest http client. Default: unlimited
+        #[arg(long)]
+        pub outbound_client_max_in_flight: Option<usize>,
+
+        /// answer guest outbound requests from a recorded fixture instead of the network
+        #[arg(long, conflicts_with = "http_record")]
+        pub http_replay: Option<PathBuf>,
+
+        /// record guest outbound requests and their responses to a fixture
+        #[arg(long)]
+        pub http_record: Option<PathBuf>,
+
+        /// directory guest key value stores are kept in. Default: `mycelia/kv` in the user's data dir
+        #[arg(long)]
+        pub kv_path: Option<PathBuf>,
+
+        /// don't provide guests a key value store
+        #[arg(long, conflicts_with = "kv_path")]
+        pub kv_disable: bool,
+
+        /// directory guest sql databases are kept in. Default: `mycelia/sql` in the user's data dir
+        #[arg(long)]
+        pub sql_path: Option<PathBuf>,
+
+        /// don't provide guests a sql database
+        #[arg(long, conflicts_with = "sql_path")]
+        pub sql_disable: bool,
+
+        /// directory guest object storage containers are kept in. Default: `mycelia/blob` in the user's data dir
+        #[arg(long)]
+        pub blob_path: Option<PathBuf>,
+
+        /// don't provide guests object storage
+        #[arg(long, conflicts_with = "blob_path")]
+        pub blob_disable: bool,
+
+        /// encrypted file components' granted secrets are read from, unlocked with the
+        /// passphrase in `MYCELIA_SECRETS_KEY`. Default: components get no secrets
+        #[arg(long)]
+        pub secrets_file: Option<PathBuf>,
     }
 }
 
+/// Storage is kept in the current user's data dir, other users can't read or replace it
+/// the way they could in the shared temp dir
+fn default_storage_dir(kind: &str) -> PathBuf {
+    dirs::data_dir()
+        .map(|dir| dir.join("mycelia").join(kind))
+        .unwrap_or_else(|| {
+            panic!(
+                "No data directory for the current user, set --{}-path",
+                kind
+            )
+        })
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
@@ -41,11 +193,154 @@ async fn main() {
     let rpc_host_addr = SocketAddr::from(([127, 0, 0, 1], args.rpc_port.unwrap_or(50051)));
     let http_host_addr = SocketAddr::from(([127, 0, 0, 1], args.http_port.unwrap_or(3001)));
 
+    let default_pool_config = PoolConfig::default();
+    let pool_config = PoolConfig {
+        min_instances: args
+            .min_instances
+            .unwrap_or(default_pool_config.min_instances),
+        max_instances: args
+            .max_instances
+            .unwrap_or(default_pool_config.max_instances),
+        idle_timeout: args
+            .idle_timeout_secs
+            .map(Duration::from_secs)
+            .unwrap_or(default_pool_config.idle_timeout),
+        max_requests_per_instance: args.max_requests_per_instance,
+    };
+
+    let default_invocation_limits = InvocationLimits::default();
+    let invocation_limits = InvocationLimits {
+        timeout: match args.invocation_timeout_ms.unwrap_or(30_000) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        },
+        max_buffered_body_bytes: args
+            .max_buffered_body_bytes
+            .unwrap_or(default_invocation_limits.max_buffered_body_bytes),
+    };
+
+    let default_guest_limits = GuestLimits::default();
+    let guest_limits = GuestLimits {
+        max_memory_bytes: args
+            .max_memory_bytes
+            .unwrap_or(default_guest_limits.max_memory_bytes),
+        max_table_elements: args
+            .max_table_elements
+            .unwrap_or(default_guest_limits.max_table_elements),
+        ..default_guest_limits
+    };
+
+    let egress_policy = EgressPolicy {
+        allow: args.egress_allow,
+        deny: args.egress_deny,
+        allow_private_ips: args.egress_allow_private_ips,
+        ..Default::default()
+    };
+
+    let default_http_client_config = HyperClientConfig::default();
+    let http_client_config = HyperClientConfig {
+        pool_max_idle_per_host: args
+            .http_client_max_idle_per_host
+            .unwrap_or(default_http_client_config.pool_max_idle_per_host),
+        dns_cache_ttl: args
+            .http_client_dns_cache_ttl_secs
+            .map(Duration::from_secs)
+            .unwrap_or(default_http_client_config.dns_cache_ttl),
+        http2: !args.http_client_disable_http2,
+        max_timeout: args
+            .http_client_max_timeout_ms
+            .map(Duration::from_millis)
+            .unwrap_or(default_http_client_config.max_timeout),
+        max_response_bytes: args
+            .http_client_max_response_bytes
+            .unwrap_or(default_http_client_config.max_response_bytes),
+        // Names are only dialed at addresses the egress policy would allow in the uri
+        allow_private_ips: egress_policy.allow_private_ips,
+        ..default_http_client_config
+    };
+
+    let outbound_http = outbound_http(
+        args.http_replay.as_ref(),
+        args.http_record.as_ref(),
+        &http_client_config,
+    );
+
+    let kv = if args.kv_disable {
+        None
+    } else {
+        let kv_path = args.kv_path.unwrap_or_else(|| default_storage_dir("kv"));
+        info!("keeping key value stores in {}", kv_path.display());
+        Some(SledKv::open(&kv_path).expect("Failed to open the key value store"))
+    };
+
+    let sql = if args.sql_disable {
+        None
+    } else {
+        let sql_path = args.sql_path.unwrap_or_else(|| default_storage_dir("sql"));
+        info!("keeping sql databases in {}", sql_path.display());
+        Some(SqliteDatabases::new(sql_path))
+    };
+
+    let blob = if args.blob_disable {
+        None
+    } else {
+        let blob_path = args
+            .blob_path
+            .unwrap_or_else(|| default_storage_dir("blob"));
+        info!(
+            "keeping object storage containers in {}",
+            blob_path.display()
+        );
+        Some(FsBlobStore::new(blob_path))
+    };
+
+    let secrets = args.secrets_file.map(|secrets_file| {
+        // Never taken from an argument, which would be visible to other processes
+        let passphrase = std::env::var(SECRETS_KEY_ENV)
+            .unwrap_or_else(|_| panic!("{} must be set to use a secrets file", SECRETS_KEY_ENV));
+        info!("reading secrets from {}", secrets_file.display());
+        SecretsFile::open(&secrets_file, &passphrase).expect("Failed to open the secrets file")
+    });
+
+    let config = ServerConfig {
+        pool_config,
+        invocation_limits,
+        guest_limits,
+        egress_policy: EgressPolicy {
+            // Nothing reaches the network when replaying, don't resolve the recorded hosts
+            allow_private_ips: egress_policy.allow_private_ips
+                || matches!(outbound_http, OutboundHttp::Replay(_)),
+            ..egress_policy
+        },
+        outbound_http,
+        outbound_limits: OutboundLimits {
+            component: RequestLimits {
+                requests: args.outbound_requests_per_sec.map(|per_sec| RateLimit {
+                    per_sec,
+                    burst: args.outbound_burst.unwrap_or(per_sec),
+                }),
+                max_in_flight: args.outbound_max_in_flight,
+                bytes: args.outbound_bytes_per_sec.map(RateLimit::per_sec),
+            },
+            client: RequestLimits {
+                requests: args
+                    .outbound_client_requests_per_sec
+                    .map(RateLimit::per_sec),
+                max_in_flight: args.outbound_client_max_in_flight,
+                bytes: None,
+            },
+        },
+        kv,
+        sql,
+        blob,
+        secrets,
+    };
+
     // Command Sink / Source
     let (command_sink, command_source) = tokio::sync::mpsc::channel(10);
 
     let rpc_server = start_rpc_server(command_sink, rpc_host_addr);
-    let http_server = start_development_server(command_source, http_host_addr);
+    let http_server = start_development_server(command_source, http_host_addr, config);
 
     let rpc_server = tokio::spawn(rpc_server);
     let http_server = tokio::spawn(http_server);
@@ -59,3 +354,26 @@ async fn main() {
         }
     };
 }
+
+fn outbound_http(
+    replay: Option<&PathBuf>,
+    record: Option<&PathBuf>,
+    http_client_config: &HyperClientConfig,
+) -> OutboundHttp {
+    if let Some(fixture) = replay {
+        info!("replaying outbound requests from {}", fixture.display());
+        let mock = MockHttp::from_file(fixture).expect("Failed to load the http fixture");
+        return OutboundHttp::Replay(mock);
+    }
+
+    let client = new_shared_client(http_client_config);
+    match record {
+        Some(fixture) => {
+            info!("recording outbound requests to {}", fixture.display());
+            let recorder =
+                HttpRecorder::new(fixture.clone()).expect("Failed to load the http fixture");
+            OutboundHttp::Record(client, recorder)
+        }
+        None => OutboundHttp::Live(client),
+    }
+}
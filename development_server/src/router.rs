@@ -0,0 +1,284 @@
+//! Routing table used by the development server to host several
+//! function components side by side.
+//!
+//! Incoming requests are matched against a [`Route`] using the `Host` header
+//! and the request path. The most specific route wins:
+//! 1. routes bound to a host beat routes which match any host
+//! 2. longer path prefixes beat shorter ones
+
+/// Matches incoming requests to a deployed function component
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Route {
+    /// Host the route is bound to. `None` matches any host
+    pub(crate) host: Option<String>,
+    /// Normalized path prefix. Always starts with `/` and never ends with one
+    /// unless it is the root prefix
+    pub(crate) path_prefix: String,
+}
+
+impl Route {
+    /// Creates a new route, normalizing the provided host and path prefix.
+    ///
+    /// Empty values are treated as "any host" and the root prefix respectively.
+    /// A trailing `/*` on the path prefix is accepted for readability, `/api/*` and `/api` are the same route.
+    pub(crate) fn new(host: Option<&str>, path_prefix: Option<&str>) -> Self {
+        let host = host.map(normalize_host).filter(|host| !host.is_empty());
+
+        let path_prefix = path_prefix.unwrap_or("/").trim();
+        let path_prefix = path_prefix.strip_suffix("/*").unwrap_or(path_prefix);
+        let path_prefix = path_prefix.trim_end_matches('/');
+        let path_prefix = if path_prefix.starts_with('/') {
+            path_prefix.to_string()
+        } else {
+            format!("/{}", path_prefix)
+        };
+
+        Self { host, path_prefix }
+    }
+
+    /// The route matching every request
+    pub(crate) fn root() -> Self {
+        Self::new(None, None)
+    }
+
+    /// Names the storage of a component deployed here without an explicit name.
+    ///
+    /// Letters and digits are kept and every other byte becomes `_` followed by its hex value,
+    /// with `-` separating the host from the path. No two routes share a name, and the leading
+    /// `_` keeps these apart from names given at deploy time.
+    pub(crate) fn storage_name(&self) -> String {
+        fn encode(name: &mut String, value: &str) {
+            for b in value.bytes() {
+                match b {
+                    b'a'..=b'z' | b'0'..=b'9' => name.push(b as char),
+                    _ => name.push_str(&format!("_{:02x}", b)),
+                }
+            }
+        }
+
+        let mut name = "_route".to_string();
+        encode(&mut name, self.host.as_deref().unwrap_or_default());
+        name.push('-');
+        encode(&mut name, &self.path_prefix);
+        name
+    }
+
+    /// Returns a score if this route matches the request `host` and `path`.
+    /// Higher scores are more specific matches.
+    fn matches(&self, host: Option<&str>, path: &str) -> Option<(bool, usize)> {
+        if let Some(route_host) = &self.host {
+            match host.map(normalize_host) {
+                Some(host) if &host == route_host => {}
+                _ => return None,
+            }
+        }
+
+        let prefix_matches = self.path_prefix == "/"
+            || path == self.path_prefix
+            || path
+                .strip_prefix(self.path_prefix.as_str())
+                .is_some_and(|rest| rest.starts_with('/'));
+
+        if !prefix_matches {
+            return None;
+        }
+
+        Some((self.host.is_some(), self.path_prefix.len()))
+    }
+}
+
+impl std::fmt::Display for Route {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            Some(host) => write!(f, "{}{}", host, self.path_prefix),
+            None => write!(f, "*{}", self.path_prefix),
+        }
+    }
+}
+
+/// Lowercases the host and strips any port
+fn normalize_host(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    match host.rsplit_once(':') {
+        // Don't mangle bare ipv6 addresses like `::1`
+        Some((name, port)) if !name.ends_with(':') && port.chars().all(|c| c.is_ascii_digit()) => {
+            name.to_string()
+        }
+        _ => host,
+    }
+}
+
+/// A deployed component and the route it is reachable on
+pub(crate) struct RouteEntry<M> {
+    pub(crate) route: Route,
+    /// Path the component was loaded from. `None` for the built in base component
+    pub(crate) component_path: Option<String>,
+    /// Unique per deployment. A redeploy to an existing route produces a new id
+    pub(crate) id: u64,
+    pub(crate) maker: M,
+}
+
+impl<M> RouteEntry<M> {
+    /// Human readable name of the deployed component, used in logs
+    pub(crate) fn component_name(&self) -> String {
+        self.component_path
+            .as_deref()
+            .map(std::path::Path::new)
+            .and_then(|path| path.file_stem())
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or("base".to_string())
+    }
+}
+
+/// Holds every deployed component keyed by its route.
+///
+/// `M` is whatever produces services for the route, for the development server
+/// this is a `HttpFunctionComponentMaker`.
+pub(crate) struct RoutingTable<M> {
+    entries: Vec<RouteEntry<M>>,
+    next_id: u64,
+}
+
+impl<M> RoutingTable<M> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: vec![],
+            next_id: 0,
+        }
+    }
+
+    /// Deploys `maker` to `route`, replacing any component already deployed there
+    pub(crate) fn insert(&mut self, route: Route, component_path: Option<String>, maker: M) {
+        self.entries.retain(|entry| entry.route != route);
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.entries.push(RouteEntry {
+            route,
+            component_path,
+            id,
+            maker,
+        });
+    }
+
+    /// Removes the component deployed to `route`. Returns false if no such route exists
+    pub(crate) fn remove(&mut self, route: &Route) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|entry| &entry.route != route);
+        len != self.entries.len()
+    }
+
+    /// Finds the most specific route for a request
+    pub(crate) fn find(&self, host: Option<&str>, path: &str) -> Option<&RouteEntry<M>> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.route.matches(host, path).map(|score| (score, entry)))
+            .max_by_key(|(score, _)| *score)
+            .map(|(_, entry)| entry)
+    }
+
+    pub(crate) fn contains_id(&self, id: u64) -> bool {
+        self.entries.iter().any(|entry| entry.id == id)
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &RouteEntry<M>> {
+        self.entries.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table() -> RoutingTable<&'static str> {
+        let mut table = RoutingTable::new();
+        table.insert(Route::root(), None, "root");
+        table.insert(Route::new(None, Some("/api/*")), None, "api");
+        table.insert(Route::new(None, Some("auth")), None, "auth");
+        table.insert(
+            Route::new(Some("Admin.localhost:3001"), Some("/api")),
+            None,
+            "admin-api",
+        );
+        table
+    }
+
+    fn find(
+        table: &RoutingTable<&'static str>,
+        host: Option<&str>,
+        path: &str,
+    ) -> Option<&'static str> {
+        table.find(host, path).map(|entry| entry.maker)
+    }
+
+    #[test]
+    fn it_normalizes_routes() {
+        assert_eq!(
+            Route::new(None, Some("/api/*")),
+            Route::new(None, Some("api/"))
+        );
+        assert_eq!(Route::new(Some(""), Some("")), Route::root());
+        assert_eq!(
+            Route::new(Some("Example.com:8080"), None).host.as_deref(),
+            Some("example.com")
+        );
+    }
+
+    #[test]
+    fn it_matches_the_most_specific_route() {
+        let table = table();
+
+        assert_eq!(find(&table, None, "/"), Some("root"));
+        assert_eq!(find(&table, None, "/apix"), Some("root"));
+        assert_eq!(find(&table, None, "/api"), Some("api"));
+        assert_eq!(find(&table, None, "/api/users"), Some("api"));
+        assert_eq!(
+            find(&table, Some("localhost:3001"), "/auth/login"),
+            Some("auth")
+        );
+        assert_eq!(
+            find(&table, Some("admin.localhost"), "/api/users"),
+            Some("admin-api")
+        );
+        assert_eq!(find(&table, Some("admin.localhost"), "/auth"), Some("auth"));
+    }
+
+    #[test]
+    fn it_replaces_and_removes_routes() {
+        let mut table = table();
+
+        table.insert(Route::new(None, Some("/api")), None, "api-v2");
+        assert_eq!(find(&table, None, "/api/users"), Some("api-v2"));
+        assert_eq!(table.entries().count(), 4);
+
+        assert!(table.remove(&Route::new(None, Some("/api"))));
+        assert!(!table.remove(&Route::new(None, Some("/api"))));
+        assert_eq!(find(&table, None, "/api/users"), Some("root"));
+
+        assert!(table.remove(&Route::root()));
+        assert_eq!(find(&table, None, "/api/users"), None);
+    }
+
+    #[test]
+    fn it_names_storage_after_the_route() {
+        assert_eq!(Route::root().storage_name(), "_route-_2f");
+        assert_eq!(
+            Route::new(Some("Example.com:8080"), Some("/api/*")).storage_name(),
+            "_routeexample_2ecom-_2fapi"
+        );
+
+        let routes = [
+            Route::new(None, Some("/a-b")),
+            Route::new(None, Some("/a_2db")),
+            Route::new(Some("a"), Some("/b")),
+            Route::new(None, Some("/a/b")),
+        ];
+        let names: std::collections::HashSet<_> =
+            routes.iter().map(|route| route.storage_name()).collect();
+        assert_eq!(names.len(), routes.len());
+        assert!(names
+            .iter()
+            .all(|name| resource_providers::core::is_valid_storage_name(name)));
+    }
+}
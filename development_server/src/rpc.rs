@@ -1,9 +1,17 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
 use log::info;
+use resource_providers::core::is_valid_storage_name;
+use resource_providers::http::HostPattern;
 
 use tokio::sync::oneshot;
 
+use crate::router::Route;
+use crate::ComponentOptions;
+
+/// Longest name a component may be deployed with
+const MAX_NAME_BYTES: usize = 64;
+
 pub(crate) mod protos {
     tonic::include_proto!("development");
 
@@ -14,6 +22,15 @@ pub(crate) mod protos {
 pub enum ServiceCommand {
     SwapFunctionComponent {
         component_path: String,
+        route: Route,
+        options: ComponentOptions,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+    ListRoutes {
+        reply: oneshot::Sender<anyhow::Result<Vec<(Route, Option<String>)>>>,
+    },
+    RemoveRoute {
+        route: Route,
         reply: oneshot::Sender<anyhow::Result<()>>,
     },
     StopServer {
@@ -26,8 +43,27 @@ pub type ServiceCommandSource = tokio::sync::mpsc::Receiver<ServiceCommand>;
 
 use crate::protos::{
     development_server::Development, DeployReply, DeployRequest, EchoReply, EchoRequest, Empty,
+    ListRoutesReply, RemoveRouteReply, RemoveRouteRequest, RouteEntry,
 };
 
+impl From<Option<protos::Route>> for Route {
+    fn from(value: Option<protos::Route>) -> Self {
+        match value {
+            Some(route) => Route::new(Some(&route.host), Some(&route.path_prefix)),
+            None => Route::root(),
+        }
+    }
+}
+
+impl From<Route> for protos::Route {
+    fn from(value: Route) -> Self {
+        protos::Route {
+            path_prefix: value.path_prefix,
+            host: value.host.unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct RpcServer {
     command_sink: ServiceCommandSink,
@@ -58,9 +94,29 @@ impl Development for RpcServer {
         info!("received deploy_component cmd");
         let request = request.into_inner();
         let component_path = request.component_path;
+        let route = request.route.into();
+        let options = ComponentOptions {
+            name: parse_component_name(&request.name)?,
+            invocation_timeout: (request.timeout_ms > 0)
+                .then(|| Duration::from_millis(request.timeout_ms.into())),
+            max_memory_bytes: (request.max_memory_bytes > 0)
+                .then(|| request.max_memory_bytes as usize),
+            max_table_elements: (request.max_table_elements > 0)
+                .then_some(request.max_table_elements),
+            max_instances: (request.max_instances > 0).then(|| request.max_instances as usize),
+            egress_allow: parse_host_patterns(&request.egress_allow)?,
+            egress_deny: parse_host_patterns(&request.egress_deny)?,
+            max_response_bytes: (request.max_response_bytes > 0)
+                .then_some(request.max_response_bytes),
+            env: parse_env(request.env)?,
+            config: request.config.into_iter().collect(),
+            secrets: request.secrets.into_iter().collect(),
+        };
         let (reply, rx) = oneshot::channel();
         let cmd = ServiceCommand::SwapFunctionComponent {
             component_path,
+            route,
+            options,
             reply,
         };
         let _ = self.command_sink.send(cmd).await;
@@ -74,6 +130,53 @@ impl Development for RpcServer {
         }))
     }
 
+    async fn list_routes(
+        &self,
+        _request: tonic::Request<Empty>,
+    ) -> Result<tonic::Response<ListRoutesReply>, tonic::Status> {
+        let (reply, rx) = oneshot::channel();
+        let _ = self
+            .command_sink
+            .send(ServiceCommand::ListRoutes { reply })
+            .await;
+
+        match rx.await {
+            Ok(Ok(routes)) => {
+                let routes = routes
+                    .into_iter()
+                    .map(|(route, component_path)| RouteEntry {
+                        route: Some(route.into()),
+                        component_path: component_path.unwrap_or_default(),
+                    })
+                    .collect();
+                Ok(tonic::Response::new(ListRoutesReply { routes }))
+            }
+            Ok(Err(e)) => Err(tonic::Status::from_error(e.into())),
+            Err(_) => Err(tonic::Status::from_error("Failed to list routes".into())),
+        }
+    }
+
+    async fn remove_route(
+        &self,
+        request: tonic::Request<RemoveRouteRequest>,
+    ) -> Result<tonic::Response<RemoveRouteReply>, tonic::Status> {
+        let route: Route = request.into_inner().route.into();
+        info!("received remove_route cmd for route {}", route);
+        let (reply, rx) = oneshot::channel();
+        let _ = self
+            .command_sink
+            .send(ServiceCommand::RemoveRoute { route, reply })
+            .await;
+
+        match rx.await {
+            Ok(Ok(())) => Ok(tonic::Response::new(RemoveRouteReply {
+                message: "Ok".into(),
+            })),
+            Ok(Err(e)) => Err(tonic::Status::not_found(e.to_string())),
+            Err(_) => Err(tonic::Status::from_error("Failed to remove route".into())),
+        }
+    }
+
     async fn stop_server(
         &self,
         _request: tonic::Request<Empty>,
@@ -91,6 +194,46 @@ impl Development for RpcServer {
     }
 }
 
+fn parse_host_patterns(patterns: &[String]) -> Result<Vec<HostPattern>, tonic::Status> {
+    patterns
+        .iter()
+        .map(|p| {
+            p.parse::<HostPattern>()
+                .map_err(|e| tonic::Status::invalid_argument(e.to_string()))
+        })
+        .collect()
+}
+
+/// An empty name derives one from the route. Names starting with `_` are kept for the server
+fn parse_component_name(name: &str) -> Result<Option<String>, tonic::Status> {
+    if name.is_empty() {
+        return Ok(None);
+    }
+    if !is_valid_storage_name(name) || name.starts_with('_') || name.len() > MAX_NAME_BYTES {
+        return Err(tonic::Status::invalid_argument(format!(
+            "invalid component name {:?}, expected at most {} of `a-z`, `0-9`, `_` and `-` not starting with `_`",
+            name, MAX_NAME_BYTES
+        )));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// Sorts environment variables by name, rejecting names wasi can't represent
+fn parse_env(env: HashMap<String, String>) -> Result<Vec<(String, String)>, tonic::Status> {
+    let mut env: Vec<_> = env.into_iter().collect();
+    if let Some((name, _)) = env
+        .iter()
+        .find(|(name, _)| name.is_empty() || name.contains(['=', '\0']))
+    {
+        return Err(tonic::Status::invalid_argument(format!(
+            "invalid environment variable name {:?}",
+            name
+        )));
+    }
+    env.sort();
+    Ok(env)
+}
+
 pub(crate) async fn start_rpc_server(command_sink: ServiceCommandSink, socket_addr: SocketAddr) {
     let reflection = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(protos::FILE_DESCRIPTOR_SET)
@@ -106,3 +249,36 @@ pub(crate) async fn start_rpc_server(command_sink: ServiceCommandSink, socket_ad
         .serve(socket_addr)
         .await;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_sorts_environment_variables() {
+        let env = HashMap::from([
+            ("B".to_string(), "2".to_string()),
+            ("A".to_string(), "a=1".to_string()),
+        ]);
+        assert_eq!(
+            parse_env(env).unwrap(),
+            [
+                ("A".to_string(), "a=1".to_string()),
+                ("B".to_string(), "2".to_string())
+            ]
+        );
+        assert!(parse_env(HashMap::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_rejects_invalid_environment_variable_names() {
+        for name in ["", "A=B", "A\0B"] {
+            let env = HashMap::from([
+                ("VALID".to_string(), String::new()),
+                (name.to_string(), String::new()),
+            ]);
+            let status = parse_env(env).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{:?}", name);
+        }
+    }
+}
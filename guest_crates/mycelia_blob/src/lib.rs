@@ -0,0 +1,22 @@
+//! A convenient rust crate providing wasm object storage
+//! see `resource_providers::blob` for the host side code
+//!
+//! Usage of this crate isn't necessary as long as your
+//! usage complies with the .wit contract specified
+
+mod bindgen {
+    wit_bindgen::generate!({
+        // the name of the world in the `*.wit` input file
+        world: "command",
+    });
+}
+
+pub type Container = bindgen::mycelia_alpha::blob::interfaces::Container;
+pub type ObjectMetadata = bindgen::mycelia_alpha::blob::types::ObjectMetadata;
+pub type BlobError = bindgen::mycelia_alpha::blob::types::BlobError;
+
+/// Facade for opening one of the component's containers, creating it if it doesn't exist.
+/// Objects written here are visible to later invocations of the same component
+pub fn open_container(name: &str) -> Result<Container, BlobError> {
+    bindgen::mycelia_alpha::blob::interfaces::open_container(name)
+}
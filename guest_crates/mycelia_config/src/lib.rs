@@ -0,0 +1,43 @@
+//! A convenient rust crate providing a wasm component its deploy time configuration
+//! see `resource_providers::config` for the host side code
+//!
+//! Usage of this crate isn't necessary as long as your
+//! usage complies with the .wit contract specified
+
+mod bindgen {
+    wit_bindgen::generate!({
+        // the name of the world in the `*.wit` input file
+        world: "command",
+    });
+}
+
+use bindgen::mycelia_alpha::config::interfaces;
+
+pub type ConfigError = bindgen::mycelia_alpha::config::types::ConfigError;
+
+/// the value of `key`, `None` when the component wasn't deployed with it
+pub fn get(key: &str) -> Option<String> {
+    interfaces::get(key)
+}
+
+/// the value of `key`, or `default` when the component wasn't deployed with it
+pub fn get_or(key: &str, default: &str) -> String {
+    get(key).unwrap_or_else(|| default.to_string())
+}
+
+/// every key and value the component was deployed with, sorted by key
+pub fn get_all() -> Vec<(String, String)> {
+    interfaces::get_all()
+}
+
+pub fn get_i64(key: &str) -> Result<i64, ConfigError> {
+    interfaces::get_s64(key)
+}
+
+pub fn get_f64(key: &str) -> Result<f64, ConfigError> {
+    interfaces::get_float64(key)
+}
+
+pub fn get_bool(key: &str) -> Result<bool, ConfigError> {
+    interfaces::get_bool(key)
+}
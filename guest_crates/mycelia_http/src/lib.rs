@@ -21,10 +21,15 @@ mod bindgen {
 use bindgen::mycelia_alpha::http::types::{*};
 
 pub type Client = bindgen::mycelia_alpha::http::interfaces::Client;
+pub type IncomingBody = bindgen::mycelia_alpha::http::interfaces::IncomingBody;
+pub type HttpStreamingResponse = bindgen::mycelia_alpha::http::interfaces::StreamingResponse;
+pub type HttpStreamingResult = bindgen::mycelia_alpha::http::interfaces::StreamingResult;
 pub type HttpRequest = ClientRequest;
 pub type HttpResponse = ClientResponse;
 pub type HttpResult = ClientResult;
 pub type HttpMethod = Method;
+pub type HttpOptions = Options;
+pub type HttpError = ClientError;
 
 /// Facade for producing a new client.
 /// The returned client can be used to make http requests on behalf of the guest
@@ -39,6 +44,12 @@ pub struct HttpClient {
 }
 
 impl HttpClient {
+    /// set the default options for requests sent by this client.
+    /// options set on a request take precedence
+    pub fn set_options(&mut self, options: HttpOptions) {
+        self.inner.set_options(options)
+    }
+
     /// send a http request
     ///
     /// note this operation appears blocking to you but is async in the host
@@ -46,4 +57,12 @@ impl HttpClient {
     pub fn send(&mut self, request: &HttpRequest) -> HttpResult {
         self.inner.send(request)
     }
+
+    /// send a http request, reading the response body as it arrives
+    ///
+    /// responses sent with `send` are buffered by the host and limited in size.
+    /// prefer this for large or long lived responses
+    pub fn send_streaming(&mut self, request: &HttpRequest) -> HttpStreamingResult {
+        self.inner.send_streaming(request)
+    }
 }
@@ -0,0 +1,65 @@
+//! A convenient rust crate providing a wasm key value store
+//! see `resource_providers::kv` for the host side code
+//!
+//! Usage of this crate isn't necessary as long as your
+//! usage complies with the .wit contract specified
+
+mod bindgen {
+    wit_bindgen::generate!({
+        // the name of the world in the `*.wit` input file
+        world: "command",
+    });
+}
+
+pub type Store = bindgen::mycelia_alpha::kv::interfaces::Store;
+pub type KvError = bindgen::mycelia_alpha::kv::types::KvError;
+
+/// Facade for opening the component's store.
+/// Values written here are visible to later invocations of the same component
+pub fn open_store() -> KvStore {
+    KvStore {
+        inner: Store::new(),
+    }
+}
+
+pub struct KvStore {
+    inner: Store,
+}
+
+impl KvStore {
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, KvError> {
+        self.inner.get(key)
+    }
+
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<(), KvError> {
+        self.inner.set(key, value)
+    }
+
+    /// returns whether the key existed
+    pub fn delete(&self, key: &str) -> Result<bool, KvError> {
+        self.inner.delete(key)
+    }
+
+    /// at most `limit` keys starting with `prefix`, in lexicographic order.
+    /// pass the last key returned as `start_after` to read the next page,
+    /// an empty page means there are no more keys
+    pub fn list_keys(
+        &self,
+        prefix: &str,
+        start_after: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(prefix, start_after, limit)
+    }
+
+    /// sets `key` to `new` only if it currently holds `expected`.
+    /// `None` means the key must not exist. returns whether the value was replaced
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool, KvError> {
+        self.inner.compare_and_swap(key, expected, new)
+    }
+}
@@ -0,0 +1,47 @@
+//! A convenient rust crate providing a wasm component structured logging
+//! see `resource_providers::logging` for the host side code
+//!
+//! Usage of this crate isn't necessary as long as your
+//! usage complies with the .wit contract specified
+
+mod bindgen {
+    wit_bindgen::generate!({
+        // the name of the world in the `*.wit` input file
+        world: "command",
+    });
+}
+
+use bindgen::mycelia_alpha::logging::interfaces;
+
+pub type Level = bindgen::mycelia_alpha::logging::types::Level;
+
+/// records `message` with `fields` in the host's log.
+/// The host tags it with the component's name and the id of the request being handled
+pub fn log(level: Level, message: &str, fields: &[(&str, &str)]) {
+    interfaces::log(level, message, fields)
+}
+
+/// whether messages at `level` are recorded
+pub fn enabled(level: Level) -> bool {
+    interfaces::enabled(level)
+}
+
+pub fn trace(message: &str, fields: &[(&str, &str)]) {
+    log(Level::Trace, message, fields)
+}
+
+pub fn debug(message: &str, fields: &[(&str, &str)]) {
+    log(Level::Debug, message, fields)
+}
+
+pub fn info(message: &str, fields: &[(&str, &str)]) {
+    log(Level::Info, message, fields)
+}
+
+pub fn warn(message: &str, fields: &[(&str, &str)]) {
+    log(Level::Warn, message, fields)
+}
+
+pub fn error(message: &str, fields: &[(&str, &str)]) {
+    log(Level::Error, message, fields)
+}
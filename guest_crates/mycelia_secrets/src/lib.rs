@@ -0,0 +1,20 @@
+//! A convenient rust crate providing a wasm component the secrets it was granted
+//! see `resource_providers::secrets` for the host side code
+//!
+//! Usage of this crate isn't necessary as long as your
+//! usage complies with the .wit contract specified
+
+mod bindgen {
+    wit_bindgen::generate!({
+        // the name of the world in the `*.wit` input file
+        world: "command",
+    });
+}
+
+pub type SecretsError = bindgen::mycelia_alpha::secrets::types::SecretsError;
+
+/// the value of the secret `name`.
+/// Read secrets when they're needed rather than keeping them in globals
+pub fn get(name: &str) -> Result<String, SecretsError> {
+    bindgen::mycelia_alpha::secrets::interfaces::get(name)
+}
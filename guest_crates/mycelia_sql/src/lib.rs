@@ -0,0 +1,46 @@
+//! A convenient rust crate providing a wasm sqlite database
+//! see `resource_providers::sql` for the host side code
+//!
+//! Usage of this crate isn't necessary as long as your
+//! usage complies with the .wit contract specified
+
+mod bindgen {
+    wit_bindgen::generate!({
+        // the name of the world in the `*.wit` input file
+        world: "command",
+    });
+}
+
+use bindgen::mycelia_alpha::sql::types::*;
+
+pub type Connection = bindgen::mycelia_alpha::sql::interfaces::Connection;
+pub type Statement = bindgen::mycelia_alpha::sql::interfaces::Statement;
+pub type Value = SqlValue;
+pub type Row = Vec<SqlValue>;
+pub type Rows = QueryResult;
+pub type Executed = ExecuteResult;
+pub type Error = SqlError;
+
+/// Facade for connecting to the component's database.
+/// Every instance of the component shares the same database
+pub fn connect() -> Connection {
+    Connection::new()
+}
+
+/// Runs `f` in a transaction, committing when it succeeds and rolling back when it fails
+pub fn transaction<T>(
+    connection: &Connection,
+    f: impl FnOnce(&Connection) -> Result<T, Error>,
+) -> Result<T, Error> {
+    connection.begin()?;
+    match f(connection) {
+        Ok(value) => {
+            connection.commit()?;
+            Ok(value)
+        }
+        Err(e) => {
+            let _ = connection.rollback();
+            Err(e)
+        }
+    }
+}
@@ -26,6 +26,7 @@ impl Guest for TestFunction {
             headers: vec![],
             body: vec![],
             uri: "https://google.com".to_string(),
+            options: None,
         };
 
         client.send(&request);
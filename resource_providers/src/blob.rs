@@ -0,0 +1,381 @@
+//! Host side implementations for providing wasm guests object storage.
+//! Guests open named `container` resources and put, get, list and delete objects in them.
+//! Opening a container and each operation on it are requests to Tower services,
+//! so containers can be backed by a local directory or a remote object store.
+//!
+//! # Usage
+//! Link the interface with `add_to_linker` and give every store a `HostBlobResource`
+//! whose maker opens containers belonging to the component being run,
+//! see `providers::blob_store_fs` for the default filesystem backed containers.
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tower::util::BoxService;
+use wasmtime::component::{Linker, Resource};
+
+use crate::core::{
+    unavailable_maker, HostResourceMaker, ResourceHost, ResourceOpenError, ResourceTableError,
+};
+
+use self::bindgen::mycelia_alpha::blob::interfaces::{Container, HostContainer};
+use self::bindgen::Command;
+
+pub use self::bindgen::mycelia_alpha::blob::types::{BlobError, ObjectMetadata};
+
+mod bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../guest_crates/mycelia_blob/wit",
+      world: "command",
+      async: true
+    });
+}
+
+/// An operation a guest performs on a container
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobRequest {
+    Put {
+        name: String,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    },
+    Get {
+        name: String,
+    },
+    GetRange {
+        name: String,
+        offset: u64,
+        length: u64,
+    },
+    Metadata {
+        name: String,
+    },
+    List {
+        prefix: String,
+        start_after: Option<String>,
+        limit: u32,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+/// The result of a `BlobRequest`
+#[derive(Debug, Clone)]
+pub enum BlobResponse {
+    Object(ObjectMetadata),
+    Data(Vec<u8>),
+    Objects(Vec<ObjectMetadata>),
+    Deleted(bool),
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when opening a container for a guest
+pub enum ContainerMakeError {
+    #[error("no object storage is available to this guest")]
+    Unavailable,
+    #[error("invalid container name - {reason}")]
+    InvalidName { reason: String },
+    #[error("failed to open the container - {cause}")]
+    Storage { cause: String },
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when the host performs an operation on behalf of a guest
+pub enum BlobStoreError {
+    #[error("container isn't ready. wait and try again.")]
+    NotReady,
+    #[error("object {name} not found")]
+    NotFound { name: String },
+    #[error("invalid object name - {reason}")]
+    InvalidName { reason: String },
+    #[error("object exceeded the limit of {limit} bytes")]
+    TooLarge { limit: u64 },
+    #[error("component exceeded its quota of {limit} bytes")]
+    QuotaExceeded { limit: u64 },
+    #[error("storage failure - {cause}")]
+    Storage { cause: String },
+    /// The container answered with a response for a different kind of request
+    #[error("container produced an unexpected response")]
+    UnexpectedResponse,
+    #[error("guest tried to use a container which does not exist")]
+    HostResourceNotFound,
+}
+
+impl From<ResourceTableError> for BlobStoreError {
+    fn from(_: ResourceTableError) -> Self {
+        BlobStoreError::HostResourceNotFound
+    }
+}
+
+impl From<BlobStoreError> for BlobError {
+    fn from(e: BlobStoreError) -> Self {
+        match e {
+            BlobStoreError::NotReady => BlobError::Busy,
+            BlobStoreError::NotFound { name } => BlobError::NotFound(name),
+            BlobStoreError::InvalidName { reason } => BlobError::InvalidName(reason),
+            BlobStoreError::TooLarge { limit } => BlobError::TooLarge(limit),
+            BlobStoreError::QuotaExceeded { .. } => BlobError::QuotaExceeded,
+            BlobStoreError::Storage { cause } => BlobError::Other(cause),
+            BlobStoreError::UnexpectedResponse | BlobStoreError::HostResourceNotFound => {
+                BlobError::Other(e.to_string())
+            }
+        }
+    }
+}
+
+impl From<ContainerMakeError> for BlobError {
+    fn from(e: ContainerMakeError) -> Self {
+        match e {
+            ContainerMakeError::InvalidName { reason } => BlobError::InvalidName(reason),
+            ContainerMakeError::Storage { cause } => BlobError::Other(cause),
+            ContainerMakeError::Unavailable => BlobError::Other(e.to_string()),
+        }
+    }
+}
+
+/// Abstract service type defining a container of objects,
+/// for example see `providers::blob_store_fs::FsContainer`
+pub type HostBlobContainer = BoxService<BlobRequest, BlobResponse, BlobStoreError>;
+
+/// Abstract service type for a thing which opens HostBlobContainers by name
+pub type HostBlobContainerMaker = BoxService<String, HostBlobContainer, ContainerMakeError>;
+
+/// A maker for guests which aren't given object storage. Opening a container traps the guest
+pub fn unavailable_container_maker() -> HostBlobContainerMaker {
+    unavailable_maker(|| ContainerMakeError::Unavailable)
+}
+
+/// The containers a guest opened, see `ResourceHost`
+pub type HostBlobResource = ResourceHost<HostBlobContainerMaker, HostBlobContainer>;
+
+/// Sends a request expecting `BlobResponse::Object`
+async fn call_object(
+    resource: &mut HostBlobResource,
+    id: u32,
+    req: BlobRequest,
+) -> anyhow::Result<Result<ObjectMetadata, BlobError>> {
+    Ok(match resource.call(id, req).await {
+        Ok(BlobResponse::Object(object)) => Ok(object),
+        Ok(_) => Err(BlobStoreError::UnexpectedResponse.into()),
+        Err(e) => Err(e.into()),
+    })
+}
+
+/// Sends a request expecting `BlobResponse::Data`
+async fn call_data(
+    resource: &mut HostBlobResource,
+    id: u32,
+    req: BlobRequest,
+) -> anyhow::Result<Result<Vec<u8>, BlobError>> {
+    Ok(match resource.call(id, req).await {
+        Ok(BlobResponse::Data(data)) => Ok(data),
+        Ok(_) => Err(BlobStoreError::UnexpectedResponse.into()),
+        Err(e) => Err(e.into()),
+    })
+}
+
+#[async_trait]
+impl bindgen::mycelia_alpha::blob::interfaces::Host for HostBlobResource {
+    /// Opens a container and returns the resource to the guest.
+    /// Invalid names and storage failures are returned to the guest as a `BlobError`,
+    /// a guest without object storage is trapped like one without a store or database
+    async fn open_container(
+        &mut self,
+        name: String,
+    ) -> anyhow::Result<Result<Resource<Container>, BlobError>> {
+        match self.open(name).await {
+            Ok(id) => Ok(Ok(Resource::new_own(id))),
+            Err(ResourceOpenError::Ids(_)) => Ok(Err(BlobError::Busy)),
+            Err(ResourceOpenError::Make(ContainerMakeError::Unavailable)) => {
+                Err(ContainerMakeError::Unavailable.into())
+            }
+            Err(ResourceOpenError::Make(e)) => Ok(Err(e.into())),
+            Err(e @ ResourceOpenError::Table(_)) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl HostContainer for HostBlobResource {
+    async fn put(
+        &mut self,
+        guest_self: Resource<Container>,
+        name: String,
+        data: Vec<u8>,
+        content_type: Option<String>,
+    ) -> anyhow::Result<Result<ObjectMetadata, BlobError>> {
+        let req = BlobRequest::Put {
+            name,
+            data,
+            content_type,
+        };
+        call_object(self, guest_self.rep(), req).await
+    }
+
+    async fn get(
+        &mut self,
+        guest_self: Resource<Container>,
+        name: String,
+    ) -> anyhow::Result<Result<Vec<u8>, BlobError>> {
+        let req = BlobRequest::Get { name };
+        call_data(self, guest_self.rep(), req).await
+    }
+
+    async fn get_range(
+        &mut self,
+        guest_self: Resource<Container>,
+        name: String,
+        offset: u64,
+        length: u64,
+    ) -> anyhow::Result<Result<Vec<u8>, BlobError>> {
+        let req = BlobRequest::GetRange {
+            name,
+            offset,
+            length,
+        };
+        call_data(self, guest_self.rep(), req).await
+    }
+
+    async fn metadata(
+        &mut self,
+        guest_self: Resource<Container>,
+        name: String,
+    ) -> anyhow::Result<Result<ObjectMetadata, BlobError>> {
+        let req = BlobRequest::Metadata { name };
+        call_object(self, guest_self.rep(), req).await
+    }
+
+    async fn list(
+        &mut self,
+        guest_self: Resource<Container>,
+        prefix: String,
+        start_after: Option<String>,
+        limit: u32,
+    ) -> anyhow::Result<Result<Vec<ObjectMetadata>, BlobError>> {
+        let req = BlobRequest::List {
+            prefix,
+            start_after,
+            limit,
+        };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(BlobResponse::Objects(objects)) => Ok(objects),
+            Ok(_) => Err(BlobStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn delete(
+        &mut self,
+        guest_self: Resource<Container>,
+        name: String,
+    ) -> anyhow::Result<Result<bool, BlobError>> {
+        let req = BlobRequest::Delete { name };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(BlobResponse::Deleted(existed)) => Ok(existed),
+            Ok(_) => Err(BlobStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    /// Called when a container is released by a guest.
+    /// Releasing a container which does not exist traps the guest instance
+    fn drop(&mut self, val: Resource<Container>) -> anyhow::Result<()> {
+        self.remove(val.rep())?;
+        Ok(())
+    }
+}
+
+impl bindgen::mycelia_alpha::blob::types::Host for HostBlobResource {}
+
+/// Tells the linker how to provide guests access to their containers
+pub fn add_to_linker<T: HostResourceMaker<HostBlobResource> + Send>(
+    linker: &mut Linker<T>,
+) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostBlobResource>(linker, |v| v.host_resource())
+}
+
+#[cfg(test)]
+mod test {
+    use tower::service_fn;
+
+    use super::bindgen::mycelia_alpha::blob::interfaces::Host;
+    use super::*;
+    use crate::core::ResourceIdPool;
+
+    /// Opens containers which answer every request with the container's name
+    fn named_container_maker() -> HostBlobContainerMaker {
+        BoxService::new(service_fn(|name: String| async move {
+            if name.is_empty() {
+                return Err(ContainerMakeError::InvalidName {
+                    reason: "empty".to_string(),
+                });
+            }
+            let container = service_fn(move |_: BlobRequest| {
+                let data = name.clone().into_bytes();
+                async move { Ok::<_, BlobStoreError>(BlobResponse::Data(data)) }
+            });
+            Ok::<HostBlobContainer, ContainerMakeError>(BoxService::new(container))
+        }))
+    }
+
+    #[tokio::test]
+    async fn it_opens_containers_by_name() {
+        let id_pool = ResourceIdPool::new(2);
+        let mut resource = HostBlobResource::with_id_pool(named_container_maker(), id_pool.clone());
+
+        let uploads = resource
+            .open_container("uploads".into())
+            .await
+            .unwrap()
+            .unwrap();
+        let uploads_id = uploads.rep();
+        let data = resource.get(uploads, "a".into()).await.unwrap().unwrap();
+        assert_eq!(data, b"uploads");
+
+        // Mismatched responses are returned to the guest, not trapped
+        let result = resource
+            .delete(Resource::new_own(uploads_id), "a".into())
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(BlobError::Other(_))));
+
+        let result = resource.open_container("".into()).await.unwrap();
+        assert!(matches!(result, Err(BlobError::InvalidName(_))));
+        assert_eq!(id_pool.live(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_limits_open_containers() {
+        let id_pool = ResourceIdPool::new(1);
+        let mut resource = HostBlobResource::with_id_pool(named_container_maker(), id_pool.clone());
+
+        let assets = resource
+            .open_container("assets".into())
+            .await
+            .unwrap()
+            .unwrap();
+        let result = resource.open_container("uploads".into()).await.unwrap();
+        assert!(matches!(result, Err(BlobError::Busy)));
+
+        let assets_id = assets.rep();
+        HostContainer::drop(&mut resource, assets).unwrap();
+        assert!(HostContainer::drop(&mut resource, Resource::new_own(assets_id)).is_err());
+        assert!(resource
+            .open_container("uploads".into())
+            .await
+            .unwrap()
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_traps_without_object_storage() {
+        let id_pool = ResourceIdPool::new(1);
+        let mut resource =
+            HostBlobResource::with_id_pool(unavailable_container_maker(), id_pool.clone());
+        assert!(resource.open_container("uploads".into()).await.is_err());
+        // The id isn't leaked
+        assert_eq!(id_pool.live(), 0);
+    }
+}
@@ -0,0 +1,135 @@
+//! Host side implementation for providing wasm guests their deploy time configuration.
+//! Values are plain strings set when a component is deployed, the typed getters
+//! parse them on the host so every guest reads them the same way.
+//!
+//! # Usage
+//! Link the interface with `add_to_linker` and give every store a `HostConfig`
+//! holding the values of the component being run. Stores share the values, so
+//! a redeploy is the only way they change.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use wasmtime::component::Linker;
+
+use self::bindgen::Command;
+
+pub use self::bindgen::mycelia_alpha::config::types::ConfigError;
+
+mod bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../guest_crates/mycelia_config/wit",
+      world: "command",
+      async: true
+    });
+}
+
+/// Configuration values keyed by name, shared by every store of a component
+pub type ConfigValues = Arc<BTreeMap<String, String>>;
+
+/// A guest's view of its configuration
+#[derive(Debug, Clone, Default)]
+pub struct HostConfig {
+    values: ConfigValues,
+}
+
+impl HostConfig {
+    pub fn new(values: ConfigValues) -> Self {
+        Self { values }
+    }
+
+    fn parse<T: FromStr>(&self, key: &str, kind: &str) -> Result<T, ConfigError> {
+        let value = self
+            .values
+            .get(key)
+            .ok_or_else(|| ConfigError::NotFound(key.to_string()))?;
+        value
+            .trim()
+            .parse()
+            .map_err(|_| ConfigError::Invalid(format!("{} is not {}", key, kind)))
+    }
+}
+
+#[async_trait]
+impl bindgen::mycelia_alpha::config::interfaces::Host for HostConfig {
+    async fn get(&mut self, key: String) -> anyhow::Result<Option<String>> {
+        Ok(self.values.get(&key).cloned())
+    }
+
+    async fn get_all(&mut self) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(self
+            .values
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn get_s64(&mut self, key: String) -> anyhow::Result<Result<i64, ConfigError>> {
+        Ok(self.parse(&key, "a whole number"))
+    }
+
+    async fn get_float64(&mut self, key: String) -> anyhow::Result<Result<f64, ConfigError>> {
+        Ok(self.parse(&key, "a number"))
+    }
+
+    async fn get_bool(&mut self, key: String) -> anyhow::Result<Result<bool, ConfigError>> {
+        Ok(self.parse(&key, "true or false"))
+    }
+}
+
+impl bindgen::mycelia_alpha::config::types::Host for HostConfig {}
+
+/// Implemented by store data which provides guests their configuration
+pub trait HostConfigMaker {
+    fn config(&mut self) -> &mut HostConfig;
+}
+
+/// Tells the linker how to provide guests their configuration
+pub fn add_to_linker<T: HostConfigMaker + Send>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostConfig>(linker, |v| v.config())
+}
+
+#[cfg(test)]
+mod test {
+    use super::bindgen::mycelia_alpha::config::interfaces::Host;
+    use super::*;
+
+    #[tokio::test]
+    async fn it_reads_typed_values() {
+        let values = [("debug", "true"), ("players", " 4 "), ("ratio", "0.5")]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let mut config = HostConfig::new(Arc::new(values));
+
+        assert_eq!(
+            config.get("debug".into()).await.unwrap().as_deref(),
+            Some("true")
+        );
+        assert_eq!(config.get("missing".into()).await.unwrap(), None);
+        let keys: Vec<_> = config
+            .get_all()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, ["debug", "players", "ratio"]);
+
+        assert!(config.get_bool("debug".into()).await.unwrap().unwrap());
+        assert_eq!(config.get_s64("players".into()).await.unwrap().unwrap(), 4);
+        assert_eq!(
+            config.get_float64("ratio".into()).await.unwrap().unwrap(),
+            0.5
+        );
+
+        let result = config.get_s64("ratio".into()).await.unwrap();
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+        let result = config.get_bool("missing".into()).await.unwrap();
+        assert!(matches!(result, Err(ConfigError::NotFound(key)) if key == "missing"));
+    }
+}
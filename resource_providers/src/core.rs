@@ -8,8 +8,15 @@
 //! - TLS needs to be enabled in the `providers::hyper` module's client.
 //! - Error handling and its nuances need further refinement.
 
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::future::{ready, Ready};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
 use thiserror::Error;
 use tower::util::BoxService;
+use tower::{service_fn, Service, ServiceExt};
 
 #[derive(Error, Debug)]
 /// Errors associated with ID production.
@@ -26,5 +33,329 @@ pub enum IdProductionError {
 }
 
 /// A service that generates unique resource identifiers for wasm component resource providers.
-/// TODO we need to provide a concrete implementation
+/// See `ResourceIdPool` for the default implementation
 pub type HostResourceIdProvider = BoxService<(), u32, IdProductionError>;
+
+/// Issues resource ids for a single guest.
+///
+/// At most `max_live` ids are handed out at once, further requests fail with
+/// `IdProductionError::NotReady` until the guest releases some of its resources.
+/// Released ids are reused before new ones are minted.
+#[derive(Clone, Debug)]
+pub struct ResourceIdPool {
+    state: Arc<Mutex<IdPoolState>>,
+}
+
+#[derive(Debug)]
+struct IdPoolState {
+    next: u32,
+    free: Vec<u32>,
+    live: HashSet<u32>,
+    max_live: usize,
+}
+
+impl ResourceIdPool {
+    pub fn new(max_live: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(IdPoolState {
+                next: 0,
+                free: vec![],
+                live: HashSet::new(),
+                max_live,
+            })),
+        }
+    }
+
+    /// A boxed provider drawing from this pool
+    pub fn provider(&self) -> HostResourceIdProvider {
+        BoxService::new(self.clone())
+    }
+
+    /// Returns `id` to the pool. Ids which aren't live are ignored
+    pub fn release(&self, id: u32) {
+        let mut state = self.state.lock().expect("id pool lock poisoned");
+        if state.live.remove(&id) {
+            state.free.push(id);
+        }
+    }
+
+    /// The number of ids currently handed out
+    pub fn live(&self) -> usize {
+        self.state.lock().expect("id pool lock poisoned").live.len()
+    }
+
+    fn allocate(&self) -> Result<u32, IdProductionError> {
+        let mut state = self.state.lock().expect("id pool lock poisoned");
+        if state.live.len() >= state.max_live {
+            return Err(IdProductionError::NotReady);
+        }
+
+        let id = match state.free.pop() {
+            Some(id) => id,
+            None => {
+                let id = state.next;
+                // Every id is live, which can only happen with an absurd `max_live`
+                state.next = id.checked_add(1).ok_or(IdProductionError::NotReady)?;
+                id
+            }
+        };
+
+        state.live.insert(id);
+        Ok(id)
+    }
+}
+
+impl Service<()> for ResourceIdPool {
+    type Response = u32;
+    type Error = IdProductionError;
+    type Future = Ready<Result<u32, IdProductionError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: ()) -> Self::Future {
+        ready(self.allocate())
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// Errors raised when a guest references resources it does not own
+pub enum ResourceTableError {
+    #[error("resource id {0} is already in use")]
+    Occupied(u32),
+    #[error("resource id {0} does not exist")]
+    NotFound(u32),
+}
+
+/// Tracks the host side state behind the resources handed to a single guest store.
+///
+/// Ids are produced by a `HostResourceIdProvider` and must be unique within the table.
+pub struct ResourceTable<T> {
+    entries: HashMap<u32, T>,
+}
+
+impl<T> Default for ResourceTable<T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<T> ResourceTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Associates `value` with `id`. Fails if `id` is already live
+    pub fn insert(&mut self, id: u32, value: T) -> Result<(), ResourceTableError> {
+        match self.entries.entry(id) {
+            Entry::Occupied(_) => Err(ResourceTableError::Occupied(id)),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Result<&T, ResourceTableError> {
+        self.entries
+            .get(&id)
+            .ok_or(ResourceTableError::NotFound(id))
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Result<&mut T, ResourceTableError> {
+        self.entries
+            .get_mut(&id)
+            .ok_or(ResourceTableError::NotFound(id))
+    }
+
+    pub fn remove(&mut self, id: u32) -> Result<T, ResourceTableError> {
+        self.entries
+            .remove(&id)
+            .ok_or(ResourceTableError::NotFound(id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Errors which might occur when making a resource for a guest
+#[derive(Error, Debug)]
+pub enum ResourceOpenError<E> {
+    /// The guest holds as many resources as it may
+    #[error("no resource id is available - {0}")]
+    Ids(#[from] IdProductionError),
+    #[error(transparent)]
+    Make(E),
+    /// A duplicate id is indicative of a bug in the upstream id provider
+    #[error(transparent)]
+    Table(#[from] ResourceTableError),
+}
+
+/// Makes resources for a guest with `M` and keeps the resulting services under the ids the
+/// guest refers to them by.
+///
+/// Each wasmtime store gets its own host so guests can only reach the resources they opened.
+/// Interfaces implement their bindings on a host, e.g. `kv::HostKvResource`, lowering host
+/// failures into the error variant their guests see.
+pub struct ResourceHost<M, S> {
+    pub resource_id_provider: HostResourceIdProvider,
+    pub maker: M,
+    pub resources: ResourceTable<S>,
+    /// Released ids are returned here when ids come from a `ResourceIdPool`
+    pub id_pool: Option<ResourceIdPool>,
+}
+
+impl<M, S> ResourceHost<M, S> {
+    pub fn new(maker: M, resource_id_provider: HostResourceIdProvider) -> Self {
+        Self {
+            resource_id_provider,
+            maker,
+            resources: Default::default(),
+            id_pool: None,
+        }
+    }
+
+    /// Creates a host which draws its ids from `id_pool`, capping the resources a guest may hold open
+    pub fn with_id_pool(maker: M, id_pool: ResourceIdPool) -> Self {
+        Self {
+            id_pool: Some(id_pool.clone()),
+            ..Self::new(maker, id_pool.provider())
+        }
+    }
+
+    /// Draws an id for a new resource, see `release_id`
+    pub async fn next_id(&mut self) -> Result<u32, IdProductionError> {
+        self.resource_id_provider.ready().await?.call(()).await
+    }
+
+    /// Returns `id` to the pool it was drawn from, if any
+    pub fn release_id(&self, id: u32) {
+        if let Some(id_pool) = &self.id_pool {
+            id_pool.release(id);
+        }
+    }
+
+    /// Makes a resource from `req` and returns its id.
+    /// The id is released again if making the resource fails
+    pub async fn open<Req>(&mut self, req: Req) -> Result<u32, ResourceOpenError<M::Error>>
+    where
+        M: Service<Req>,
+        S: From<M::Response>,
+    {
+        let id = self.next_id().await?;
+        let made = match self.maker.ready().await {
+            Ok(maker) => maker.call(req).await,
+            Err(e) => Err(e),
+        };
+        match made {
+            Ok(resource) => {
+                self.resources.insert(id, resource.into())?;
+                Ok(id)
+            }
+            Err(e) => {
+                self.release_id(id);
+                Err(ResourceOpenError::Make(e))
+            }
+        }
+    }
+
+    /// Sends `req` to the resource behind `id`
+    pub async fn call<Req>(&mut self, id: u32, req: Req) -> Result<S::Response, S::Error>
+    where
+        S: Service<Req>,
+        S::Error: From<ResourceTableError>,
+    {
+        let resource = self.resources.get_mut(id)?;
+        resource.ready().await?.call(req).await
+    }
+
+    /// Removes the resource behind `id` once the guest releases it, freeing its id.
+    /// Fails if the guest doesn't hold `id`
+    pub fn remove(&mut self, id: u32) -> Result<S, ResourceTableError> {
+        let resource = self.resources.remove(id)?;
+        self.release_id(id);
+        Ok(resource)
+    }
+}
+
+/// Implemented by store data which provides guests the resources of `R`,
+/// e.g. a `kv::HostKvResource`. Each interface's `add_to_linker` requires it
+pub trait HostResourceMaker<R> {
+    fn host_resource(&mut self) -> &mut R;
+}
+
+/// A maker for guests which aren't given a provider, every request fails with `unavailable()`
+pub fn unavailable_maker<Req, S, E>(unavailable: fn() -> E) -> BoxService<Req, S, E>
+where
+    Req: 'static,
+    S: Send + 'static,
+    E: Send + 'static,
+{
+    BoxService::new(service_fn(move |_: Req| ready(Err(unavailable()))))
+}
+
+/// Whether `name` may identify a component's storage, it must match `[a-z0-9_-]+`.
+///
+/// Providers use the name as a file name or database key, so anything else is rejected
+/// before it reaches the disk.
+pub fn is_valid_storage_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'_' | b'-'))
+}
+
+#[cfg(test)]
+mod test {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_issues_unique_ids_up_to_the_limit() {
+        let pool = ResourceIdPool::new(3);
+        let mut provider = pool.provider();
+
+        let mut ids = HashSet::new();
+        for _ in 0..3 {
+            ids.insert(provider.ready().await.unwrap().call(()).await.unwrap());
+        }
+        assert_eq!(ids.len(), 3);
+        assert_eq!(pool.live(), 3);
+
+        let result = provider.ready().await.unwrap().call(()).await;
+        assert!(matches!(result, Err(IdProductionError::NotReady)));
+    }
+
+    #[tokio::test]
+    async fn it_reuses_released_ids() {
+        let pool = ResourceIdPool::new(1);
+        let mut provider = pool.provider();
+
+        let id = provider.ready().await.unwrap().call(()).await.unwrap();
+        pool.release(id);
+        // Releasing twice must not let the guest exceed its limit
+        pool.release(id);
+
+        assert_eq!(provider.ready().await.unwrap().call(()).await.unwrap(), id);
+        assert!(provider.ready().await.unwrap().call(()).await.is_err());
+    }
+
+    #[test]
+    fn it_validates_storage_names() {
+        for name in ["counter", "my-app_2", "_base"] {
+            assert!(is_valid_storage_name(name), "{}", name);
+        }
+        for name in ["", "Counter", "../counter", "a/b", "a.b", "caf\u{e9}"] {
+            assert!(!is_valid_storage_name(name), "{}", name);
+        }
+    }
+}
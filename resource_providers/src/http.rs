@@ -7,18 +7,24 @@
 //! This is intended to be used by the wasmtime hosts (like `mycelia`) to provide HTTP clients to wasm component guests.
 //! It's pretty straightforward :)
 
-use core::panic;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::fmt;
+use std::time::Duration;
 
 use async_trait::async_trait;
+use hyper::body::{Bytes, HttpBody};
 
 use tower::{util::BoxService, Service, ServiceExt};
 use wasmtime::component::{Component, Linker, Resource};
 use wasmtime::Store;
 
-use crate::core::HostResourceIdProvider;
+use crate::core::{
+    unavailable_maker, HostResourceIdProvider, ResourceHost, ResourceIdPool, ResourceTable,
+};
 
-use self::bindgen::mycelia_alpha::http::interfaces::Client;
+use self::bindgen::mycelia_alpha::http::interfaces::{
+    Client, HostIncomingBody, IncomingBody, StreamingResponse, StreamingResult,
+};
 
 /// Provides the host side implementation for a client resource.
 ///
@@ -32,7 +38,15 @@ use self::bindgen::mycelia_alpha::http::interfaces::HostClient as HostClientInte
 use self::bindgen::Command;
 
 pub use self::bindgen::mycelia_alpha::http::interfaces::{ClientRequest, ClientResult};
-pub use self::bindgen::mycelia_alpha::http::types::{ClientResponse, Method};
+pub use self::bindgen::mycelia_alpha::http::types::{
+    ClientError, ClientResponse, Headers, Method, Options, TimeoutError, TimeoutPhase,
+};
+
+pub mod egress;
+pub mod limits;
+
+pub use self::egress::{with_egress_policy, EgressPolicy, EgressPolicyLayer, HostPattern};
+pub use self::limits::{OutboundLimiter, OutboundLimits, RateLimit, RequestLimits};
 
 // this helps with syntax completion as it isn't entirely obvious what's happening under the hood
 // if you get really stuck you can use `cargo expand >> expanded.rs` to view the generated code
@@ -53,6 +67,8 @@ use thiserror::Error;
 pub enum ClientMakeError {
     #[error("wasm guest resource not found.")]
     NotFound,
+    #[error("no http client is available to this guest")]
+    Unavailable,
     #[error("attempted to associate a resource to an existing id")]
     BadResourceId,
 }
@@ -69,6 +85,20 @@ pub enum HttpClientError {
     ClientError { cause: String },
     #[error("guest produced a malformed request")]
     BadRequest,
+    #[error("request timed out during {phase:?} after {limit_ms}ms")]
+    Timeout { phase: TimeoutPhase, limit_ms: u32 },
+    #[error("dns lookup failed - {cause}")]
+    DnsFailure { cause: String },
+    #[error("connection refused - {cause}")]
+    ConnectRefused { cause: String },
+    #[error("tls failure - {cause}")]
+    TlsError { cause: String },
+    #[error("response body exceeded the limit of {limit} bytes")]
+    BodyTooLarge { limit: u64 },
+    #[error("request denied by policy - {reason}")]
+    PolicyDenied { reason: String },
+    #[error("too many requests. wait and try again.")]
+    RateLimited,
     /// This should never happen. If we cannot find a client the guest is
     /// either somehow intentionally requesting access to resources it should not access
     /// Or, more likely we have a bug tracking the lifetime of http client resources
@@ -76,6 +106,28 @@ pub enum HttpClientError {
     HostResourceNotFound,
 }
 
+impl From<HttpClientError> for ClientError {
+    /// Lowers host failures into the error variant returned to guests
+    fn from(e: HttpClientError) -> Self {
+        match e {
+            HttpClientError::NotReady | HttpClientError::RateLimited => ClientError::RateLimited,
+            HttpClientError::BadRequest => ClientError::BadRequest(e.to_string()),
+            HttpClientError::Timeout { phase, limit_ms } => {
+                ClientError::Timeout(TimeoutError { phase, limit_ms })
+            }
+            HttpClientError::DnsFailure { cause } => ClientError::DnsFailure(cause),
+            HttpClientError::ConnectRefused { cause } => ClientError::ConnectRefused(cause),
+            HttpClientError::TlsError { cause } => ClientError::TlsError(cause),
+            HttpClientError::BodyTooLarge { limit } => ClientError::BodyTooLarge(limit),
+            HttpClientError::PolicyDenied { reason } => ClientError::PolicyDenied(reason),
+            HttpClientError::ClientError { cause } => ClientError::Other(cause),
+            HttpClientError::Unknown | HttpClientError::HostResourceNotFound => {
+                ClientError::Other(e.to_string())
+            }
+        }
+    }
+}
+
 /// Abstract service type defining an HttpClient
 /// which can be provided by specific concrete implementations
 /// for example we have a `HyperHostClient`
@@ -85,11 +137,134 @@ pub type HostClient = BoxService<ClientRequest, ClientResult, HttpClientError>;
 /// for example see `providers::hyper::new_client_maker`
 pub type HostClientMaker = BoxService<(), HostClient, ClientMakeError>;
 
+/// A maker for guests which aren't given outbound http. Creating a client traps the guest
+pub fn unavailable_client_maker() -> HostClientMaker {
+    unavailable_maker(|| ClientMakeError::Unavailable)
+}
+
+/// Lists every value of every header as the raw bytes received.
+/// Repeated headers keep their order
+pub fn map_headers(headers: &http::HeaderMap) -> Headers {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+        .collect()
+}
+
+/// Headers which commonly carry credentials. Their values are left out of `Redacted` output
+/// and of recorded fixtures
+pub const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// Whether `name` is one of the `SENSITIVE_HEADERS`, ignoring case
+pub fn is_sensitive_header(name: &str) -> bool {
+    SENSITIVE_HEADERS
+        .iter()
+        .any(|sensitive| sensitive.eq_ignore_ascii_case(name))
+}
+
+/// Formats headers for logging without the values of `SENSITIVE_HEADERS`,
+/// so credentials and secrets sent to or returned by a guest aren't written to trace output
+pub struct Redacted<'a>(pub &'a Headers);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let entries = self.0.iter().map(|(name, value)| {
+            let value = if is_sensitive_header(name) {
+                Cow::Borrowed("[redacted]")
+            } else {
+                String::from_utf8_lossy(value)
+            };
+            (name, value)
+        });
+        f.debug_list().entries(entries).finish()
+    }
+}
+
+/// Proptest strategies for the values guests exchange with the host.
+/// Available to other crates with the `proptest` feature
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies {
+    use proptest::prelude::*;
+
+    use super::Headers;
+
+    /// Header names from a small alphabet, so generated sets often repeat a name,
+    /// with values of any bytes a header value may hold
+    pub fn headers() -> impl Strategy<Value = Headers> {
+        let value =
+            prop::collection::vec(prop_oneof![Just(b'\t'), 0x20u8..0x7f, 0x80u8..=0xff], 0..32);
+        prop::collection::vec(("[a-d][a-z0-9-]{0,3}", value), 0..16)
+    }
+}
+
+/// A response whose body is read incrementally by the guest
+pub struct StreamedResponse {
+    pub status: u16,
+    pub headers: Headers,
+    pub body: hyper::Body,
+}
+
+pub enum StreamedResult {
+    Ok(StreamedResponse),
+    Error(ClientError),
+}
+
+/// A client which leaves response bodies unread, see `providers::hyper::new_streaming_client_maker`
+pub type HostStreamingClient = BoxService<ClientRequest, StreamedResult, HttpClientError>;
+
+pub type HostStreamingClientMaker = BoxService<(), HostStreamingClient, ClientMakeError>;
+
+/// Produces clients responding with `R`. Lets layers apply to both `HostClientMaker`
+/// and `HostStreamingClientMaker`
+pub type ClientMaker<R> =
+    BoxService<(), BoxService<ClientRequest, R, HttpClientError>, ClientMakeError>;
+
+/// Host state behind a single guest http client resource
+pub struct HostClientEntry {
+    pub client: HostClient,
+    /// Made the first time the guest streams a response
+    pub streaming_client: Option<HostStreamingClient>,
+    /// Default request options set by the guest
+    pub options: Option<Options>,
+}
+
+impl From<HostClient> for HostClientEntry {
+    fn from(client: HostClient) -> Self {
+        Self {
+            client,
+            streaming_client: None,
+            options: None,
+        }
+    }
+}
+
+/// How long a guest waits for the next chunk of a streamed body before the read fails
+pub const DEFAULT_BODY_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Host state behind a guest `incoming-body` resource
+pub struct IncomingBodyState {
+    body: hyper::Body,
+    /// Data received but not yet read by the guest
+    pending: Option<Bytes>,
+}
+
 /// Manages the associations between guest wasm http clients and their host instances.
+///
+/// Each store gets its own `HostClientResource` so guests can only reach the clients they created.
 pub struct HostClientResource {
-    pub resource_id_provider: HostResourceIdProvider,
-    pub client_maker: HostClientMaker,
-    pub clients: HashMap<u32, HostClient>,
+    /// The clients a guest made, see `ResourceHost`. Streamed bodies draw their ids from it too
+    pub clients: ResourceHost<HostClientMaker, HostClientEntry>,
+    /// Without a streaming maker, streamed responses are buffered by the client first
+    pub streaming_client_maker: Option<HostStreamingClientMaker>,
+    pub bodies: ResourceTable<IncomingBodyState>,
+    /// Longest a single read of a streamed body may wait for data
+    pub body_read_timeout: Duration,
 }
 
 impl HostClientResource {
@@ -97,12 +272,28 @@ impl HostClientResource {
         client_maker: HostClientMaker,
         resource_id_provider: HostResourceIdProvider,
     ) -> Self {
+        Self::with_clients(ResourceHost::new(client_maker, resource_id_provider))
+    }
+
+    fn with_clients(clients: ResourceHost<HostClientMaker, HostClientEntry>) -> Self {
         Self {
-            resource_id_provider,
-            client_maker,
-            clients: Default::default(),
+            clients,
+            streaming_client_maker: None,
+            bodies: Default::default(),
+            body_read_timeout: DEFAULT_BODY_READ_TIMEOUT,
         }
     }
+
+    /// Lets guests read response bodies incrementally with clients from `streaming_client_maker`
+    pub fn with_streaming(mut self, streaming_client_maker: HostStreamingClientMaker) -> Self {
+        self.streaming_client_maker = Some(streaming_client_maker);
+        self
+    }
+
+    /// Creates a resource which draws its ids from `id_pool`, capping the clients a guest may hold
+    pub fn with_id_pool(client_maker: HostClientMaker, id_pool: ResourceIdPool) -> Self {
+        Self::with_clients(ResourceHost::with_id_pool(client_maker, id_pool))
+    }
 }
 
 #[async_trait]
@@ -113,52 +304,203 @@ impl HostClientInterface for HostClientResource {
     /// internally, we create a mapping of <ID, Client> so we can lookup the correct
     /// client to make the request.
     ///
+    /// An id which is already in use traps the guest instance rather than replacing the live client.
+    ///
     /// Note, in the future this will allow us to have precise control over
     /// the behavior of the host client. Rate limiting, logging, etc, request tagging, etc :D
     async fn new(&mut self) -> anyhow::Result<Resource<Client>> {
-        let rdy_client = self.resource_id_provider.ready().await?;
-        let new_id = rdy_client.call(()).await?;
-
-        let rdy_client = self.client_maker.ready().await?;
-        let new_client = rdy_client.call(()).await?;
-
-        if let Some(_) = self.clients.insert(new_id, new_client) {
-            // This is indicative of a bug in the upstream id provider client
-            panic!("Existing http_client resource found for resource id {:#?}", new_id)
-        }
+        Ok(Resource::new_own(self.clients.open(()).await?))
+    }
 
-        Ok(Resource::new_own(new_id))
+    /// Sets the default options for requests made with `guest_self`
+    async fn set_options(
+        &mut self,
+        guest_self: Resource<Client>,
+        options: Options,
+    ) -> anyhow::Result<()> {
+        self.clients.resources.get_mut(guest_self.rep())?.options = Some(options);
+        Ok(())
     }
 
     /// Attempts to make an HttpRequest `req` using some resource `guest_self`
+    ///
+    /// Failures making the request, including referencing an unknown client,
+    /// are returned to the guest as a `ClientError`. They never trap the guest instance.
     async fn send(
         &mut self,
         guest_self: Resource<Client>,
-        req: ClientRequest,
+        mut req: ClientRequest,
     ) -> anyhow::Result<ClientResult> {
-        let id = guest_self.rep();
-        match self.clients.get_mut(&id) {
-            Some(client) => {
-                let client = client.ready().await?;
-                Ok(client.call(req).await?)
+        let Ok(entry) = self.clients.resources.get_mut(guest_self.rep()) else {
+            return Ok(ClientResult::Error(
+                HttpClientError::HostResourceNotFound.into(),
+            ));
+        };
+
+        req.options = merge_options(req.options, entry.options.as_ref());
+        let result = match entry.client.ready().await {
+            Ok(client) => client.call(req).await,
+            Err(e) => Err(e),
+        };
+        Ok(result.unwrap_or_else(|e| ClientResult::Error(e.into())))
+    }
+
+    /// Like `send`, but the response body is handed to the guest as an `incoming-body`
+    async fn send_streaming(
+        &mut self,
+        guest_self: Resource<Client>,
+        req: ClientRequest,
+    ) -> anyhow::Result<StreamingResult> {
+        let response = match self.send_streamed(guest_self.rep(), req).await {
+            Ok(StreamedResult::Ok(response)) => response,
+            Ok(StreamedResult::Error(e)) => return Ok(StreamingResult::Error(e)),
+            Err(e) => return Ok(StreamingResult::Error(e.into())),
+        };
+
+        let Ok(body_id) = self.clients.next_id().await else {
+            return Ok(StreamingResult::Error(HttpClientError::NotReady.into()));
+        };
+        self.bodies
+            .insert(
+                body_id,
+                IncomingBodyState {
+                    body: response.body,
+                    pending: None,
+                },
+            )
+            .map_err(|_| ClientMakeError::BadResourceId)?;
+
+        Ok(StreamingResult::Ok(StreamingResponse {
+            status: response.status,
+            headers: response.headers,
+            body: Resource::new_own(body_id),
+        }))
+    }
+
+    /// Called when a resource is released by a guest.
+    /// Releasing a client which does not exist traps the guest instance
+    fn drop(&mut self, val: Resource<Client>) -> anyhow::Result<()> {
+        self.clients.remove(val.rep())?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HostIncomingBody for HostClientResource {
+    /// Reads the next chunk of a streamed response body, at most `max_bytes` long.
+    /// Fails with a timeout when no data arrives within `body_read_timeout`
+    async fn read(
+        &mut self,
+        guest_self: Resource<IncomingBody>,
+        max_bytes: u32,
+    ) -> anyhow::Result<Result<Option<Vec<u8>>, ClientError>> {
+        let read_timeout = self.body_read_timeout;
+        let Ok(state) = self.bodies.get_mut(guest_self.rep()) else {
+            return Ok(Err(HttpClientError::HostResourceNotFound.into()));
+        };
+
+        let mut chunk = match state.pending.take() {
+            Some(chunk) => chunk,
+            None => match tokio::time::timeout(read_timeout, state.body.data()).await {
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(e))) => return Ok(Err(HttpClientError::from(e).into())),
+                Ok(None) => return Ok(Ok(None)),
+                Err(_) => {
+                    let limit_ms = read_timeout.as_millis().try_into().unwrap_or(u32::MAX);
+                    let timeout = HttpClientError::Timeout {
+                        phase: TimeoutPhase::Total,
+                        limit_ms,
+                    };
+                    return Ok(Err(timeout.into()));
+                }
             },
-            None => panic!("client requested http_client resource id {:#?} which does not exist. Guest {:#?}", id, guest_self),
+        };
+
+        let max_bytes = max_bytes.max(1) as usize;
+        if chunk.len() > max_bytes {
+            state.pending = Some(chunk.split_off(max_bytes));
         }
+        Ok(Ok(Some(chunk.to_vec())))
     }
 
-    /// Called when a resource is released by a guest
-    fn drop(&mut self, val: Resource<Client>) -> anyhow::Result<()> {
-        let id = val.rep();
-        self.clients.remove(&id);
+    /// Called when a body is released by a guest, whether or not it was fully read
+    fn drop(&mut self, val: Resource<IncomingBody>) -> anyhow::Result<()> {
+        self.bodies.remove(val.rep())?;
+        self.clients.release_id(val.rep());
         Ok(())
     }
 }
 
+impl HostClientResource {
+    /// Sends `req` with the client's streaming client, falling back to buffering
+    /// the response when no streaming maker is configured
+    async fn send_streamed(
+        &mut self,
+        id: u32,
+        mut req: ClientRequest,
+    ) -> Result<StreamedResult, HttpClientError> {
+        let entry = self
+            .clients
+            .resources
+            .get_mut(id)
+            .map_err(|_| HttpClientError::HostResourceNotFound)?;
+        req.options = merge_options(req.options, entry.options.as_ref());
+
+        let Some(streaming_client_maker) = self.streaming_client_maker.as_mut() else {
+            let result = entry.client.ready().await?.call(req).await?;
+            return Ok(match result {
+                ClientResult::Ok(response) => StreamedResult::Ok(StreamedResponse {
+                    status: response.status,
+                    headers: response.headers,
+                    body: hyper::Body::from(response.body),
+                }),
+                ClientResult::Error(e) => StreamedResult::Error(e),
+            });
+        };
+
+        if entry.streaming_client.is_none() {
+            let client = streaming_client_maker
+                .ready()
+                .await
+                .map_err(|_| HttpClientError::NotReady)?
+                .call(())
+                .await
+                .map_err(|_| HttpClientError::NotReady)?;
+            entry.streaming_client = Some(client);
+        }
+
+        let Some(client) = entry.streaming_client.as_mut() else {
+            return Err(HttpClientError::Unknown);
+        };
+        client.ready().await?.call(req).await
+    }
+}
+
+/// Fills any options unset on the request from the client's defaults
+fn merge_options(request: Option<Options>, client: Option<&Options>) -> Option<Options> {
+    match (request, client) {
+        (Some(request), Some(client)) => Some(Options {
+            timeout_ms: request.timeout_ms.or(client.timeout_ms),
+            connect_timeout_ms: request.connect_timeout_ms.or(client.connect_timeout_ms),
+        }),
+        (Some(request), None) => Some(request),
+        (None, client) => client.cloned(),
+    }
+}
+
 impl bindgen::mycelia_alpha::http::types::Host for HostClientResource {}
 impl bindgen::mycelia_alpha::http::interfaces::Host for HostClientResource {}
 
+/// Implemented by store data which provides guests http clients
 pub trait HostClientResourceMaker {
-    fn new(&mut self) -> anyhow::Result<&mut HostClientResource>;
+    fn http_client(&mut self) -> &mut HostClientResource;
+}
+
+/// Tells the linker how to provide guests access to their http clients
+pub fn add_to_linker<T: HostClientResourceMaker + Send>(
+    linker: &mut Linker<T>,
+) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostClientResource>(linker, |v| v.http_client())
 }
 
 // tell the linker how to provide access to the http client resource
@@ -171,9 +513,7 @@ pub async fn setup_with_wasmtime<T: HostClientResourceMaker + Send>(
     component: &Component,
     linker: &mut Linker<T>,
 ) -> anyhow::Result<()> {
-    let _ = Command::add_to_linker::<T, HostClientResource>(linker, |v| {
-        v.new().expect("failed to produce new host client resource")
-    })?;
+    add_to_linker(linker)?;
 
     // TODO ask in zulip chat about relationship here and w/ https://docs.wasmtime.dev/api/wasmtime/component/struct.Linker.html#method.instantiate_async
     // TODO expand macro generation and check out what the code is doing
@@ -181,3 +521,397 @@ pub async fn setup_with_wasmtime<T: HostClientResourceMaker + Send>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use tower::service_fn;
+    use wasmtime::component::Resource;
+    use wasmtime::{Config, Engine};
+
+    use super::*;
+    use crate::core::IdProductionError;
+    use crate::providers::http_client_hyper::{
+        new_client_maker, new_shared_client, new_streaming_client_maker, HyperClientConfig,
+    };
+
+    fn echo_client_maker() -> HostClientMaker {
+        BoxService::new(service_fn(|_: ()| async {
+            let client = service_fn(|req: ClientRequest| async move {
+                Ok::<_, HttpClientError>(ClientResult::Ok(ClientResponse {
+                    status: 200,
+                    headers: req.headers,
+                    body: req.body,
+                }))
+            });
+            Ok::<HostClient, ClientMakeError>(BoxService::new(client))
+        }))
+    }
+
+    fn fixed_id_provider(id: u32) -> HostResourceIdProvider {
+        BoxService::new(service_fn(move |_: ()| async move {
+            Ok::<_, IdProductionError>(id)
+        }))
+    }
+
+    fn request() -> ClientRequest {
+        ClientRequest {
+            method: Method::Get,
+            headers: vec![],
+            body: vec![1, 2, 3],
+            uri: "http://localhost".to_string(),
+            options: None,
+        }
+    }
+
+    #[test]
+    fn it_redacts_sensitive_headers() {
+        let headers = vec![
+            ("Authorization".into(), b"Bearer hunter2".to_vec()),
+            ("x-api-key".into(), b"hunter3".to_vec()),
+            ("accept".into(), b"text/plain".to_vec()),
+        ];
+        let output = format!("{:?}", Redacted(&headers));
+        assert!(!output.contains("hunter"));
+        assert!(output.contains("text/plain"));
+    }
+
+    #[test]
+    fn it_lowers_host_client_errors() {
+        let cause = || "cause".to_string();
+        let cases: Vec<(HttpClientError, fn(&ClientError) -> bool)> = vec![
+            (
+                HttpClientError::DnsFailure { cause: cause() },
+                |e| matches!(e, ClientError::DnsFailure(c) if c == "cause"),
+            ),
+            (
+                HttpClientError::ConnectRefused { cause: cause() },
+                |e| matches!(e, ClientError::ConnectRefused(c) if c == "cause"),
+            ),
+            (
+                HttpClientError::TlsError { cause: cause() },
+                |e| matches!(e, ClientError::TlsError(c) if c == "cause"),
+            ),
+            (
+                HttpClientError::PolicyDenied { reason: cause() },
+                |e| matches!(e, ClientError::PolicyDenied(r) if r == "cause"),
+            ),
+            (HttpClientError::NotReady, |e| {
+                matches!(e, ClientError::RateLimited)
+            }),
+            (HttpClientError::RateLimited, |e| {
+                matches!(e, ClientError::RateLimited)
+            }),
+            (
+                HttpClientError::Timeout {
+                    phase: TimeoutPhase::Total,
+                    limit_ms: 100,
+                },
+                |e| {
+                    matches!(
+                        e,
+                        ClientError::Timeout(TimeoutError {
+                            phase: TimeoutPhase::Total,
+                            limit_ms: 100
+                        })
+                    )
+                },
+            ),
+            (HttpClientError::BodyTooLarge { limit: 10 }, |e| {
+                matches!(e, ClientError::BodyTooLarge(10))
+            }),
+            (HttpClientError::BadRequest, |e| {
+                matches!(e, ClientError::BadRequest(_))
+            }),
+            (
+                HttpClientError::ClientError { cause: cause() },
+                |e| matches!(e, ClientError::Other(c) if c == "cause"),
+            ),
+            (HttpClientError::Unknown, |e| {
+                matches!(e, ClientError::Other(_))
+            }),
+            (HttpClientError::HostResourceNotFound, |e| {
+                matches!(e, ClientError::Other(_))
+            }),
+        ];
+
+        for (error, is_expected) in cases {
+            let description = error.to_string();
+            let lowered = ClientError::from(error);
+            assert!(
+                is_expected(&lowered),
+                "{} lowered to {:?}",
+                description,
+                lowered
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_rejects_a_duplicate_resource_id() {
+        let mut resource = HostClientResource::new(echo_client_maker(), fixed_id_provider(7));
+
+        let client = HostClientInterface::new(&mut resource).await.unwrap();
+        assert!(HostClientInterface::new(&mut resource).await.is_err());
+
+        // The live client is left untouched
+        let result = resource.send(client, request()).await.unwrap();
+        assert!(matches!(result, ClientResult::Ok(r) if r.body == vec![1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn it_returns_an_error_for_an_unknown_client() {
+        let mut resource = HostClientResource::new(echo_client_maker(), fixed_id_provider(7));
+
+        let result = resource
+            .send(Resource::new_own(42), request())
+            .await
+            .unwrap();
+        assert!(matches!(result, ClientResult::Error(ClientError::Other(_))));
+
+        let options = Options {
+            timeout_ms: Some(10),
+            connect_timeout_ms: None,
+        };
+        assert!(resource
+            .set_options(Resource::new_own(42), options)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn it_rejects_use_after_drop() {
+        let mut resource = HostClientResource::new(echo_client_maker(), fixed_id_provider(7));
+
+        let client = HostClientInterface::new(&mut resource).await.unwrap();
+        let id = client.rep();
+        HostClientInterface::drop(&mut resource, client).unwrap();
+        assert!(resource.clients.resources.is_empty());
+
+        assert!(HostClientInterface::drop(&mut resource, Resource::new_own(id)).is_err());
+
+        let result = resource
+            .send(Resource::new_own(id), request())
+            .await
+            .unwrap();
+        assert!(matches!(result, ClientResult::Error(_)));
+
+        // The id can be handed out again once released
+        assert!(HostClientInterface::new(&mut resource).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_streams_buffered_responses_in_chunks() {
+        let mut resource =
+            HostClientResource::with_id_pool(echo_client_maker(), ResourceIdPool::new(4));
+        let client = HostClientInterface::new(&mut resource).await.unwrap();
+
+        let result = resource.send_streaming(client, request()).await.unwrap();
+        let StreamingResult::Ok(response) = result else {
+            panic!("expected a response");
+        };
+
+        let body = response.body.rep();
+        let mut chunks = vec![];
+        while let Some(chunk) = resource
+            .read(Resource::new_own(body), 2)
+            .await
+            .unwrap()
+            .unwrap()
+        {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, vec![vec![1, 2], vec![3]]);
+
+        HostIncomingBody::drop(&mut resource, Resource::new_own(body)).unwrap();
+        assert!(resource.bodies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_limits_live_clients_with_an_id_pool() {
+        let id_pool = ResourceIdPool::new(2);
+        let mut resource = HostClientResource::with_id_pool(echo_client_maker(), id_pool.clone());
+
+        let first = HostClientInterface::new(&mut resource).await.unwrap();
+        let _second = HostClientInterface::new(&mut resource).await.unwrap();
+        assert!(HostClientInterface::new(&mut resource).await.is_err());
+
+        HostClientInterface::drop(&mut resource, first).unwrap();
+        assert_eq!(id_pool.live(), 1);
+        assert!(HostClientInterface::new(&mut resource).await.is_ok());
+    }
+
+    /// Misuses a client through the component model, the way a buggy guest would
+    const MISUSING_GUEST: &str = r#"
+        (component
+          (import "mycelia-alpha:http/interfaces" (instance $http
+            (export "client" (type $client (sub resource)))
+            (type $options' (record
+              (field "timeout-ms" (option u32))
+              (field "connect-timeout-ms" (option u32))))
+            (export "options" (type $options (eq $options')))
+            (export "[constructor]client" (func (result (own $client))))
+            (export "[method]client.set-options"
+              (func (param "self" (borrow $client)) (param "options" $options)))))
+          (alias export $http "client" (type $client))
+          (core func $new (canon lower (func $http "[constructor]client")))
+          (core func $set-options (canon lower (func $http "[method]client.set-options")))
+          (core func $drop (canon resource.drop $client))
+          (core module $m
+            (import "" "new" (func $new (result i32)))
+            (import "" "set-options" (func $set-options (param i32 i32 i32 i32 i32)))
+            (import "" "drop" (func $drop (param i32)))
+            (func (export "use-after-drop") (local $client i32)
+              (local.set $client (call $new))
+              (call $drop (local.get $client))
+              (call $set-options (local.get $client)
+                (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)))
+            (func (export "double-drop") (local $client i32)
+              (local.set $client (call $new))
+              (call $drop (local.get $client))
+              (call $drop (local.get $client))))
+          (core instance $i (instantiate $m
+            (with "" (instance
+              (export "new" (func $new))
+              (export "set-options" (func $set-options))
+              (export "drop" (func $drop))))))
+          (func (export "use-after-drop") (canon lift (core func $i "use-after-drop")))
+          (func (export "double-drop") (canon lift (core func $i "double-drop"))))
+    "#;
+
+    struct GuestView {
+        http_client: HostClientResource,
+    }
+
+    impl HostClientResourceMaker for GuestView {
+        fn http_client(&mut self) -> &mut HostClientResource {
+            &mut self.http_client
+        }
+    }
+
+    #[tokio::test]
+    async fn it_traps_guests_which_misuse_clients() {
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        config.async_support(true);
+        let engine = Engine::new(&config).unwrap();
+        let component = Component::new(&engine, MISUSING_GUEST).unwrap();
+        let mut linker = Linker::new(&engine);
+        add_to_linker(&mut linker).unwrap();
+
+        for export in ["use-after-drop", "double-drop"] {
+            let id_pool = ResourceIdPool::new(1);
+            let view = GuestView {
+                http_client: HostClientResource::with_id_pool(echo_client_maker(), id_pool.clone()),
+            };
+            let mut store = Store::new(&engine, view);
+            let instance = linker
+                .instantiate_async(&mut store, &component)
+                .await
+                .unwrap();
+            let misuse = instance
+                .get_typed_func::<(), ()>(&mut store, export)
+                .unwrap();
+
+            // The stale handle is rejected before the host ever sees it
+            assert!(misuse.call_async(&mut store, ()).await.is_err(), "{export}");
+            assert!(store.data().http_client.clients.resources.is_empty());
+            assert_eq!(id_pool.live(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_traps_without_a_client() {
+        let id_pool = ResourceIdPool::new(1);
+        let mut resource =
+            HostClientResource::with_id_pool(unavailable_client_maker(), id_pool.clone());
+        assert!(HostClientInterface::new(&mut resource).await.is_err());
+        assert_eq!(id_pool.live(), 0);
+    }
+
+    /// Serves a body from `respond` to every request
+    fn serve(respond: fn() -> hyper::Body) -> std::net::SocketAddr {
+        let make_service = hyper::service::make_service_fn(move |_| async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |_| async move {
+                Ok::<_, std::convert::Infallible>(hyper::Response::new(respond()))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn streaming_resource(max_response_bytes: u64) -> HostClientResource {
+        let client = new_shared_client(&HyperClientConfig {
+            max_response_bytes,
+            ..Default::default()
+        });
+        HostClientResource::with_id_pool(new_client_maker(client.clone()), ResourceIdPool::new(4))
+            .with_streaming(new_streaming_client_maker(client))
+    }
+
+    fn get(addr: std::net::SocketAddr) -> ClientRequest {
+        ClientRequest {
+            uri: format!("http://{}/", addr),
+            body: vec![],
+            ..request()
+        }
+    }
+
+    #[tokio::test]
+    async fn it_streams_bodies_larger_than_the_buffer_limit() {
+        let addr = serve(|| hyper::Body::from(vec![7u8; 4096]));
+        let mut resource = streaming_resource(1024);
+        let client = HostClientInterface::new(&mut resource).await.unwrap();
+        let id = client.rep();
+
+        let buffered = resource.send(client, get(addr)).await.unwrap();
+        assert!(matches!(
+            buffered,
+            ClientResult::Error(ClientError::BodyTooLarge(1024))
+        ));
+
+        let streamed = resource
+            .send_streaming(Resource::new_own(id), get(addr))
+            .await
+            .unwrap();
+        let StreamingResult::Ok(response) = streamed else {
+            panic!("expected a response");
+        };
+        let body = response.body.rep();
+        let mut read = 0;
+        while let Some(chunk) = resource
+            .read(Resource::new_own(body), 512)
+            .await
+            .unwrap()
+            .unwrap()
+        {
+            assert!(chunk.len() <= 512);
+            read += chunk.len();
+        }
+        assert_eq!(read, 4096);
+    }
+
+    #[tokio::test]
+    async fn it_times_out_stalled_body_reads() {
+        let addr = serve(|| {
+            let (sender, body) = hyper::Body::channel();
+            tokio::spawn(async move {
+                let _sender = sender;
+                tokio::time::sleep(Duration::from_secs(60)).await;
+            });
+            body
+        });
+        let mut resource = streaming_resource(1024);
+        resource.body_read_timeout = Duration::from_millis(50);
+        let client = HostClientInterface::new(&mut resource).await.unwrap();
+
+        let StreamingResult::Ok(response) =
+            resource.send_streaming(client, get(addr)).await.unwrap()
+        else {
+            panic!("expected a response");
+        };
+        let result = resource.read(response.body, 512).await.unwrap();
+        assert!(matches!(result, Err(ClientError::Timeout(_))));
+    }
+}
@@ -0,0 +1,468 @@
+//! Egress policy for guest outbound requests.
+//!
+//! `EgressPolicyLayer` wraps a `HostClient` and rejects requests the policy doesn't allow
+//! before they reach the network. Denied requests fail with `HttpClientError::PolicyDenied`,
+//! which guests see as `client-error::policy-denied`.
+//!
+//! # Notes
+//! - The policy only checks addresses written into the uri. Names are resolved, and their
+//!   addresses checked, by the client as it connects so a name can't resolve to a public
+//!   address for the check and a private one for the connection.
+//!   See `providers::http_client_hyper::HyperClientConfig::allow_private_ips`.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin};
+
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tower::{util::BoxService, Layer, Service, ServiceExt};
+
+use super::{ClientMaker, ClientRequest, HttpClientError};
+
+#[derive(Error, Debug)]
+#[error("invalid host pattern `{0}`. expected host, *.domain or *, with an optional :port")]
+pub struct InvalidHostPattern(String);
+
+/// Matches the host and port of a request uri.
+///
+/// `example.com` matches that host only, `*.example.com` matches its subdomains
+/// and `*` matches any host. Without a port every port matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPattern {
+    host: String,
+    port: Option<u16>,
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        if self.port.is_some_and(|p| p != port) {
+            return false;
+        }
+
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match self.host.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => host.len() > suffix.len() && host.ends_with(suffix),
+            None => self.host == host,
+        }
+    }
+
+    /// The hosts and ports matched by both `self` and `other`.
+    /// `None` when no host matches both
+    pub fn intersect(&self, other: &HostPattern) -> Option<HostPattern> {
+        let port = match (self.port, other.port) {
+            (Some(a), Some(b)) if a != b => return None,
+            (port, None) => port,
+            (_, port) => port,
+        };
+
+        let host = if self.contains_host(&other.host) {
+            other.host.clone()
+        } else if other.contains_host(&self.host) {
+            self.host.clone()
+        } else {
+            return None;
+        };
+
+        Some(HostPattern { host, port })
+    }
+
+    /// Whether every host matched by the host pattern `host` is matched by `self`
+    fn contains_host(&self, host: &str) -> bool {
+        match (self.host.strip_prefix('*'), host.strip_prefix('*')) {
+            (Some(""), _) => true,
+            (Some(_), Some("")) => false,
+            // *.example.com contains *.api.example.com
+            (Some(suffix), Some(other)) => other.ends_with(suffix),
+            (Some(suffix), None) => host.len() > suffix.len() && host.ends_with(suffix),
+            (None, _) => self.host == host,
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = InvalidHostPattern;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidHostPattern(s.to_string());
+        let pattern = s.trim().to_ascii_lowercase();
+
+        let (host, port) = if let Some(rest) = pattern.strip_prefix('[') {
+            // [v6]:port
+            let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => Some(port),
+                None if rest.is_empty() => None,
+                None => return Err(invalid()),
+            };
+            (host, port)
+        } else {
+            match pattern.split_once(':') {
+                // a bare v6 address has no port
+                Some((_, rest)) if rest.contains(':') => (pattern.as_str(), None),
+                Some((host, port)) => (host, Some(port)),
+                None => (pattern.as_str(), None),
+            }
+        };
+
+        let port = port
+            .map(|port| port.parse::<u16>().map_err(|_| invalid()))
+            .transpose()?;
+
+        let wildcard_ok = host == "*" || host.strip_prefix("*.").is_some_and(|d| !d.is_empty());
+        if host.is_empty() || (host.contains('*') && !wildcard_ok) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+/// Which requests a guest may send
+#[derive(Debug, Clone)]
+pub struct EgressPolicy {
+    /// Schemes requests may use
+    pub allowed_schemes: Vec<String>,
+    /// When not empty, only matching hosts may be reached
+    pub allow: Vec<HostPattern>,
+    /// Matching hosts are never reached, even when allowed
+    pub deny: Vec<HostPattern>,
+    /// Permit loopback, private, link-local and other non public addresses
+    pub allow_private_ips: bool,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allow: vec![],
+            deny: vec![],
+            allow_private_ips: false,
+        }
+    }
+}
+
+impl EgressPolicy {
+    /// Narrows the policy so only hosts matching both its allowlist and `allow` may be reached.
+    /// `None` when the two allowlists have no host in common
+    pub fn restrict_allow(&self, allow: &[HostPattern]) -> Option<EgressPolicy> {
+        let allow = match (self.allow.is_empty(), allow.is_empty()) {
+            (_, true) => self.allow.clone(),
+            (true, false) => allow.to_vec(),
+            (false, false) => {
+                let mut intersection: Vec<HostPattern> = vec![];
+                for pattern in &self.allow {
+                    for other in allow {
+                        if let Some(p) = pattern.intersect(other) {
+                            if !intersection.contains(&p) {
+                                intersection.push(p);
+                            }
+                        }
+                    }
+                }
+                if intersection.is_empty() {
+                    return None;
+                }
+                intersection
+            }
+        };
+
+        Some(EgressPolicy {
+            allow,
+            ..self.clone()
+        })
+    }
+
+    /// Checks `uri` against the policy.
+    /// Hosts given by name are checked for private addresses by the client when it connects
+    pub async fn check(&self, uri: &str) -> Result<(), HttpClientError> {
+        let uri: http::Uri = uri.parse().map_err(|_| HttpClientError::BadRequest)?;
+        let scheme = uri.scheme_str().ok_or(HttpClientError::BadRequest)?;
+        let host = uri
+            .host()
+            .ok_or(HttpClientError::BadRequest)?
+            .to_ascii_lowercase();
+
+        if !self.allowed_schemes.iter().any(|s| s == scheme) {
+            return Err(denied(format!("scheme {} is not allowed", scheme)));
+        }
+
+        let port = match (uri.port_u16(), scheme) {
+            (Some(port), _) => port,
+            (None, "https") => 443,
+            (None, _) => 80,
+        };
+
+        if self.deny.iter().any(|p| p.matches(&host, port)) {
+            return Err(denied(format!("{}:{} is denied", host, port)));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| p.matches(&host, port)) {
+            return Err(denied(format!("{}:{} is not allowed", host, port)));
+        }
+
+        if self.allow_private_ips {
+            return Ok(());
+        }
+
+        let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+        match bare_host.parse::<IpAddr>() {
+            Ok(ip) if !is_public(&ip) => Err(denied(format!("{} is not a public address", ip))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn denied(reason: String) -> HttpClientError {
+    HttpClientError::PolicyDenied { reason }
+}
+
+/// Whether `ip` is reachable on the public internet
+pub fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(&ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "this network"
+        || a == 0
+        // shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // benchmarking, 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18)
+        // reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    let first = segments[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local, fc00::/7
+        || (first & 0xfe00) == 0xfc00
+        // link local, fe80::/10
+        || (first & 0xffc0) == 0xfe80
+        // deprecated site local, fec0::/10
+        || (first & 0xffc0) == 0xfec0
+        // NAT64, 64:ff9b::/96, translated to any v4 address
+        || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+        // 6to4, 2002::/16, relayed to any v4 address
+        || first == 0x2002
+        // deprecated v4 compatible, ::a.b.c.d
+        || segments[..6] == [0; 6])
+}
+
+/// Applies an `EgressPolicy` to every request made by a client
+#[derive(Debug, Clone)]
+pub struct EgressPolicyLayer {
+    policy: Arc<EgressPolicy>,
+}
+
+impl EgressPolicyLayer {
+    pub fn new(policy: EgressPolicy) -> Self {
+        Self {
+            policy: Arc::new(policy),
+        }
+    }
+}
+
+impl<S> Layer<S> for EgressPolicyLayer {
+    type Service = EgressPolicyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        EgressPolicyService {
+            inner: Arc::new(Mutex::new(inner)),
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+pub struct EgressPolicyService<S> {
+    inner: Arc<Mutex<S>>,
+    policy: Arc<EgressPolicy>,
+}
+
+impl<S> Service<ClientRequest> for EgressPolicyService<S>
+where
+    S: Service<ClientRequest, Error = HttpClientError> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = HttpClientError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The inner client is readied once the request passes the policy
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ClientRequest) -> Self::Future {
+        let inner = self.inner.clone();
+        let policy = self.policy.clone();
+
+        Box::pin(async move {
+            policy.check(&req.uri).await?;
+
+            let response = {
+                let mut inner = inner.lock().await;
+                inner.ready().await?.call(req)
+            };
+            response.await
+        })
+    }
+}
+
+/// Wraps every client produced by `client_maker` with `policy`
+pub fn with_egress_policy<R: 'static>(
+    client_maker: ClientMaker<R>,
+    policy: EgressPolicy,
+) -> ClientMaker<R> {
+    let layer = EgressPolicyLayer::new(policy);
+    BoxService::new(client_maker.map_response(move |client| BoxService::new(layer.layer(client))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn it_parses_host_patterns() {
+        assert!(pattern("example.com").matches("example.com", 443));
+        assert!(!pattern("example.com").matches("api.example.com", 443));
+        assert!(pattern("*.example.com").matches("api.example.com", 80));
+        assert!(!pattern("*.example.com").matches("example.com", 80));
+        assert!(pattern("*").matches("anything", 1));
+        assert!(pattern("Example.com:8080").matches("example.com", 8080));
+        assert!(!pattern("example.com:8080").matches("example.com", 80));
+        assert!(pattern("[::1]:80").matches("[::1]", 80));
+        assert!(pattern("::1").matches("::1", 443));
+
+        assert!("".parse::<HostPattern>().is_err());
+        assert!("a*.example.com".parse::<HostPattern>().is_err());
+        assert!("example.com:http".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn it_classifies_non_public_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "::127.0.0.1",
+            "fec0::1",
+        ] {
+            assert!(!is_public(&ip.parse().unwrap()), "{} is not public", ip);
+        }
+
+        for ip in ["1.1.1.1", "198.20.0.1", "2606:4700:4700::1111"] {
+            assert!(is_public(&ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_denies_requests_outside_the_policy() {
+        let policy = EgressPolicy {
+            allow: vec![pattern("*.example.com"), pattern("1.1.1.1")],
+            deny: vec![pattern("internal.example.com")],
+            ..Default::default()
+        };
+
+        let is_denied =
+            |r: Result<(), HttpClientError>| matches!(r, Err(HttpClientError::PolicyDenied { .. }));
+
+        assert!(policy.check("https://1.1.1.1/").await.is_ok());
+        assert!(is_denied(policy.check("ftp://1.1.1.1/").await));
+        assert!(is_denied(policy.check("https://8.8.8.8/").await));
+        assert!(is_denied(
+            policy.check("https://internal.example.com/").await
+        ));
+
+        let open = EgressPolicy::default();
+        assert!(is_denied(open.check("http://127.0.0.1:8080/").await));
+        assert!(is_denied(open.check("http://[::1]/").await));
+        assert!(is_denied(
+            open.check("http://169.254.169.254/latest/meta-data").await
+        ));
+        // Names are left to the client, which checks the addresses it connects to
+        assert!(open.check("http://localhost/").await.is_ok());
+        assert!(matches!(
+            open.check("not a uri").await,
+            Err(HttpClientError::BadRequest)
+        ));
+    }
+
+    #[test]
+    fn it_intersects_allowlists() {
+        let server = EgressPolicy {
+            allow: vec![pattern("*.example.com"), pattern("api.other.com:443")],
+            ..Default::default()
+        };
+
+        let restricted = server
+            .restrict_allow(&[pattern("api.example.com"), pattern("*.other.com")])
+            .unwrap();
+        assert_eq!(
+            restricted.allow,
+            vec![pattern("api.example.com"), pattern("api.other.com:443")]
+        );
+
+        let restricted = server.restrict_allow(&[pattern("*")]).unwrap();
+        assert_eq!(restricted.allow, server.allow);
+
+        let restricted = server
+            .restrict_allow(&[pattern("*.eu.example.com:8080")])
+            .unwrap();
+        assert_eq!(restricted.allow, vec![pattern("*.eu.example.com:8080")]);
+
+        // A component can't widen the server's allowlist
+        assert!(server.restrict_allow(&[pattern("example.com")]).is_none());
+        assert!(server
+            .restrict_allow(&[pattern("api.other.com:80")])
+            .is_none());
+
+        let open = EgressPolicy::default();
+        let restricted = open.restrict_allow(&[pattern("example.com")]).unwrap();
+        assert_eq!(restricted.allow, vec![pattern("example.com")]);
+    }
+}
@@ -0,0 +1,432 @@
+//! Rate and concurrency limits for guest outbound requests.
+//!
+//! Limits apply at two scopes. Component limits are shared by every client made for a
+//! component, client limits apply to each client on its own. A guest exceeding either sees
+//! `HttpClientError::NotReady` from `poll_ready` (or `call`, when another client raced it)
+//! which is returned to it as `client-error::rate-limited`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use std::{future::Future, pin::Pin};
+
+use tower::{util::BoxService, Layer, Service, ServiceExt};
+
+use super::{ClientMaker, ClientRequest, HttpClientError};
+
+/// A token bucket refilled at `per_sec`, holding at most `burst` tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub per_sec: u32,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allows `per_sec` with bursts of the same size
+    pub fn per_sec(per_sec: u32) -> Self {
+        Self {
+            per_sec,
+            burst: per_sec,
+        }
+    }
+}
+
+/// Limits for a single scope. Unset limits aren't enforced
+#[derive(Debug, Clone, Default)]
+pub struct RequestLimits {
+    /// Requests started
+    pub requests: Option<RateLimit>,
+    /// Requests awaiting a response
+    pub max_in_flight: Option<usize>,
+    /// Request body bytes sent
+    pub bytes: Option<RateLimit>,
+}
+
+/// Limits for a component's outbound requests
+#[derive(Debug, Clone, Default)]
+pub struct OutboundLimits {
+    /// Shared by every client of the component
+    pub component: RequestLimits,
+    /// Applied to each client individually
+    pub client: RequestLimits,
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.into(),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * f64::from(self.limit.per_sec)).min(self.limit.burst.into());
+        self.refilled_at = now;
+    }
+
+    fn has(&mut self, n: u64) -> bool {
+        self.refill();
+        self.tokens >= n as f64
+    }
+
+    pub(crate) fn take(&mut self, n: u64) -> bool {
+        let has = self.has(n);
+        if has {
+            self.tokens -= n as f64;
+        }
+        has
+    }
+
+    fn give_back(&mut self, n: u64) {
+        self.tokens = (self.tokens + n as f64).min(self.limit.burst.into());
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    requests: Option<Mutex<TokenBucket>>,
+    bytes: Option<Mutex<TokenBucket>>,
+    in_flight: AtomicUsize,
+    max_in_flight: Option<usize>,
+}
+
+impl LimiterState {
+    fn new(limits: &RequestLimits) -> Self {
+        Self {
+            requests: limits.requests.map(|l| Mutex::new(TokenBucket::new(l))),
+            bytes: limits.bytes.map(|l| Mutex::new(TokenBucket::new(l))),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: limits.max_in_flight,
+        }
+    }
+
+    fn bucket(bucket: &Mutex<TokenBucket>) -> std::sync::MutexGuard<'_, TokenBucket> {
+        bucket.lock().expect("token bucket lock poisoned")
+    }
+
+    /// Whether a request could start now
+    fn is_ready(&self) -> bool {
+        if let Some(max) = self.max_in_flight {
+            if self.in_flight.load(Ordering::Acquire) >= max {
+                return false;
+            }
+        }
+        match &self.requests {
+            Some(requests) => Self::bucket(requests).has(1),
+            None => true,
+        }
+    }
+
+    fn acquire(self: &Arc<Self>, body_len: u64) -> Result<Permit, HttpClientError> {
+        if let Some(bytes) = &self.bytes {
+            let mut bytes = Self::bucket(bytes);
+            // Would never be let through, waiting won't help
+            if body_len > bytes.limit.burst.into() {
+                return Err(HttpClientError::PolicyDenied {
+                    reason: format!(
+                        "request body of {} bytes exceeds the limit of {} bytes",
+                        body_len, bytes.limit.burst
+                    ),
+                });
+            }
+            if !bytes.has(body_len) {
+                return Err(HttpClientError::NotReady);
+            }
+        }
+
+        if let Some(max) = self.max_in_flight {
+            self.in_flight
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    (n < max).then_some(n + 1)
+                })
+                .map_err(|_| HttpClientError::NotReady)?;
+        } else {
+            self.in_flight.fetch_add(1, Ordering::AcqRel);
+        }
+        // From here on dropping the permit releases what was taken
+        let mut permit = Permit {
+            state: self.clone(),
+            request: false,
+            bytes: 0,
+            committed: false,
+        };
+
+        if let Some(requests) = &self.requests {
+            if !Self::bucket(requests).take(1) {
+                return Err(HttpClientError::NotReady);
+            }
+            permit.request = true;
+        }
+
+        if let Some(bytes) = &self.bytes {
+            if !Self::bucket(bytes).take(body_len) {
+                return Err(HttpClientError::NotReady);
+            }
+            permit.bytes = body_len;
+        }
+
+        Ok(permit)
+    }
+}
+
+/// Held for the duration of a request.
+/// Tokens are returned if the request never started
+struct Permit {
+    state: Arc<LimiterState>,
+    request: bool,
+    bytes: u64,
+    committed: bool,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::AcqRel);
+        if self.committed {
+            return;
+        }
+        if let (true, Some(requests)) = (self.request, &self.state.requests) {
+            LimiterState::bucket(requests).give_back(1);
+        }
+        if let Some(bytes) = &self.state.bytes {
+            LimiterState::bucket(bytes).give_back(self.bytes);
+        }
+    }
+}
+
+/// Holds a component's shared limits. Make one per component and apply it to
+/// every client maker for the component
+#[derive(Debug, Clone)]
+pub struct OutboundLimiter {
+    component: Arc<LimiterState>,
+    client: RequestLimits,
+}
+
+impl OutboundLimiter {
+    pub fn new(limits: &OutboundLimits) -> Self {
+        Self {
+            component: Arc::new(LimiterState::new(&limits.component)),
+            client: limits.client.clone(),
+        }
+    }
+
+    /// Limits every client produced by `client_maker`
+    pub fn apply<R: 'static>(&self, client_maker: ClientMaker<R>) -> ClientMaker<R> {
+        let layer = OutboundLimitLayer {
+            limiter: self.clone(),
+        };
+        BoxService::new(
+            client_maker.map_response(move |client| BoxService::new(layer.layer(client))),
+        )
+    }
+}
+
+/// Applies component and client limits to a client
+#[derive(Debug, Clone)]
+pub struct OutboundLimitLayer {
+    limiter: OutboundLimiter,
+}
+
+impl OutboundLimitLayer {
+    pub fn new(limiter: OutboundLimiter) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for OutboundLimitLayer {
+    type Service = OutboundLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        OutboundLimitService {
+            inner,
+            client: Arc::new(LimiterState::new(&self.limiter.client)),
+            component: self.limiter.component.clone(),
+        }
+    }
+}
+
+pub struct OutboundLimitService<S> {
+    inner: S,
+    client: Arc<LimiterState>,
+    component: Arc<LimiterState>,
+}
+
+impl<S> Service<ClientRequest> for OutboundLimitService<S>
+where
+    S: Service<ClientRequest, Error = HttpClientError>,
+    S::Response: 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+
+    type Error = HttpClientError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if !self.client.is_ready() || !self.component.is_ready() {
+            return Poll::Ready(Err(HttpClientError::NotReady));
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ClientRequest) -> Self::Future {
+        let body_len = req.body.len() as u64;
+        let permits = self.client.acquire(body_len).and_then(|client| {
+            let component = self.component.acquire(body_len)?;
+            Ok([client, component])
+        });
+
+        let mut permits = match permits {
+            Ok(permits) => permits,
+            Err(e) => return Box::pin(async move { Err(e) }),
+        };
+        for permit in permits.iter_mut() {
+            permit.committed = true;
+        }
+
+        let response = self.inner.call(req);
+        Box::pin(async move {
+            let _permits = permits;
+            response.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tower::service_fn;
+
+    use super::*;
+    use crate::http::{ClientResponse, ClientResult, HostClient, Method};
+
+    fn request(body: Vec<u8>) -> ClientRequest {
+        ClientRequest {
+            method: Method::Post,
+            headers: vec![],
+            body,
+            uri: "http://localhost".to_string(),
+            options: None,
+        }
+    }
+
+    fn client(limiter: &OutboundLimiter) -> HostClient {
+        let inner = service_fn(|_: ClientRequest| async {
+            // Long enough for the in flight tests to overlap requests
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Ok::<_, HttpClientError>(ClientResult::Ok(ClientResponse {
+                status: 200,
+                headers: vec![],
+                body: vec![],
+            }))
+        });
+        BoxService::new(OutboundLimitLayer::new(limiter.clone()).layer(inner))
+    }
+
+    #[tokio::test]
+    async fn it_rate_limits_requests() {
+        let limiter = OutboundLimiter::new(&OutboundLimits {
+            client: RequestLimits {
+                requests: Some(RateLimit {
+                    per_sec: 1,
+                    burst: 2,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut client = client(&limiter);
+
+        for _ in 0..2 {
+            client
+                .ready()
+                .await
+                .unwrap()
+                .call(request(vec![]))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            client.ready().await.err(),
+            Some(HttpClientError::NotReady)
+        ));
+
+        // Other clients have their own budget
+        let mut other = self::client(&limiter);
+        assert!(other.ready().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_limits_in_flight_requests_across_a_component() {
+        let limiter = OutboundLimiter::new(&OutboundLimits {
+            component: RequestLimits {
+                max_in_flight: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut first = client(&limiter);
+        let mut second = client(&limiter);
+
+        let in_flight = first.ready().await.unwrap().call(request(vec![]));
+        assert!(matches!(
+            second.ready().await.err(),
+            Some(HttpClientError::NotReady)
+        ));
+
+        in_flight.await.unwrap();
+        assert!(second.ready().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn it_limits_outbound_bytes() {
+        let limiter = OutboundLimiter::new(&OutboundLimits {
+            client: RequestLimits {
+                bytes: Some(RateLimit {
+                    per_sec: 1,
+                    burst: 8,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let mut client = client(&limiter);
+
+        assert!(matches!(
+            client
+                .ready()
+                .await
+                .unwrap()
+                .call(request(vec![0; 16]))
+                .await,
+            Err(HttpClientError::PolicyDenied { .. })
+        ));
+        client
+            .ready()
+            .await
+            .unwrap()
+            .call(request(vec![0; 6]))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client
+                .ready()
+                .await
+                .unwrap()
+                .call(request(vec![0; 6]))
+                .await,
+            Err(HttpClientError::NotReady)
+        ));
+    }
+}
@@ -0,0 +1,326 @@
+//! Host side implementations for providing wasm key value stores.
+//! Guests open a `store` resource and read and write keys through it. Each operation is
+//! a request to a Tower service, so stores can be backed by anything from an in memory
+//! map to a remote database.
+//!
+//! # Usage
+//! Link the interface with `add_to_linker` and give every store a `HostKvResource`
+//! whose maker opens stores scoped to the component being run,
+//! see `providers::kv_store_sled` for the default on-disk store.
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tower::util::BoxService;
+use wasmtime::component::{Linker, Resource};
+
+use crate::core::{unavailable_maker, HostResourceMaker, ResourceHost, ResourceTableError};
+
+use self::bindgen::mycelia_alpha::kv::interfaces::HostStore as HostStoreInterface;
+use self::bindgen::mycelia_alpha::kv::interfaces::Store;
+use self::bindgen::Command;
+
+pub use self::bindgen::mycelia_alpha::kv::types::{Key, KvError, Value};
+
+mod bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../guest_crates/mycelia_kv/wit",
+      world: "command",
+      async: true
+    });
+}
+
+/// An operation a guest performs on its store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvRequest {
+    Get {
+        key: Key,
+    },
+    Set {
+        key: Key,
+        value: Value,
+    },
+    Delete {
+        key: Key,
+    },
+    /// At most `limit` keys starting with `prefix` which sort after `start_after`
+    ListKeys {
+        prefix: String,
+        start_after: Option<Key>,
+        limit: u32,
+    },
+    CompareAndSwap {
+        key: Key,
+        expected: Option<Value>,
+        new: Value,
+    },
+}
+
+/// The result of a `KvRequest`, each request has a matching variant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvResponse {
+    Value(Option<Value>),
+    Set,
+    Deleted(bool),
+    Keys(Vec<Key>),
+    Swapped(bool),
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when opening a new store for a guest
+pub enum StoreMakeError {
+    #[error("no key value store is available to this guest")]
+    Unavailable,
+    #[error("failed to open the store - {cause}")]
+    Storage { cause: String },
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when the host performs an operation on behalf of a guest
+pub enum KvStoreError {
+    #[error("store isn't ready. wait and try again.")]
+    NotReady,
+    #[error("invalid key - {reason}")]
+    InvalidKey { reason: String },
+    #[error("value exceeded the limit of {limit} bytes")]
+    ValueTooLarge { limit: usize },
+    #[error("store exceeded its quota of {limit} bytes")]
+    QuotaExceeded { limit: u64 },
+    #[error("storage failure - {cause}")]
+    Storage { cause: String },
+    /// The store answered with a response for a different kind of request
+    #[error("store produced an unexpected response")]
+    UnexpectedResponse,
+    #[error("guest tried to use a store which does not exist")]
+    HostResourceNotFound,
+}
+
+impl From<ResourceTableError> for KvStoreError {
+    fn from(_: ResourceTableError) -> Self {
+        KvStoreError::HostResourceNotFound
+    }
+}
+
+impl From<KvStoreError> for KvError {
+    fn from(e: KvStoreError) -> Self {
+        match e {
+            KvStoreError::NotReady => KvError::Busy,
+            KvStoreError::InvalidKey { .. } | KvStoreError::ValueTooLarge { .. } => {
+                KvError::Invalid(e.to_string())
+            }
+            KvStoreError::QuotaExceeded { .. } => KvError::QuotaExceeded,
+            KvStoreError::Storage { cause } => KvError::Other(cause),
+            KvStoreError::UnexpectedResponse | KvStoreError::HostResourceNotFound => {
+                KvError::Other(e.to_string())
+            }
+        }
+    }
+}
+
+/// Abstract service type defining a key value store,
+/// for example see `providers::kv_store_sled::SledKvStore`
+pub type HostKvStore = BoxService<KvRequest, KvResponse, KvStoreError>;
+
+/// Abstract service type for a thing which opens new HostKvStores
+pub type HostKvStoreMaker = BoxService<(), HostKvStore, StoreMakeError>;
+
+/// A maker for guests which aren't given a store. Opening one traps the guest
+pub fn unavailable_store_maker() -> HostKvStoreMaker {
+    unavailable_maker(|| StoreMakeError::Unavailable)
+}
+
+/// The stores a guest opened, see `ResourceHost`
+pub type HostKvResource = ResourceHost<HostKvStoreMaker, HostKvStore>;
+
+#[async_trait]
+impl HostStoreInterface for HostKvResource {
+    /// Opens a store and returns the resource to the guest.
+    /// Failing to open a store traps the guest instance
+    async fn new(&mut self) -> anyhow::Result<Resource<Store>> {
+        Ok(Resource::new_own(self.open(()).await?))
+    }
+
+    async fn get(
+        &mut self,
+        guest_self: Resource<Store>,
+        key: Key,
+    ) -> anyhow::Result<Result<Option<Value>, KvError>> {
+        let req = KvRequest::Get { key };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(KvResponse::Value(value)) => Ok(value),
+            Ok(_) => Err(KvStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn set(
+        &mut self,
+        guest_self: Resource<Store>,
+        key: Key,
+        value: Value,
+    ) -> anyhow::Result<Result<(), KvError>> {
+        let req = KvRequest::Set { key, value };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(KvResponse::Set) => Ok(()),
+            Ok(_) => Err(KvStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn delete(
+        &mut self,
+        guest_self: Resource<Store>,
+        key: Key,
+    ) -> anyhow::Result<Result<bool, KvError>> {
+        let req = KvRequest::Delete { key };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(KvResponse::Deleted(existed)) => Ok(existed),
+            Ok(_) => Err(KvStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn list_keys(
+        &mut self,
+        guest_self: Resource<Store>,
+        prefix: String,
+        start_after: Option<Key>,
+        limit: u32,
+    ) -> anyhow::Result<Result<Vec<Key>, KvError>> {
+        let req = KvRequest::ListKeys {
+            prefix,
+            start_after,
+            limit,
+        };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(KvResponse::Keys(keys)) => Ok(keys),
+            Ok(_) => Err(KvStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn compare_and_swap(
+        &mut self,
+        guest_self: Resource<Store>,
+        key: Key,
+        expected: Option<Value>,
+        new: Value,
+    ) -> anyhow::Result<Result<bool, KvError>> {
+        let req = KvRequest::CompareAndSwap { key, expected, new };
+        Ok(match self.call(guest_self.rep(), req).await {
+            Ok(KvResponse::Swapped(swapped)) => Ok(swapped),
+            Ok(_) => Err(KvStoreError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    /// Called when a store is released by a guest.
+    /// Releasing a store which does not exist traps the guest instance
+    fn drop(&mut self, val: Resource<Store>) -> anyhow::Result<()> {
+        self.remove(val.rep())?;
+        Ok(())
+    }
+}
+
+impl bindgen::mycelia_alpha::kv::types::Host for HostKvResource {}
+impl bindgen::mycelia_alpha::kv::interfaces::Host for HostKvResource {}
+
+/// Tells the linker how to provide guests access to their key value store
+pub fn add_to_linker<T: HostResourceMaker<HostKvResource> + Send>(
+    linker: &mut Linker<T>,
+) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostKvResource>(linker, |v| v.host_resource())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use tower::service_fn;
+
+    use super::*;
+    use crate::core::ResourceIdPool;
+
+    /// A store kept in memory, shared by every store the maker opens
+    fn memory_store_maker() -> HostKvStoreMaker {
+        let entries = Arc::new(Mutex::new(BTreeMap::<Key, Value>::new()));
+        BoxService::new(service_fn(move |_: ()| {
+            let entries = entries.clone();
+            async move {
+                let store = service_fn(move |req: KvRequest| {
+                    let mut entries = entries.lock().unwrap();
+                    let response = match req {
+                        KvRequest::Get { key } => KvResponse::Value(entries.get(&key).cloned()),
+                        KvRequest::Set { key, value } => {
+                            entries.insert(key, value);
+                            KvResponse::Set
+                        }
+                        _ => KvResponse::Deleted(false),
+                    };
+                    async move { Ok::<_, KvStoreError>(response) }
+                });
+                Ok::<HostKvStore, StoreMakeError>(BoxService::new(store))
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn it_shares_values_between_stores() {
+        let mut resource =
+            HostKvResource::with_id_pool(memory_store_maker(), ResourceIdPool::new(4));
+
+        let first = HostStoreInterface::new(&mut resource).await.unwrap();
+        let second = HostStoreInterface::new(&mut resource).await.unwrap();
+        let first_id = first.rep();
+
+        resource
+            .set(first, "visits".into(), vec![1])
+            .await
+            .unwrap()
+            .unwrap();
+        let value = resource
+            .get(second, "visits".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, Some(vec![1]));
+
+        // Mismatched responses are returned to the guest, not trapped
+        let result = resource
+            .list_keys(Resource::new_own(first_id), "".into(), None, 10)
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(KvError::Other(_))));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_use_after_drop() {
+        let id_pool = ResourceIdPool::new(1);
+        let mut resource = HostKvResource::with_id_pool(memory_store_maker(), id_pool.clone());
+
+        let store = HostStoreInterface::new(&mut resource).await.unwrap();
+        let id = store.rep();
+        assert!(HostStoreInterface::new(&mut resource).await.is_err());
+
+        HostStoreInterface::drop(&mut resource, store).unwrap();
+        assert_eq!(id_pool.live(), 0);
+        assert!(HostStoreInterface::drop(&mut resource, Resource::new_own(id)).is_err());
+
+        let result = resource
+            .get(Resource::new_own(id), "visits".into())
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(KvError::Other(_))));
+    }
+
+    #[tokio::test]
+    async fn it_traps_without_a_store() {
+        let id_pool = ResourceIdPool::new(1);
+        let mut resource = HostKvResource::with_id_pool(unavailable_store_maker(), id_pool.clone());
+        assert!(HostStoreInterface::new(&mut resource).await.is_err());
+        // The id isn't leaked
+        assert_eq!(id_pool.live(), 0);
+    }
+}
@@ -1,3 +1,10 @@
+pub mod blob;
+pub mod config;
 pub mod core;
 pub mod http;
+pub mod kv;
+pub mod logging;
 pub mod providers;
+pub mod secrets;
+pub mod sql;
+pub mod wasi_http;
@@ -0,0 +1,383 @@
+//! Host side implementation for capturing what wasm guests log.
+//! Messages guests record with `mycelia-alpha:logging`, and every line they print to
+//! stdout or stderr, are forwarded into the host's `log` facade tagged with the
+//! component's name and the id of the request being handled.
+//!
+//! # Usage
+//! Link the interface with `add_to_linker`, give every store a `HostLogging` and
+//! pass `GuestOutput`s to the store's wasi context as stdout and stderr.
+//! Update the request id with `GuestLogContext::set_request_id` before each invocation.
+//! Guests logging faster than `GUEST_LOG_RATE_LIMIT` have their messages dropped.
+//!
+//! Guest messages use the `guest` target, printed lines `guest::stdout` and `guest::stderr`,
+//! so they can be filtered separately from the host's own logs e.g. `RUST_LOG=info,guest=debug`.
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+use wasmtime::component::Linker;
+
+use crate::http::limits::{RateLimit, TokenBucket};
+
+use self::bindgen::mycelia_alpha::logging::types::{Fields, Level};
+use self::bindgen::Command;
+
+mod bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../guest_crates/mycelia_logging/wit",
+      world: "command",
+      async: true
+    });
+}
+
+/// Longest message or printed line forwarded, longer ones are truncated
+pub const MAX_LOG_LINE_BYTES: usize = 16 * 1024;
+
+/// Messages a guest may log, further messages are dropped until the budget refills
+pub const GUEST_LOG_RATE_LIMIT: RateLimit = RateLimit {
+    per_sec: 100,
+    burst: 1000,
+};
+
+pub const GUEST_TARGET: &str = "guest";
+pub const GUEST_STDOUT_TARGET: &str = "guest::stdout";
+pub const GUEST_STDERR_TARGET: &str = "guest::stderr";
+
+/// Who logged a message, shared by everything logging on behalf of a single store
+#[derive(Debug, Clone, Default)]
+pub struct GuestLogContext {
+    component: String,
+    request_id: Arc<Mutex<Option<String>>>,
+}
+
+impl GuestLogContext {
+    pub fn new(component: &str) -> Self {
+        Self {
+            component: component.to_string(),
+            request_id: Default::default(),
+        }
+    }
+
+    /// Messages logged from now on are tagged with `request_id`
+    pub fn set_request_id(&self, request_id: Option<String>) {
+        *self.request_id.lock().unwrap() = request_id;
+    }
+
+    /// The request being handled
+    fn request_id(&self) -> Option<String> {
+        self.request_id.lock().unwrap().clone()
+    }
+
+    /// Tags `message` with the component and request, guest controlled text is escaped
+    /// so a guest can't forge lines in the host's log
+    fn format(
+        &self,
+        request_id: Option<&str>,
+        message: &str,
+        fields: &[(String, String)],
+    ) -> String {
+        let mut formatted = format!(
+            "[{} {}] {}",
+            self.component,
+            request_id.unwrap_or("-"),
+            escape(truncate(message))
+        );
+        for (key, value) in fields {
+            let _ = write!(formatted, " {}={}", escape(key), escape(truncate(value)));
+        }
+        formatted
+    }
+
+    fn log(
+        &self,
+        target: &str,
+        level: log::Level,
+        request_id: Option<&str>,
+        message: &str,
+        fields: &[(String, String)],
+    ) {
+        if log::log_enabled!(target: target, level) {
+            let formatted = self.format(request_id, message, fields);
+            log::log!(target: target, level, "{}", formatted);
+        }
+    }
+}
+
+fn truncate(s: &str) -> &str {
+    if s.len() <= MAX_LOG_LINE_BYTES {
+        return s;
+    }
+    let mut end = MAX_LOG_LINE_BYTES;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn escape(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(char::is_control) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(
+        s.chars()
+            .map(|c| {
+                if c.is_control() {
+                    c.escape_default().to_string()
+                } else {
+                    c.to_string()
+                }
+            })
+            .collect(),
+    )
+}
+
+impl From<Level> for log::Level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Trace => log::Level::Trace,
+            Level::Debug => log::Level::Debug,
+            Level::Info => log::Level::Info,
+            Level::Warn => log::Level::Warn,
+            Level::Error => log::Level::Error,
+        }
+    }
+}
+
+/// Provides guests `mycelia-alpha:logging`
+#[derive(Debug)]
+pub struct HostLogging {
+    pub context: GuestLogContext,
+    messages: TokenBucket,
+    // Messages dropped since the last one logged
+    dropped: u64,
+}
+
+impl HostLogging {
+    pub fn new(context: GuestLogContext) -> Self {
+        Self::with_rate_limit(context, GUEST_LOG_RATE_LIMIT)
+    }
+
+    /// Drops messages logged faster than `limit`
+    pub fn with_rate_limit(context: GuestLogContext, limit: RateLimit) -> Self {
+        Self {
+            context,
+            messages: TokenBucket::new(limit),
+            dropped: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl bindgen::mycelia_alpha::logging::interfaces::Host for HostLogging {
+    /// Messages over the rate limit are dropped, the next message logged is preceded
+    /// by a warning counting them
+    async fn log(&mut self, level: Level, message: String, fields: Fields) -> anyhow::Result<()> {
+        if !self.messages.take(1) {
+            self.dropped += 1;
+            return Ok(());
+        }
+        let request_id = self.context.request_id();
+        if self.dropped > 0 {
+            let dropped = format!("dropped {} messages logged too quickly", self.dropped);
+            self.context.log(
+                GUEST_TARGET,
+                log::Level::Warn,
+                request_id.as_deref(),
+                &dropped,
+                &[],
+            );
+            self.dropped = 0;
+        }
+        self.context.log(
+            GUEST_TARGET,
+            level.into(),
+            request_id.as_deref(),
+            &message,
+            &fields,
+        );
+        Ok(())
+    }
+
+    async fn enabled(&mut self, level: Level) -> anyhow::Result<bool> {
+        Ok(log::log_enabled!(target: GUEST_TARGET, level.into()))
+    }
+}
+
+impl bindgen::mycelia_alpha::logging::types::Host for HostLogging {}
+
+/// Implemented by store data which captures guest logs
+pub trait HostLoggingMaker {
+    fn logging(&mut self) -> &mut HostLogging;
+}
+
+/// Tells the linker how to capture guest logs
+pub fn add_to_linker<T: HostLoggingMaker + Send>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostLogging>(linker, |v| v.logging())
+}
+
+/// Which of a guest's output streams a `GuestOutput` captures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestStream {
+    Stdout,
+    Stderr,
+}
+
+/// A guest's stdout or stderr, forwarding each line printed into the host's log.
+/// Stdout is logged at info and stderr at warn.
+///
+/// Lines are tagged with the request being handled when they were written, a line left
+/// unfinished when the request changes is logged as it is rather than joined with the next
+pub struct GuestOutput {
+    context: GuestLogContext,
+    stream: GuestStream,
+    // The start of a line which hasn't been ended yet
+    pending: Vec<u8>,
+    // The request being handled when the pending line was written
+    pending_request_id: Option<String>,
+}
+
+impl GuestOutput {
+    pub fn new(context: GuestLogContext, stream: GuestStream) -> Self {
+        Self {
+            context,
+            stream,
+            pending: vec![],
+            pending_request_id: None,
+        }
+    }
+
+    fn log_line(&self, line: &[u8]) {
+        let (target, level) = match self.stream {
+            GuestStream::Stdout => (GUEST_STDOUT_TARGET, log::Level::Info),
+            GuestStream::Stderr => (GUEST_STDERR_TARGET, log::Level::Warn),
+        };
+        let line = String::from_utf8_lossy(line);
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        self.context
+            .log(target, level, self.pending_request_id.as_deref(), line, &[]);
+    }
+
+    fn log_pending(&mut self) {
+        if !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            self.log_line(&pending);
+        }
+    }
+}
+
+/// Appends `bytes` to `pending`, returning every line it completes.
+/// A line longer than `MAX_LOG_LINE_BYTES` is cut there rather than buffered indefinitely
+fn take_lines(pending: &mut Vec<u8>, bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut lines = vec![];
+    for &byte in bytes {
+        if byte == b'\n' {
+            lines.push(std::mem::take(pending));
+        } else {
+            pending.push(byte);
+            if pending.len() >= MAX_LOG_LINE_BYTES {
+                lines.push(std::mem::take(pending));
+            }
+        }
+    }
+    lines
+}
+
+impl AsyncWrite for GuestOutput {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let request_id = self.context.request_id();
+        if request_id != self.pending_request_id {
+            self.log_pending();
+            self.pending_request_id = request_id;
+        }
+        for line in take_lines(&mut self.pending, buf) {
+            self.log_line(&line);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Partial lines are kept until they're ended, so `print!` followed by a flush
+    /// doesn't split a line in two
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.log_pending();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for GuestOutput {
+    fn drop(&mut self) {
+        self.log_pending();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::bindgen::mycelia_alpha::logging::interfaces::Host;
+    use super::*;
+
+    #[test]
+    fn it_tags_and_escapes_messages() {
+        let context = GuestLogContext::new("checkout");
+        let fields = vec![("user".to_string(), "42".to_string())];
+        assert_eq!(
+            context.format(None, "paid", &fields),
+            "[checkout -] paid user=42"
+        );
+
+        assert_eq!(
+            context.format(Some("abc-1"), "line\n[other 1] forged", &[]),
+            "[checkout abc-1] line\\n[other 1] forged"
+        );
+
+        let long = "é".repeat(MAX_LOG_LINE_BYTES);
+        assert!(context.format(None, &long, &[]).len() < MAX_LOG_LINE_BYTES + 32);
+    }
+
+    #[tokio::test]
+    async fn it_drops_messages_logged_too_quickly() {
+        let limit = RateLimit {
+            per_sec: 0,
+            burst: 2,
+        };
+        let mut logging = HostLogging::with_rate_limit(GuestLogContext::new("checkout"), limit);
+
+        for _ in 0..5 {
+            logging
+                .log(Level::Info, "spam".to_string(), vec![])
+                .await
+                .unwrap();
+        }
+        assert_eq!(logging.dropped, 3);
+    }
+
+    #[test]
+    fn it_splits_output_into_lines() {
+        let mut pending = vec![];
+        assert!(take_lines(&mut pending, b"hello ").is_empty());
+        assert_eq!(
+            take_lines(&mut pending, b"world\nsecond\nthi"),
+            vec![b"hello world".to_vec(), b"second".to_vec()]
+        );
+        assert_eq!(pending, b"thi");
+
+        let mut pending = vec![];
+        let lines = take_lines(&mut pending, &vec![b'a'; MAX_LOG_LINE_BYTES + 1]);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(pending, b"a");
+    }
+}
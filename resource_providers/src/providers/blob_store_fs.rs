@@ -0,0 +1,804 @@
+//! Provides a local filesystem implementation of host object storage.
+//! Every component is given its own root directory and each container is a directory beneath it.
+//! Object names are percent-encoded into a single file name, so guests can't reach
+//! any path outside of the containers belonging to their component.
+//!
+//! A container is laid out as `objects/<encoded name>`. Each file starts with a line of json
+//! holding the metadata a guest supplied when writing it, followed by the object's bytes.
+//! Keeping both in one file lets a put replace them with a single rename, so a crash
+//! never leaves an object paired with another write's metadata.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::UNIX_EPOCH;
+use std::{future::Future, pin::Pin};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tower::{service_fn, util::BoxService, Service};
+
+use crate::blob::{
+    BlobRequest, BlobResponse, BlobStoreError, ContainerMakeError, HostBlobContainer,
+    HostBlobContainerMaker, ObjectMetadata,
+};
+use crate::core::is_valid_storage_name;
+
+/// Largest object a guest may store including its metadata, in bytes
+pub const MAX_OBJECT_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Longest container name a guest may use
+pub const MAX_CONTAINER_NAME_CHARS: usize = 64;
+
+/// Most objects returned by a single `List`, guests page through the rest
+pub const MAX_LIST_OBJECTS: u32 = 1000;
+
+/// Bytes of objects a component may hold across its containers unless configured otherwise
+pub const DEFAULT_COMPONENT_QUOTA_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Longest file name most filesystems accept, an encoded object name must fit in it
+const MAX_FILE_NAME_BYTES: usize = 255;
+
+/// Prefix of the files objects are written to before being renamed into place
+const TEMP_FILE_PREFIX: &str = ".tmp-";
+
+/// Distinguishes temporary files written concurrently to the same container
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// A directory holding one root per component
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+    component_quota_bytes: u64,
+    /// Bytes held by each opened component, shared by every maker for the same component
+    usage: Arc<Mutex<HashMap<String, Arc<Mutex<u64>>>>>,
+}
+
+impl FsBlobStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            component_quota_bytes: DEFAULT_COMPONENT_QUOTA_BYTES,
+            usage: Default::default(),
+        }
+    }
+
+    /// Limits the bytes of objects each component may hold across all of its containers
+    pub fn with_component_quota(mut self, bytes: u64) -> Self {
+        self.component_quota_bytes = bytes;
+        self
+    }
+
+    /// The containers belonging to the component `name`, which must match `[a-z0-9_-]+`
+    pub fn component(&self, name: &str) -> anyhow::Result<FsBlobRoot> {
+        if !is_valid_storage_name(name) {
+            anyhow::bail!("invalid blob root name {:?}", name);
+        }
+        let dir = self.root.join(name);
+
+        let mut usage = self
+            .usage
+            .lock()
+            .map_err(|_| anyhow!("component usage lock poisoned"))?;
+        let usage = match usage.get(name) {
+            Some(used) => used.clone(),
+            None => {
+                let used = Arc::new(Mutex::new(stored_bytes(&dir)?));
+                usage.insert(name.to_string(), used.clone());
+                used
+            }
+        };
+
+        Ok(FsBlobRoot {
+            dir,
+            quota_bytes: self.component_quota_bytes,
+            usage,
+        })
+    }
+}
+
+/// The root directory of a single component's containers
+#[derive(Debug, Clone)]
+pub struct FsBlobRoot {
+    dir: PathBuf,
+    quota_bytes: u64,
+    /// Bytes of objects in every container. Held while writing so the quota can't be raced past
+    usage: Arc<Mutex<u64>>,
+}
+
+impl FsBlobRoot {
+    /// Every container opened by the returned maker is beneath this root.
+    /// Containers are created when first opened
+    pub fn container_maker(&self) -> HostBlobContainerMaker {
+        let root = self.clone();
+        let maker = service_fn(move |name: String| {
+            let root = root.clone();
+            async move {
+                check_container_name(&name)?;
+                let container = tokio::task::spawn_blocking(move || FsContainer::open(root, &name))
+                    .await
+                    .map_err(|e| ContainerMakeError::Storage {
+                        cause: e.to_string(),
+                    })??;
+                Ok::<HostBlobContainer, ContainerMakeError>(BoxService::new(container))
+            }
+        });
+        BoxService::new(maker)
+    }
+}
+
+/// A guest's handle to one of its containers
+#[derive(Clone)]
+pub struct FsContainer {
+    dir: PathBuf,
+    root: FsBlobRoot,
+}
+
+impl FsContainer {
+    fn open(root: FsBlobRoot, name: &str) -> Result<Self, ContainerMakeError> {
+        let storage = |e: std::io::Error| ContainerMakeError::Storage {
+            cause: e.to_string(),
+        };
+        let dir = root.dir.join(name);
+        std::fs::create_dir_all(dir.join("objects")).map_err(storage)?;
+        remove_stale_temp_files(&dir).map_err(storage)?;
+        Ok(Self { dir, root })
+    }
+}
+
+impl Service<BlobRequest> for FsContainer {
+    type Response = BlobResponse;
+    type Error = BlobStoreError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: BlobRequest) -> Self::Future {
+        let container = self.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || handle(&container, req))
+                .await
+                .map_err(|e| BlobStoreError::Storage {
+                    cause: e.to_string(),
+                })?
+        })
+    }
+}
+
+/// Metadata which can't be read back from the filesystem
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredMetadata {
+    content_type: Option<String>,
+}
+
+fn handle(container: &FsContainer, req: BlobRequest) -> Result<BlobResponse, BlobStoreError> {
+    let dir = container.dir.as_path();
+    match req {
+        BlobRequest::Put {
+            name,
+            data,
+            content_type,
+        } => {
+            let file_name = encode_name(&name)?;
+            let mut header = serde_json::to_vec(&StoredMetadata { content_type }).map_err(|e| {
+                BlobStoreError::Storage {
+                    cause: e.to_string(),
+                }
+            })?;
+            // Compact json never contains a raw newline, so it ends the header
+            header.push(b'\n');
+            let size = (header.len() + data.len()) as u64;
+            if size > MAX_OBJECT_BYTES {
+                return Err(BlobStoreError::TooLarge {
+                    limit: MAX_OBJECT_BYTES,
+                });
+            }
+
+            let path = object_path(dir, &file_name);
+            let mut used = lock_usage(container)?;
+            let reserved = container.root.reserve(*used, file_len(&path)?, size)?;
+            write_atomic(dir, &path, &[header.as_slice(), data.as_slice()])?;
+            *used = reserved;
+            let (_, object) = open_object(dir, &name, &file_name)?;
+            Ok(BlobResponse::Object(object))
+        }
+        BlobRequest::Get { name } => {
+            let file_name = encode_name(&name)?;
+            let (mut object, _) = open_object(dir, &name, &file_name)?;
+            let mut data = Vec::new();
+            object.reader.read_to_end(&mut data)?;
+            Ok(BlobResponse::Data(data))
+        }
+        BlobRequest::GetRange {
+            name,
+            offset,
+            length,
+        } => {
+            let file_name = encode_name(&name)?;
+            let (mut object, _) = open_object(dir, &name, &file_name)?;
+            let mut data = Vec::new();
+            // Offsets past the end of the file read nothing
+            if let Some(start) = object.header_len.checked_add(offset) {
+                object.reader.seek(SeekFrom::Start(start))?;
+                object
+                    .reader
+                    .take(length.min(MAX_OBJECT_BYTES))
+                    .read_to_end(&mut data)?;
+            }
+            Ok(BlobResponse::Data(data))
+        }
+        BlobRequest::Metadata { name } => {
+            let file_name = encode_name(&name)?;
+            let (_, object) = open_object(dir, &name, &file_name)?;
+            Ok(BlobResponse::Object(object))
+        }
+        BlobRequest::List {
+            prefix,
+            start_after,
+            limit,
+        } => {
+            let mut names = Vec::new();
+            for entry in std::fs::read_dir(dir.join("objects"))? {
+                let file_name = entry?.file_name().to_string_lossy().to_string();
+                let Some(name) = decode_name(&file_name) else {
+                    continue;
+                };
+                let after = start_after.as_ref().map_or(true, |after| &name > after);
+                if after && name.starts_with(&prefix) {
+                    names.push((name, file_name));
+                }
+            }
+            names.sort();
+
+            let mut objects = Vec::new();
+            for (name, file_name) in names.into_iter().take(limit.min(MAX_LIST_OBJECTS) as usize) {
+                match open_object(dir, &name, &file_name) {
+                    Ok((_, object)) => objects.push(object),
+                    // Deleted since the directory was read
+                    Err(BlobStoreError::NotFound { .. }) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(BlobResponse::Objects(objects))
+        }
+        BlobRequest::Delete { name } => {
+            let file_name = encode_name(&name)?;
+            let path = object_path(dir, &file_name);
+            let mut used = lock_usage(container)?;
+            let freed = file_len(&path)?;
+            let existed = remove_if_exists(&path)?;
+            *used = used.saturating_sub(freed);
+            Ok(BlobResponse::Deleted(existed))
+        }
+    }
+}
+
+impl FsBlobRoot {
+    /// Usage after replacing `current` bytes with `new` bytes, failing if it would grow past the quota
+    fn reserve(&self, used: u64, current: u64, new: u64) -> Result<u64, BlobStoreError> {
+        let after = used.saturating_sub(current) + new;
+        if new > current && after > self.quota_bytes {
+            return Err(BlobStoreError::QuotaExceeded {
+                limit: self.quota_bytes,
+            });
+        }
+        Ok(after)
+    }
+}
+
+fn lock_usage(container: &FsContainer) -> Result<MutexGuard<'_, u64>, BlobStoreError> {
+    container
+        .root
+        .usage
+        .lock()
+        .map_err(|_| BlobStoreError::Storage {
+            cause: "component usage lock poisoned".to_string(),
+        })
+}
+
+fn object_path(dir: &Path, file_name: &str) -> PathBuf {
+    dir.join("objects").join(file_name)
+}
+
+/// An object file read up to the start of its data
+struct StoredObject {
+    reader: BufReader<File>,
+    header_len: u64,
+}
+
+fn open_object(
+    dir: &Path,
+    name: &str,
+    file_name: &str,
+) -> Result<(StoredObject, ObjectMetadata), BlobStoreError> {
+    let file = File::open(object_path(dir, file_name)).map_err(not_found(name))?;
+    let file_metadata = file.metadata()?;
+    let modified_ms = file_metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|modified| modified.as_millis() as u64)
+        .unwrap_or_default();
+
+    let mut reader = BufReader::new(file);
+    let mut header = Vec::new();
+    (&mut reader)
+        .take(MAX_OBJECT_BYTES)
+        .read_until(b'\n', &mut header)?;
+    let corrupt = || BlobStoreError::Storage {
+        cause: format!("object {} is corrupt", name),
+    };
+    if header.last() != Some(&b'\n') {
+        return Err(corrupt());
+    }
+    let stored: StoredMetadata = serde_json::from_slice(&header).map_err(|_| corrupt())?;
+    let header_len = header.len() as u64;
+
+    let object = ObjectMetadata {
+        name: name.to_string(),
+        size: file_metadata.len().saturating_sub(header_len),
+        content_type: stored.content_type,
+        modified_ms,
+    };
+    Ok((StoredObject { reader, header_len }, object))
+}
+
+/// Writes `parts` to a temporary file first and renames it into place,
+/// so readers never observe a partially written object
+fn write_atomic(dir: &Path, path: &Path, parts: &[&[u8]]) -> Result<(), BlobStoreError> {
+    let temp = dir.join(format!(
+        "{}{}-{}",
+        TEMP_FILE_PREFIX,
+        std::process::id(),
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let write = || {
+        let mut file = File::create(&temp)?;
+        for part in parts {
+            file.write_all(part)?;
+        }
+        // The contents must reach the disk before the rename does
+        file.sync_all()?;
+        std::fs::rename(&temp, path)
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Removes temporary files left behind by another process which crashed mid write
+fn remove_stale_temp_files(dir: &Path) -> std::io::Result<()> {
+    let own = format!("{}{}-", TEMP_FILE_PREFIX, std::process::id());
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if file_name.starts_with(TEMP_FILE_PREFIX) && !file_name.starts_with(&own) {
+            match std::fs::remove_file(entry.path()) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of every object stored beneath a component's root
+fn stored_bytes(root: &Path) -> std::io::Result<u64> {
+    let mut used = 0;
+    for container in read_dir_if_exists(root)? {
+        for object in read_dir_if_exists(&container?.path().join("objects"))? {
+            used += object?.metadata()?.len();
+        }
+    }
+    Ok(used)
+}
+
+fn read_dir_if_exists(dir: &Path) -> std::io::Result<Vec<std::io::Result<std::fs::DirEntry>>> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => Ok(entries.collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// The length of the file at `path`, 0 if it doesn't exist
+fn file_len(path: &Path) -> Result<u64, BlobStoreError> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<bool, BlobStoreError> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn not_found(name: &str) -> impl FnOnce(std::io::Error) -> BlobStoreError + '_ {
+    move |e| match e.kind() {
+        ErrorKind::NotFound => BlobStoreError::NotFound {
+            name: name.to_string(),
+        },
+        _ => e.into(),
+    }
+}
+
+fn check_container_name(name: &str) -> Result<(), ContainerMakeError> {
+    let invalid = |reason: &str| {
+        Err(ContainerMakeError::InvalidName {
+            reason: reason.to_string(),
+        })
+    };
+    if name.is_empty() {
+        return invalid("container names may not be empty");
+    }
+    if name.len() > MAX_CONTAINER_NAME_CHARS {
+        return invalid("container names may be at most 64 characters");
+    }
+    if name.starts_with('.') {
+        return invalid("container names may not start with '.'");
+    }
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c);
+    if !name.chars().all(allowed) {
+        return invalid("container names may only contain a-z, 0-9, '-', '_' and '.'");
+    }
+    Ok(())
+}
+
+/// Encodes an object name as a single file name.
+/// Any byte outside of `[a-z0-9_.-]` and a leading '.' are percent-encoded,
+/// so names like `..` or `a/b` can never be interpreted as a path and names differing
+/// only in case stay distinct on case-insensitive filesystems
+fn encode_name(name: &str) -> Result<String, BlobStoreError> {
+    if name.is_empty() {
+        return Err(BlobStoreError::InvalidName {
+            reason: "object names may not be empty".to_string(),
+        });
+    }
+    let mut encoded = String::with_capacity(name.len());
+    for (i, byte) in name.bytes().enumerate() {
+        let plain = byte.is_ascii_lowercase() || byte.is_ascii_digit() || b"_.-".contains(&byte);
+        if plain && !(i == 0 && byte == b'.') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if encoded.len() > MAX_FILE_NAME_BYTES {
+        return Err(BlobStoreError::InvalidName {
+            reason: format!(
+                "object name encodes to more than {} bytes",
+                MAX_FILE_NAME_BYTES
+            ),
+        });
+    }
+    Ok(encoded)
+}
+
+/// Reverses `encode_name`, files which it couldn't have produced are ignored
+fn decode_name(file_name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(file_name.len());
+    let mut rest = file_name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    let name = String::from_utf8(bytes).ok()?;
+    // Rejects temporary files and spellings like lowercase hex
+    (encode_name(&name).ok()? == file_name).then_some(name)
+}
+
+impl From<std::io::Error> for BlobStoreError {
+    fn from(e: std::io::Error) -> Self {
+        BlobStoreError::Storage {
+            cause: e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Opens a container of the component `component` kept in `store`
+    async fn open(
+        store: &FsBlobStore,
+        container: &str,
+    ) -> Result<HostBlobContainer, ContainerMakeError> {
+        let mut maker = store.component("component").unwrap().container_maker();
+        maker
+            .ready()
+            .await
+            .unwrap()
+            .call(container.to_string())
+            .await
+    }
+
+    async fn send(
+        container: &mut HostBlobContainer,
+        req: BlobRequest,
+    ) -> Result<BlobResponse, BlobStoreError> {
+        container.ready().await?.call(req).await
+    }
+
+    fn list(prefix: &str, start_after: Option<&str>, limit: u32) -> BlobRequest {
+        BlobRequest::List {
+            prefix: prefix.to_string(),
+            start_after: start_after.map(str::to_string),
+            limit,
+        }
+    }
+
+    /// Names of the objects a `List` returned
+    async fn list_names(container: &mut HostBlobContainer, req: BlobRequest) -> Vec<String> {
+        let Ok(BlobResponse::Objects(objects)) = send(container, req).await else {
+            panic!("expected objects");
+        };
+        objects.into_iter().map(|object| object.name).collect()
+    }
+
+    fn put(name: &str, data: &[u8]) -> BlobRequest {
+        BlobRequest::Put {
+            name: name.to_string(),
+            data: data.to_vec(),
+            content_type: Some("text/plain".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_stores_and_lists_objects() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::new(root.path().to_path_buf());
+        let mut container = open(&store, "uploads").await.unwrap();
+
+        send(&mut container, put("notes/b.txt", b"bee"))
+            .await
+            .unwrap();
+        send(&mut container, put("notes/a.txt", b"ay"))
+            .await
+            .unwrap();
+        send(&mut container, put("other", b"")).await.unwrap();
+
+        let get = BlobRequest::Get {
+            name: "notes/b.txt".to_string(),
+        };
+        let Ok(BlobResponse::Data(data)) = send(&mut container, get).await else {
+            panic!("expected object data");
+        };
+        assert_eq!(data, b"bee");
+
+        let range = BlobRequest::GetRange {
+            name: "notes/b.txt".to_string(),
+            offset: 1,
+            length: 10,
+        };
+        let Ok(BlobResponse::Data(data)) = send(&mut container, range).await else {
+            panic!("expected object data");
+        };
+        assert_eq!(data, b"ee");
+
+        let list = list("notes/", None, 10);
+        let Ok(BlobResponse::Objects(objects)) = send(&mut container, list).await else {
+            panic!("expected objects");
+        };
+        let names: Vec<_> = objects.iter().map(|object| object.name.as_str()).collect();
+        assert_eq!(names, ["notes/a.txt", "notes/b.txt"]);
+        assert_eq!(objects[1].size, 3);
+        assert_eq!(objects[1].content_type.as_deref(), Some("text/plain"));
+
+        let delete = BlobRequest::Delete {
+            name: "other".to_string(),
+        };
+        let deleted = send(&mut container, delete.clone()).await.unwrap();
+        assert!(matches!(deleted, BlobResponse::Deleted(true)));
+        let deleted = send(&mut container, delete).await.unwrap();
+        assert!(matches!(deleted, BlobResponse::Deleted(false)));
+
+        let metadata = BlobRequest::Metadata {
+            name: "other".to_string(),
+        };
+        let result = send(&mut container, metadata).await;
+        assert!(matches!(result, Err(BlobStoreError::NotFound { .. })));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_objects_inside_the_container() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::new(root.path().to_path_buf());
+        let mut container = open(&store, "uploads").await.unwrap();
+
+        let names = [
+            "..",
+            "../escape",
+            "/etc/passwd",
+            ".hidden",
+            "a\\b",
+            "README",
+            "readme",
+        ];
+        for name in names {
+            send(&mut container, put(name, name.as_bytes()))
+                .await
+                .unwrap();
+        }
+        let entries: Vec<_> = std::fs::read_dir(root.path().join("component"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["uploads"]);
+
+        let names = list_names(&mut container, list("", None, 10)).await;
+        assert_eq!(
+            names,
+            [
+                "..",
+                "../escape",
+                ".hidden",
+                "/etc/passwd",
+                "README",
+                "a\\b",
+                "readme"
+            ]
+        );
+
+        // Names differing in case never share a file, even on case-insensitive filesystems
+        assert_eq!(encode_name("README").unwrap(), "%52%45%41%44%4D%45");
+        let get = BlobRequest::Get {
+            name: "README".to_string(),
+        };
+        let Ok(BlobResponse::Data(data)) = send(&mut container, get).await else {
+            panic!("expected object data");
+        };
+        assert_eq!(data, b"README");
+
+        for name in ["", "..", "../uploads", "Uploads", "a/b"] {
+            let result = open(&store, name).await;
+            assert!(matches!(
+                result,
+                Err(ContainerMakeError::InvalidName { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn it_rejects_large_objects_and_names() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::new(root.path().to_path_buf());
+        let mut container = open(&store, "uploads").await.unwrap();
+
+        let large = vec![0; MAX_OBJECT_BYTES as usize + 1];
+        let result = send(&mut container, put("large", &large)).await;
+        assert!(matches!(result, Err(BlobStoreError::TooLarge { .. })));
+
+        let long_name = "/".repeat(MAX_FILE_NAME_BYTES);
+        let result = send(&mut container, put(&long_name, b"x")).await;
+        assert!(matches!(result, Err(BlobStoreError::InvalidName { .. })));
+    }
+
+    #[tokio::test]
+    async fn it_pages_through_objects() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::new(root.path().to_path_buf());
+        let mut container = open(&store, "uploads").await.unwrap();
+
+        for name in ["a", "b", "c", "d", "e"] {
+            send(&mut container, put(name, b"x")).await.unwrap();
+        }
+
+        let page = list_names(&mut container, list("", None, 2)).await;
+        assert_eq!(page, ["a", "b"]);
+        let page = list_names(&mut container, list("", Some("b"), 2)).await;
+        assert_eq!(page, ["c", "d"]);
+        let page = list_names(&mut container, list("", Some("d"), 2)).await;
+        assert_eq!(page, ["e"]);
+        let page = list_names(&mut container, list("", Some("e"), 2)).await;
+        assert!(page.is_empty());
+
+        // The host caps the page size
+        let objects = root
+            .path()
+            .join("component")
+            .join("uploads")
+            .join("objects");
+        for i in 0..MAX_LIST_OBJECTS {
+            let file_name = encode_name(&format!("many/{}", i)).unwrap();
+            std::fs::write(objects.join(file_name), b"{}\n").unwrap();
+        }
+        let page = list_names(&mut container, list("", None, u32::MAX)).await;
+        assert_eq!(page.len(), MAX_LIST_OBJECTS as usize);
+    }
+
+    #[tokio::test]
+    async fn it_enforces_the_component_quota() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::new(root.path().to_path_buf()).with_component_quota(200);
+        let mut container = open(&store, "uploads").await.unwrap();
+        let mut other = open(&store, "assets").await.unwrap();
+        // Every object also stores its metadata
+        let header = br#"{"content_type":"text/plain"}"#.len() + 1;
+
+        send(&mut container, put("a", &vec![0; 200 - header]))
+            .await
+            .unwrap();
+        let result = send(&mut container, put("b", b"")).await;
+        assert!(matches!(result, Err(BlobStoreError::QuotaExceeded { .. })));
+        // Every container of the component shares the quota
+        let result = send(&mut other, put("b", b"")).await;
+        assert!(matches!(result, Err(BlobStoreError::QuotaExceeded { .. })));
+        // Other components have their own quota
+        let mut maker = store.component("other").unwrap().container_maker();
+        let mut elsewhere = maker
+            .ready()
+            .await
+            .unwrap()
+            .call("uploads".to_string())
+            .await
+            .unwrap();
+        send(&mut elsewhere, put("b", b"")).await.unwrap();
+
+        // Shrinking or replacing objects is allowed when full
+        send(&mut container, put("a", &[0; 10])).await.unwrap();
+        send(&mut other, put("b", &[0; 10])).await.unwrap();
+
+        // Deleting frees space
+        let delete = BlobRequest::Delete {
+            name: "a".to_string(),
+        };
+        send(&mut container, delete).await.unwrap();
+        send(&mut container, put("c", &[0; 10])).await.unwrap();
+
+        // Usage is read back from disk by a new store
+        let store = FsBlobStore::new(root.path().to_path_buf()).with_component_quota(200);
+        let mut reopened = open(&store, "uploads").await.unwrap();
+        let free = 200 - 2 * (header + 10);
+        let result = send(&mut reopened, put("d", &vec![0; free])).await;
+        assert!(matches!(result, Err(BlobStoreError::QuotaExceeded { .. })));
+        send(&mut reopened, put("d", &vec![0; free - header]))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_removes_writes_interrupted_by_a_crash() {
+        let root = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::new(root.path().to_path_buf());
+        let dir = root.path().join("component").join("uploads");
+        std::fs::create_dir_all(&dir).unwrap();
+        // Left behind by a process which can't be running any more
+        let stale = dir.join(format!("{}0-0", TEMP_FILE_PREFIX));
+        std::fs::write(&stale, b"partial").unwrap();
+
+        let mut container = open(&store, "uploads").await.unwrap();
+        assert!(!stale.exists());
+
+        // An object and its metadata are a single file, replaced by one rename
+        send(&mut container, put("a", b"first")).await.unwrap();
+        send(&mut container, put("a", b"second")).await.unwrap();
+        let entries: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, ["objects"]);
+        let objects = std::fs::read_dir(dir.join("objects")).unwrap().count();
+        assert_eq!(objects, 1);
+    }
+}
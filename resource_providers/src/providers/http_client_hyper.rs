@@ -7,28 +7,365 @@
 //! * developers should interact with the wasm client using the [wonderful http crate](https://crates.io/crates/http)
 //!     so they're not using some weird types in their code
 
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{future::Future, pin::Pin};
 
-use anyhow::anyhow;
-use hyper::{body::HttpBody, Body, Method, Request};
-use tower::{util::BoxService, Service, ServiceBuilder, ServiceExt};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::{body::HttpBody, Body, Client, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use lru::LruCache;
+use thiserror::Error;
+use tower::{util::BoxService, BoxError, Service, ServiceBuilder, ServiceExt};
 
+use crate::http::egress::is_public;
 use crate::http::{
-    ClientRequest, ClientResponse, ClientResult, HostClientMaker, HttpClientError,
+    map_headers, ClientRequest, ClientResponse, ClientResult, HostClientMaker,
+    HostStreamingClientMaker, HttpClientError, Options, StreamedResponse, StreamedResult,
+    TimeoutPhase,
 };
 
+/// Settings for the hyper client shared by every guest in the host process
+#[derive(Debug, Clone)]
+pub struct HyperClientConfig {
+    /// Idle keep-alive connections kept open per remote host
+    pub pool_max_idle_per_host: usize,
+    /// Idle connections are closed after this long
+    pub pool_idle_timeout: Duration,
+    /// Resolved addresses are reused for this long
+    pub dns_cache_ttl: Duration,
+    /// Most names kept in the resolver cache, the least recently used are evicted past it
+    pub dns_cache_capacity: usize,
+    /// Interval of TCP keepalive probes on open connections. `None` disables them
+    pub tcp_keepalive: Option<Duration>,
+    /// Connect to loopback, private and other non public addresses names resolve to.
+    /// When unset only the public addresses of a name are dialed, see `EgressPolicy`
+    /// for addresses written into the uri
+    pub allow_private_ips: bool,
+    /// Negotiate HTTP/2 with servers which support it
+    pub http2: bool,
+    /// Connect timeout for requests which don't set one
+    pub connect_timeout: Duration,
+    /// Total timeout for requests which don't set one
+    pub request_timeout: Duration,
+    /// Ceiling applied to every timeout a guest asks for
+    pub max_timeout: Duration,
+    /// Largest response body buffered for a guest. Streamed bodies aren't limited
+    pub max_response_bytes: u64,
+}
+
+impl Default for HyperClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout: Duration::from_secs(90),
+            dns_cache_ttl: Duration::from_secs(60),
+            dns_cache_capacity: 1024,
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            allow_private_ips: false,
+            http2: true,
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            max_timeout: Duration::from_secs(120),
+            max_response_bytes: 5 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClientTimeouts {
+    connect: Duration,
+    request: Duration,
+    max: Duration,
+}
+
+impl ClientTimeouts {
+    /// Resolves the (connect, total) timeouts for a request, capped by the host ceiling
+    fn resolve(&self, options: Option<&Options>) -> (Duration, Duration) {
+        let resolve = |requested_ms: Option<u32>, default: Duration| {
+            requested_ms
+                .map(|ms| Duration::from_millis(ms.into()))
+                .unwrap_or(default)
+                .min(self.max)
+        };
+
+        (
+            resolve(options.and_then(|o| o.connect_timeout_ms), self.connect),
+            resolve(options.and_then(|o| o.timeout_ms), self.request),
+        )
+    }
+}
+
+type PooledClient = Client<TimeoutConnector<HttpsConnector<HttpConnector<CachingResolver>>>, Body>;
+
+/// A connection pooled hyper client. Cheap to clone, clones share the same pool
+#[derive(Debug, Clone)]
+pub struct SharedHyperClient {
+    client: PooledClient,
+    timeouts: ClientTimeouts,
+    max_response_bytes: u64,
+}
+
+impl SharedHyperClient {
+    /// A client sharing this pool which buffers responses up to `limit` bytes
+    pub fn with_max_response_bytes(&self, limit: u64) -> Self {
+        Self {
+            max_response_bytes: limit,
+            ..self.clone()
+        }
+    }
+}
+
+/// Builds a new connection pooled client.
+/// Create one per host process and share it with `new_client_maker`
+pub fn new_shared_client(config: &HyperClientConfig) -> SharedHyperClient {
+    let mut http = HttpConnector::new_with_resolver(CachingResolver::new(
+        config.dns_cache_ttl,
+        config.dns_cache_capacity,
+        config.allow_private_ips,
+    ));
+    // https is handled by the wrapping connector
+    http.enforce_http(false);
+    http.set_keepalive(config.tcp_keepalive);
+
+    let builder = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1();
+    let https = if config.http2 {
+        builder.enable_http2().wrap_connector(http)
+    } else {
+        builder.wrap_connector(http)
+    };
+
+    let timeouts = ClientTimeouts {
+        connect: config.connect_timeout,
+        request: config.request_timeout,
+        max: config.max_timeout,
+    };
+
+    let client = Client::builder()
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .build(TimeoutConnector {
+            inner: https,
+            default: timeouts.connect.min(timeouts.max),
+        });
+
+    SharedHyperClient {
+        client,
+        timeouts,
+        max_response_bytes: config.max_response_bytes,
+    }
+}
 
-pub fn new_client_maker() -> HostClientMaker {
-    let service = ServiceBuilder::new().service_fn(|_v: ()| async {
-        let service = HyperHostClient;
+/// Produces host clients which make requests with the shared `client`
+pub fn new_client_maker(client: SharedHyperClient) -> HostClientMaker {
+    let service = ServiceBuilder::new().service_fn(move |_v: ()| {
+        let service = HyperHostClient {
+            client: client.clone(),
+        };
 
-        Ok(service.boxed())
+        async move { Ok(service.boxed()) }
     });
 
     BoxService::new(service)
 }
 
-struct HyperHostClient;
+/// Produces host clients which leave response bodies for the guest to read incrementally
+pub fn new_streaming_client_maker(client: SharedHyperClient) -> HostStreamingClientMaker {
+    let service = ServiceBuilder::new().service_fn(move |_v: ()| {
+        let service = HyperStreamingClient {
+            client: client.clone(),
+        };
+
+        async move { Ok(service.boxed()) }
+    });
+
+    BoxService::new(service)
+}
+
+type DnsCache = LruCache<Name, (Instant, Vec<SocketAddr>)>;
+
+#[derive(Error, Debug)]
+#[error("{0} doesn't resolve to a public address")]
+struct NonPublicName(String);
+
+/// Resolves names with `getaddrinfo`, caching results for `ttl`.
+/// Guests choose the names resolved, so at most `capacity` are cached.
+///
+/// The connector dials exactly the addresses returned here, so unless `allow_private_ips`
+/// is set non public addresses are dropped and a name with none left fails to resolve.
+#[derive(Clone)]
+pub struct CachingResolver {
+    resolver: GaiResolver,
+    ttl: Duration,
+    cache: Arc<Mutex<DnsCache>>,
+    allow_private_ips: bool,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration, capacity: usize, allow_private_ips: bool) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            resolver: GaiResolver::new(),
+            ttl,
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+            allow_private_ips,
+        }
+    }
+
+    /// The addresses of `name` which may be dialed
+    fn dialable(
+        allow_private_ips: bool,
+        name: &Name,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<std::vec::IntoIter<SocketAddr>, std::io::Error> {
+        if allow_private_ips {
+            return Ok(addrs.into_iter());
+        }
+
+        let public: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| is_public(&addr.ip()))
+            .collect();
+        if public.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                NonPublicName(name.to_string()),
+            ));
+        }
+        Ok(public.into_iter())
+    }
+
+    fn cached(&self, name: &Name) -> Option<Vec<SocketAddr>> {
+        let mut cache = self.cache.lock().expect("dns cache lock poisoned");
+        match cache.get(name) {
+            Some((resolved_at, addrs)) if resolved_at.elapsed() < self.ttl => Some(addrs.clone()),
+            Some(_) => {
+                cache.pop(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(cache: &Mutex<DnsCache>, ttl: Duration, name: Name, addrs: Vec<SocketAddr>) {
+        let mut cache = cache.lock().expect("dns cache lock poisoned");
+        // Expired entries go first, so live ones aren't evicted to make room
+        while cache
+            .peek_lru()
+            .is_some_and(|(_, (resolved_at, _))| resolved_at.elapsed() >= ttl)
+        {
+            cache.pop_lru();
+        }
+        cache.put(name, (Instant::now(), addrs));
+    }
+}
+
+impl Service<Name> for CachingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+
+    type Error = std::io::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.resolver.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_private_ips = self.allow_private_ips;
+        if let Some(addrs) = self.cached(&name) {
+            return Box::pin(async move { Self::dialable(allow_private_ips, &name, addrs) });
+        }
+
+        let resolving = self.resolver.call(name.clone());
+        let (cache, ttl) = (self.cache.clone(), self.ttl);
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving.await?.collect();
+            Self::insert(&cache, ttl, name.clone(), addrs.clone());
+            Self::dialable(allow_private_ips, &name, addrs)
+        })
+    }
+}
+
+tokio::task_local! {
+    /// Connect timeout for requests made within the current scope
+    static CONNECT_TIMEOUT: Duration;
+}
+
+#[derive(Error, Debug)]
+#[error("connect timed out after {0:?}")]
+struct ConnectTimeout(Duration);
+
+/// Bounds the time taken to establish a connection.
+///
+/// The connector is shared by every request using the pool, so the limit is read from
+/// `CONNECT_TIMEOUT` for the request which triggered the connect.
+#[derive(Clone)]
+pub struct TimeoutConnector<C> {
+    inner: C,
+    default: Duration,
+}
+
+impl<C> Service<Uri> for TimeoutConnector<C>
+where
+    C: Service<Uri>,
+    C::Response: Send + 'static,
+    C::Future: Send + 'static,
+    C::Error: Into<BoxError>,
+{
+    type Response = C::Response;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let limit = CONNECT_TIMEOUT
+            .try_with(|limit| *limit)
+            .unwrap_or(self.default);
+        let connecting = self.inner.call(uri);
+
+        Box::pin(async move {
+            match tokio::time::timeout(limit, connecting).await {
+                Ok(connection) => connection.map_err(Into::into),
+                Err(_) => Err(ConnectTimeout(limit).into()),
+            }
+        })
+    }
+}
+
+fn is_connect_timeout(e: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if e.is::<ConnectTimeout>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+fn timeout_error(phase: TimeoutPhase, limit: Duration) -> HttpClientError {
+    HttpClientError::Timeout {
+        phase,
+        limit_ms: limit.as_millis().try_into().unwrap_or(u32::MAX),
+    }
+}
+
+struct HyperHostClient {
+    client: SharedHyperClient,
+}
 
 impl Service<ClientRequest> for HyperHostClient {
     type Response = ClientResult;
@@ -45,46 +382,118 @@ impl Service<ClientRequest> for HyperHostClient {
     }
 
     fn call(&mut self, req: ClientRequest) -> Self::Future {
-        // TODO creating a new client for each request for now
-        // in the future we should Arc<Mutex<Client>>
-        // and implement poll correctly
-        let _client = hyper::Client::new();
-        let https = hyper_tls::HttpsConnector::new();
-        let client = hyper::Client::builder().build(https);
+        let client = self.client.clone();
 
         Box::pin(async move {
-            // TODO we need to lower non-fatal errors to the guest
-            let resp = client.request(req.try_into()?).await?;
-            let (parts, mut data) = resp.into_parts();
-
-            let body = read_body_stream(&mut data).await;
-            let body = body.map_err(|e| HttpClientError::ClientError {
-                cause: format!("failed to ready body {:#?}", e),
-            })?;
-
-            let status = parts.status;
-
-            let headers = parts
-                .headers
-                .into_iter()
-                .map(|(k, v)| {
-                    (
-                        k.map(|v| v.to_string()).unwrap_or("".to_string()),
-                        v.to_str().unwrap().to_string(),
-                    )
-                })
-                .collect();
+            let (_, total_timeout) = client.timeouts.resolve(req.options.as_ref());
+            let limit = client.max_response_bytes;
+
+            let receiving = async move {
+                let resp = start_request(client, req).await?;
+                read_response(resp, limit).await
+            };
+
+            match tokio::time::timeout(total_timeout, receiving).await {
+                Ok(result) => result,
+                Err(_) => Err(timeout_error(TimeoutPhase::Total, total_timeout)),
+            }
+        })
+    }
+}
 
-            let r = ClientResult::Ok(ClientResponse {
-                status: status.as_u16(),
-                headers,
+struct HyperStreamingClient {
+    client: SharedHyperClient,
+}
+
+impl Service<ClientRequest> for HyperStreamingClient {
+    type Response = StreamedResult;
+
+    type Error = HttpClientError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// The total timeout only covers receiving the response head,
+    /// the guest reads the body at its own pace
+    fn call(&mut self, req: ClientRequest) -> Self::Future {
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let (_, total_timeout) = client.timeouts.resolve(req.options.as_ref());
+
+            let resp = match tokio::time::timeout(total_timeout, start_request(client, req)).await {
+                Ok(resp) => resp?,
+                Err(_) => return Err(timeout_error(TimeoutPhase::Total, total_timeout)),
+            };
+
+            let (parts, body) = resp.into_parts();
+            Ok(StreamedResult::Ok(StreamedResponse {
+                status: parts.status.as_u16(),
+                headers: map_headers(&parts.headers),
                 body,
-            });
-            Ok(r)
+            }))
         })
     }
 }
 
+/// Sends `req`, resolving once the response head is received
+async fn start_request(
+    client: SharedHyperClient,
+    req: ClientRequest,
+) -> Result<hyper::Response<Body>, HttpClientError> {
+    let SharedHyperClient {
+        client, timeouts, ..
+    } = client;
+    let (connect_timeout, _) = timeouts.resolve(req.options.as_ref());
+    let request = req.try_into()?;
+
+    CONNECT_TIMEOUT
+        .scope(connect_timeout, async move {
+            client.request(request).await.map_err(|e| {
+                if is_connect_timeout(&e) {
+                    timeout_error(TimeoutPhase::Connect, connect_timeout)
+                } else {
+                    e.into()
+                }
+            })
+        })
+        .await
+}
+
+/// Buffers a response for the guest
+async fn read_response(
+    resp: hyper::Response<Body>,
+    limit: u64,
+) -> Result<ClientResult, HttpClientError> {
+    let (parts, mut data) = resp.into_parts();
+
+    // Don't bother reading a body which is known to be too large
+    let content_length = parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(HttpClientError::BodyTooLarge { limit });
+    }
+
+    let body = read_body_stream(&mut data, limit).await?;
+
+    let status = parts.status;
+
+    let headers = map_headers(&parts.headers);
+
+    let r = ClientResult::Ok(ClientResponse {
+        status: status.as_u16(),
+        headers,
+        body,
+    });
+    Ok(r)
+}
+
 impl TryInto<Request<Body>> for ClientRequest {
     type Error = HttpClientError;
 
@@ -105,33 +514,66 @@ impl TryInto<Request<Body>> for ClientRequest {
 
 impl From<hyper::Error> for HttpClientError {
     fn from(value: hyper::Error) -> Self {
-
         let cause = value.to_string();
+
+        let mut source = std::error::Error::source(&value);
+        while let Some(e) = source {
+            if let Some(e) = classify_error(e) {
+                return e;
+            }
+            source = e.source();
+        }
+
         HttpClientError::ClientError { cause }
     }
 }
 
-// prevent a malicious tenant from consuming too much resources
-static RESPONSE_LIMIT: usize = 5 * 1024 * 1024;
+/// Recognizes failures a guest may want to handle differently, like retrying a refused connection
+fn classify_error(e: &(dyn std::error::Error + 'static)) -> Option<HttpClientError> {
+    let cause = e.to_string();
+
+    if e.is::<rustls::Error>() {
+        return Some(HttpClientError::TlsError { cause });
+    }
+
+    if let Some(io) = e.downcast_ref::<std::io::Error>() {
+        if let Some(inner) = io.get_ref().filter(|inner| inner.is::<NonPublicName>()) {
+            return Some(HttpClientError::PolicyDenied {
+                reason: inner.to_string(),
+            });
+        }
+        if io.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) {
+            return Some(HttpClientError::TlsError { cause });
+        }
+        return match io.kind() {
+            std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::ConnectionReset => {
+                Some(HttpClientError::ConnectRefused { cause })
+            }
+            _ => None,
+        };
+    }
+
+    // hyper's connector reports resolver failures as a `dns error`
+    if cause.starts_with("dns error") {
+        return Some(HttpClientError::DnsFailure { cause });
+    }
+
+    None
+}
 
-/// TODO
-/// we should create a read / write stream resources to use until wasi streams are ready.
-/// This works for now. But, guests might benefit from having streaming access to the body.
+/// Buffers `body`, failing once more than `limit` bytes are read.
+/// This prevents a malicious tenant from consuming too much resources.
 ///
-/// This work can be done whenever we start on adding websocket support
-async fn read_body_stream(body: &mut hyper::Body) -> anyhow::Result<Vec<u8>> {
+/// Guests which need large bodies should read them with `send-streaming` instead
+async fn read_body_stream(body: &mut hyper::Body, limit: u64) -> Result<Vec<u8>, HttpClientError> {
     let mut out: Vec<u8> = vec![];
-    let mut size = 0;
+    let mut size: u64 = 0;
     while let Some(response) = body.data().await {
         let bytes = response?;
 
-        size += bytes.len();
-        if size > RESPONSE_LIMIT {
-            return Err(anyhow!(
-                "attempted to stream too much data amount {} limit {}",
-                size,
-                RESPONSE_LIMIT
-            ));
+        size += bytes.len() as u64;
+        if size > limit {
+            return Err(HttpClientError::BodyTooLarge { limit });
         }
         out.reserve(bytes.len());
         out.extend_from_slice(&bytes);
@@ -152,6 +594,281 @@ fn get_method(req: &ClientRequest) -> Result<Method, HttpClientError> {
         WasmHttpMethod::Options => Ok(Method::OPTIONS),
         WasmHttpMethod::Trace => Ok(Method::TRACE),
         WasmHttpMethod::Patch => Ok(Method::PATCH),
-        WasmHttpMethod::Other(_) => Err(HttpClientError::BadRequest),
+        // Extension methods like PROPFIND must be valid tokens
+        WasmHttpMethod::Other(method) => {
+            Method::from_bytes(method.as_bytes()).map_err(|_| HttpClientError::BadRequest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::http::strategies::headers;
+    use crate::http::{Headers, Method as WasmHttpMethod};
+
+    fn request(method: WasmHttpMethod, headers: Headers) -> ClientRequest {
+        ClientRequest {
+            method,
+            headers,
+            body: vec![],
+            uri: "http://example.com/".to_string(),
+            options: None,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn it_sends_arbitrary_headers_unchanged(headers in headers()) {
+            let sent: Request<Body> =
+                request(WasmHttpMethod::Get, headers.clone()).try_into().unwrap();
+            let mapped = map_headers(sent.headers());
+
+            prop_assert_eq!(mapped.len(), headers.len());
+            for (name, _) in &headers {
+                let values = |headers: &Headers| -> Vec<Vec<u8>> {
+                    headers.iter().filter(|(n, _)| n == name).map(|(_, v)| v.clone()).collect()
+                };
+                prop_assert_eq!(values(&mapped), values(&headers));
+            }
+        }
+    }
+
+    #[test]
+    fn it_bounds_the_dns_cache() {
+        let resolver = CachingResolver::new(Duration::from_secs(60), 2, false);
+        let name = |name: &str| name.parse::<Name>().unwrap();
+        let addrs = vec![SocketAddr::from(([93, 184, 216, 34], 0))];
+        for host in ["a.example.com", "b.example.com"] {
+            CachingResolver::insert(&resolver.cache, resolver.ttl, name(host), addrs.clone());
+        }
+        // Using a name keeps it over the least recently used one
+        assert!(resolver.cached(&name("a.example.com")).is_some());
+        CachingResolver::insert(&resolver.cache, resolver.ttl, name("c.example.com"), addrs);
+
+        assert!(resolver.cached(&name("a.example.com")).is_some());
+        assert!(resolver.cached(&name("b.example.com")).is_none());
+        assert!(resolver.cached(&name("c.example.com")).is_some());
+        assert_eq!(resolver.cache.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn it_only_dials_public_addresses() {
+        let mut resolver = CachingResolver::new(Duration::from_secs(60), 8, false);
+        let name = |name: &str| name.parse::<Name>().unwrap();
+        let public = SocketAddr::from(([93, 184, 216, 34], 0));
+        let private = SocketAddr::from(([169, 254, 169, 254], 0));
+        CachingResolver::insert(
+            &resolver.cache,
+            resolver.ttl,
+            name("mixed.example.com"),
+            vec![private, public],
+        );
+        CachingResolver::insert(
+            &resolver.cache,
+            resolver.ttl,
+            name("rebound.example.com"),
+            vec![private],
+        );
+
+        let dialed: Vec<SocketAddr> = resolver
+            .call(name("mixed.example.com"))
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(dialed, vec![public]);
+
+        let err = resolver
+            .call(name("rebound.example.com"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            classify_error(&err),
+            Some(HttpClientError::PolicyDenied { .. })
+        ));
+
+        let mut permissive = CachingResolver::new(Duration::from_secs(60), 8, true);
+        CachingResolver::insert(
+            &permissive.cache,
+            permissive.ttl,
+            name("internal.example.com"),
+            vec![private],
+        );
+        let dialed: Vec<SocketAddr> = permissive
+            .call(name("internal.example.com"))
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(dialed, vec![private]);
+    }
+
+    /// An error only known by its message, like the resolver failures hyper reports
+    #[derive(Error, Debug)]
+    #[error("{0}")]
+    struct Message(&'static str);
+
+    #[test]
+    fn it_classifies_connection_failures() {
+        use std::io::{Error as IoError, ErrorKind};
+
+        let tls = || rustls::Error::General("bad certificate".into());
+        let cases: Vec<(Box<dyn std::error::Error>, fn(&HttpClientError) -> bool)> = vec![
+            (
+                Box::new(Message("dns error: failed to lookup address")),
+                |e| matches!(e, HttpClientError::DnsFailure { .. }),
+            ),
+            (Box::new(IoError::from(ErrorKind::ConnectionRefused)), |e| {
+                matches!(e, HttpClientError::ConnectRefused { .. })
+            }),
+            (Box::new(IoError::from(ErrorKind::ConnectionReset)), |e| {
+                matches!(e, HttpClientError::ConnectRefused { .. })
+            }),
+            (Box::new(tls()), |e| {
+                matches!(e, HttpClientError::TlsError { .. })
+            }),
+            (Box::new(IoError::new(ErrorKind::InvalidData, tls())), |e| {
+                matches!(e, HttpClientError::TlsError { .. })
+            }),
+            (
+                Box::new(IoError::new(
+                    ErrorKind::Other,
+                    NonPublicName("internal.example.com".into()),
+                )),
+                |e| matches!(e, HttpClientError::PolicyDenied { reason } if reason.contains("internal.example.com")),
+            ),
+        ];
+
+        for (error, is_expected) in cases {
+            let classified = classify_error(error.as_ref());
+            assert!(
+                classified.as_ref().is_some_and(is_expected),
+                "{} classified as {:?}",
+                error,
+                classified
+            );
+        }
+
+        // Anything else is left for the caller to describe
+        for error in [
+            Box::new(IoError::from(ErrorKind::BrokenPipe)) as Box<dyn std::error::Error>,
+            Box::new(Message("connection closed before message completed")),
+        ] {
+            assert!(classify_error(error.as_ref()).is_none(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn it_rejects_header_injection() {
+        let headers = vec![("x-split".to_string(), b"a\r\nhost: evil".to_vec())];
+        let sent: Result<Request<Body>, _> = request(WasmHttpMethod::Get, headers).try_into();
+        assert!(matches!(sent, Err(HttpClientError::BadRequest)));
+    }
+
+    #[test]
+    fn it_sends_extension_methods() {
+        for method in ["PROPFIND", "REPORT", "PURGE", "GET"] {
+            let sent: Request<Body> = request(WasmHttpMethod::Other(method.to_string()), vec![])
+                .try_into()
+                .unwrap();
+            assert_eq!(sent.method().as_str(), method);
+        }
+
+        for method in ["", "BAD METHOD", "GET\r\n", "M(E)"] {
+            let sent: Result<Request<Body>, _> =
+                request(WasmHttpMethod::Other(method.to_string()), vec![]).try_into();
+            assert!(
+                matches!(sent, Err(HttpClientError::BadRequest)),
+                "{:?}",
+                method
+            );
+        }
+    }
+
+    fn local_client() -> HyperHostClient {
+        HyperHostClient {
+            client: new_shared_client(&HyperClientConfig {
+                allow_private_ips: true,
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn request_to(addr: SocketAddr, options: Options) -> ClientRequest {
+        ClientRequest {
+            uri: format!("http://{}/", addr),
+            options: Some(options),
+            ..request(WasmHttpMethod::Get, vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn it_times_out_connecting_to_a_listener_which_never_accepts() {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Once the accept queue is full further connection attempts go unanswered
+        let mut queued = vec![];
+        for _ in 0..16 {
+            let connecting = tokio::net::TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(100), connecting).await {
+                Ok(Ok(stream)) => queued.push(stream),
+                _ => break,
+            }
+        }
+
+        let options = Options {
+            timeout_ms: None,
+            connect_timeout_ms: Some(200),
+        };
+        let sent = local_client().call(request_to(addr, options)).await;
+
+        assert!(
+            matches!(
+                sent,
+                Err(HttpClientError::Timeout {
+                    phase: TimeoutPhase::Connect,
+                    limit_ms: 200
+                })
+            ),
+            "{:?}",
+            sent
+        );
+    }
+
+    #[tokio::test]
+    async fn it_times_out_waiting_on_a_server_which_never_responds() {
+        let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accepts connections and never answers on them
+        tokio::spawn(async move {
+            let mut held = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let options = Options {
+            timeout_ms: Some(200),
+            connect_timeout_ms: None,
+        };
+        let sent = local_client().call(request_to(addr, options)).await;
+
+        assert!(
+            matches!(
+                sent,
+                Err(HttpClientError::Timeout {
+                    phase: TimeoutPhase::Total,
+                    limit_ms: 200
+                })
+            ),
+            "{:?}",
+            sent
+        );
     }
 }
@@ -0,0 +1,674 @@
+//! Host clients which replay canned responses instead of reaching the network.
+//!
+//! Responses are read from a json fixture file, which can be written by hand or recorded
+//! from real traffic with an `HttpRecorder`. Requests without a matching
+//! interaction fail and are kept so tests can assert nothing unexpected was sent.
+//!
+//! Fixtures tend to be committed, so the values of `SENSITIVE_HEADERS` are never recorded
+//! or matched on.
+//!
+//! A fixture looks like
+//! ```json
+//! {
+//!   "interactions": [
+//!     {
+//!       "request": { "method": "GET", "uri": "https://example.com/" },
+//!       "response": { "ok": { "status": 200, "headers": [], "body": "hello" } }
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin};
+
+use serde::{Deserialize, Serialize};
+use tower::{service_fn, util::BoxService, Service, ServiceExt};
+
+use crate::http::{
+    is_sensitive_header, ClientError, ClientMakeError, ClientRequest, ClientResponse, ClientResult,
+    Headers, HostClient, HostClientMaker, HttpClientError, Method, Redacted, TimeoutError,
+    TimeoutPhase,
+};
+
+/// Recorded interactions, in the order they were made
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Fixture {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResult,
+}
+
+/// Requests match on method and uri. Headers and body only match when they're set
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<RecordedHeaders>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<RecordedBytes>,
+}
+
+impl RecordedRequest {
+    fn matches(&self, request: &RecordedRequest) -> bool {
+        let headers_match = match &self.headers {
+            Some(headers) => headers.iter().all(|header| request.has_header(header)),
+            None => true,
+        };
+        let body_match = match &self.body {
+            Some(body) => request.body.as_ref().map(|b| b.to_bytes()) == Some(body.to_bytes()),
+            None => true,
+        };
+
+        self.method.eq_ignore_ascii_case(&request.method)
+            && self.uri == request.uri
+            && headers_match
+            && body_match
+    }
+
+    fn has_header(&self, (name, value): &(String, RecordedBytes)) -> bool {
+        self.headers
+            .iter()
+            .flatten()
+            .any(|(n, v)| n.eq_ignore_ascii_case(name) && v.to_bytes() == value.to_bytes())
+    }
+}
+
+impl fmt::Debug for RecordedRequest {
+    /// Leaves out the values of sensitive headers, unexpected requests end up in test output
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let headers = self.headers.as_ref().map(replay_headers);
+        f.debug_struct("RecordedRequest")
+            .field("method", &self.method)
+            .field("uri", &self.uri)
+            .field("headers", &headers.as_ref().map(Redacted))
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl From<&ClientRequest> for RecordedRequest {
+    fn from(request: &ClientRequest) -> Self {
+        Self {
+            method: method_name(&request.method),
+            uri: request.uri.clone(),
+            headers: Some(record_headers(&request.headers)),
+            body: Some(RecordedBytes::from_bytes(request.body.clone())),
+        }
+    }
+}
+
+/// Text bodies and header values are stored as strings, anything else as a list of bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RecordedBytes {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl RecordedBytes {
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Self::Text(text),
+            Err(e) => Self::Bytes(e.into_bytes()),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Text(text) => text.as_bytes().to_vec(),
+            Self::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RecordedResult {
+    Ok {
+        status: u16,
+        #[serde(default)]
+        headers: RecordedHeaders,
+        #[serde(default = "empty_body")]
+        body: RecordedBytes,
+    },
+    Error(RecordedError),
+}
+
+impl fmt::Debug for RecordedResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok {
+                status,
+                headers,
+                body,
+            } => f
+                .debug_struct("Ok")
+                .field("status", status)
+                .field("headers", &Redacted(&replay_headers(headers)))
+                .field("body", body)
+                .finish(),
+            Self::Error(e) => f.debug_tuple("Error").field(e).finish(),
+        }
+    }
+}
+
+pub type RecordedHeaders = Vec<(String, RecordedBytes)>;
+
+/// Sensitive headers are left out
+fn record_headers(headers: &Headers) -> RecordedHeaders {
+    headers
+        .iter()
+        .filter(|(name, _)| !is_sensitive_header(name))
+        .map(|(name, value)| (name.clone(), RecordedBytes::from_bytes(value.clone())))
+        .collect()
+}
+
+fn replay_headers(headers: &RecordedHeaders) -> Headers {
+    headers
+        .iter()
+        .map(|(name, value)| (name.clone(), value.to_bytes()))
+        .collect()
+}
+
+fn empty_body() -> RecordedBytes {
+    RecordedBytes::Text(String::new())
+}
+
+/// Mirrors `ClientError`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "kind", content = "detail")]
+pub enum RecordedError {
+    DnsFailure(String),
+    ConnectRefused(String),
+    ConnectTimeout(u32),
+    Timeout(u32),
+    TlsError(String),
+    BodyTooLarge(u64),
+    PolicyDenied(String),
+    RateLimited,
+    BadRequest(String),
+    Other(String),
+}
+
+impl From<&ClientResult> for RecordedResult {
+    fn from(result: &ClientResult) -> Self {
+        match result {
+            ClientResult::Ok(response) => Self::Ok {
+                status: response.status,
+                headers: record_headers(&response.headers),
+                body: RecordedBytes::from_bytes(response.body.clone()),
+            },
+            ClientResult::Error(e) => Self::Error(match e.clone() {
+                ClientError::DnsFailure(e) => RecordedError::DnsFailure(e),
+                ClientError::ConnectRefused(e) => RecordedError::ConnectRefused(e),
+                ClientError::Timeout(TimeoutError {
+                    phase: TimeoutPhase::Connect,
+                    limit_ms,
+                }) => RecordedError::ConnectTimeout(limit_ms),
+                ClientError::Timeout(TimeoutError { limit_ms, .. }) => {
+                    RecordedError::Timeout(limit_ms)
+                }
+                ClientError::TlsError(e) => RecordedError::TlsError(e),
+                ClientError::BodyTooLarge(limit) => RecordedError::BodyTooLarge(limit),
+                ClientError::PolicyDenied(e) => RecordedError::PolicyDenied(e),
+                ClientError::RateLimited => RecordedError::RateLimited,
+                ClientError::BadRequest(e) => RecordedError::BadRequest(e),
+                ClientError::Other(e) => RecordedError::Other(e),
+            }),
+        }
+    }
+}
+
+impl From<&RecordedResult> for ClientResult {
+    fn from(result: &RecordedResult) -> Self {
+        match result.clone() {
+            RecordedResult::Ok {
+                status,
+                headers,
+                body,
+            } => ClientResult::Ok(ClientResponse {
+                status,
+                headers: replay_headers(&headers),
+                body: body.to_bytes(),
+            }),
+            RecordedResult::Error(e) => ClientResult::Error(match e {
+                RecordedError::DnsFailure(e) => ClientError::DnsFailure(e),
+                RecordedError::ConnectRefused(e) => ClientError::ConnectRefused(e),
+                RecordedError::ConnectTimeout(limit_ms) => ClientError::Timeout(TimeoutError {
+                    phase: TimeoutPhase::Connect,
+                    limit_ms,
+                }),
+                RecordedError::Timeout(limit_ms) => ClientError::Timeout(TimeoutError {
+                    phase: TimeoutPhase::Total,
+                    limit_ms,
+                }),
+                RecordedError::TlsError(e) => ClientError::TlsError(e),
+                RecordedError::BodyTooLarge(limit) => ClientError::BodyTooLarge(limit),
+                RecordedError::PolicyDenied(e) => ClientError::PolicyDenied(e),
+                RecordedError::RateLimited => ClientError::RateLimited,
+                RecordedError::BadRequest(e) => ClientError::BadRequest(e),
+                RecordedError::Other(e) => ClientError::Other(e),
+            }),
+        }
+    }
+}
+
+fn method_name(method: &Method) -> String {
+    match method {
+        Method::Get => "GET",
+        Method::Head => "HEAD",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        Method::Connect => "CONNECT",
+        Method::Options => "OPTIONS",
+        Method::Trace => "TRACE",
+        Method::Patch => "PATCH",
+        Method::Other(method) => method,
+    }
+    .to_string()
+}
+
+#[derive(Debug, Default)]
+struct ReplayState {
+    interactions: Vec<Interaction>,
+    used: Vec<bool>,
+    unexpected: Vec<RecordedRequest>,
+}
+
+impl ReplayState {
+    /// Prefers interactions which haven't been replayed yet, so a fixture can
+    /// hold a sequence of different responses for the same request
+    fn respond(&mut self, request: RecordedRequest) -> Result<ClientResult, HttpClientError> {
+        let matching = |(_, i): &(usize, &Interaction)| i.request.matches(&request);
+        let unused = self
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(matching)
+            .find(|(n, _)| !self.used[*n]);
+        let found = unused.or_else(|| self.interactions.iter().enumerate().rfind(matching));
+
+        match found {
+            Some((n, interaction)) => {
+                let result = ClientResult::from(&interaction.response);
+                self.used[n] = true;
+                Ok(result)
+            }
+            None => {
+                let cause = format!("unexpected request {} {}", request.method, request.uri);
+                self.unexpected.push(request);
+                Err(HttpClientError::ClientError { cause })
+            }
+        }
+    }
+}
+
+/// Serves responses from a `Fixture`. Clones share the same fixture and history
+#[derive(Debug, Clone, Default)]
+pub struct MockHttp {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl MockHttp {
+    pub fn new(fixture: Fixture) -> Self {
+        let used = vec![false; fixture.interactions.len()];
+        Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                interactions: fixture.interactions,
+                used,
+                unexpected: vec![],
+            })),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(Fixture::load(path)?))
+    }
+
+    /// Responds to `method` requests for `uri` with `response`
+    pub fn on(self, method: &str, uri: &str, response: RecordedResult) -> Self {
+        {
+            let mut state = self.state();
+            state.interactions.push(Interaction {
+                request: RecordedRequest {
+                    method: method.to_string(),
+                    uri: uri.to_string(),
+                    headers: None,
+                    body: None,
+                },
+                response,
+            });
+            state.used.push(false);
+        }
+        self
+    }
+
+    /// Produces clients replaying this fixture
+    pub fn client_maker(&self) -> HostClientMaker {
+        let mock = self.clone();
+        BoxService::new(service_fn(move |_: ()| {
+            let mock = mock.clone();
+            async move {
+                let client = service_fn(move |request: ClientRequest| {
+                    let result = mock.state().respond(RecordedRequest::from(&request));
+                    async move { result }
+                });
+                Ok::<_, ClientMakeError>(client.boxed())
+            }
+        }))
+    }
+
+    /// Requests which didn't match any interaction
+    pub fn unexpected_requests(&self) -> Vec<RecordedRequest> {
+        self.state().unexpected.clone()
+    }
+
+    /// Interactions which were never replayed
+    pub fn unused_interactions(&self) -> Vec<Interaction> {
+        let state = self.state();
+        state
+            .interactions
+            .iter()
+            .zip(state.used.iter())
+            .filter(|(_, used)| !**used)
+            .map(|(i, _)| i.clone())
+            .collect()
+    }
+
+    /// Panics if a request didn't match any interaction
+    pub fn assert_no_unexpected_requests(&self) {
+        let unexpected = self.unexpected_requests();
+        assert!(
+            unexpected.is_empty(),
+            "unexpected outbound requests {:#?}",
+            unexpected
+        );
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ReplayState> {
+        self.state.lock().expect("mock http lock poisoned")
+    }
+}
+
+/// Records interactions to a fixture at `path`, after any interactions it already holds.
+/// The fixture is rewritten after each response. Clones share the same fixture.
+///
+/// Requests are recorded by method, uri and body, see `with_headers` to record headers too
+#[derive(Debug, Clone)]
+pub struct HttpRecorder {
+    fixture: Arc<Mutex<Fixture>>,
+    /// Held while the fixture is written, so an older copy never replaces a newer one
+    saving: Arc<Mutex<()>>,
+    path: PathBuf,
+    record_headers: bool,
+}
+
+impl HttpRecorder {
+    /// Fails if `path` exists but isn't a fixture
+    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let fixture = if path.exists() {
+            Fixture::load(&path)?
+        } else {
+            Fixture::default()
+        };
+        Ok(Self {
+            fixture: Arc::new(Mutex::new(fixture)),
+            saving: Default::default(),
+            path,
+            record_headers: false,
+        })
+    }
+
+    /// Records request headers, so replayed requests only match when they're sent again
+    pub fn with_headers(mut self) -> Self {
+        self.record_headers = true;
+        self
+    }
+
+    /// Records every interaction of the clients produced by `client_maker`
+    pub fn apply(&self, client_maker: HostClientMaker) -> HostClientMaker {
+        let recorder = self.clone();
+        BoxService::new(client_maker.map_response(move |client| {
+            BoxService::new(RecordingClient {
+                inner: Arc::new(tokio::sync::Mutex::new(client)),
+                recorder: recorder.clone(),
+            })
+        }))
+    }
+
+    fn record(&self, mut request: RecordedRequest, result: &ClientResult) {
+        if !self.record_headers {
+            request.headers = None;
+        }
+        let mut fixture = self.fixture.lock().expect("fixture lock poisoned");
+        fixture.interactions.push(Interaction {
+            request,
+            response: RecordedResult::from(result),
+        });
+    }
+
+    /// Writes the fixture without blocking the async runtime
+    async fn save(&self) -> anyhow::Result<()> {
+        let recorder = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _saving = recorder.saving.lock().expect("fixture lock poisoned");
+            let fixture = recorder
+                .fixture
+                .lock()
+                .expect("fixture lock poisoned")
+                .clone();
+            fixture.save(&recorder.path)
+        })
+        .await?
+    }
+}
+
+#[derive(Clone)]
+struct RecordingClient {
+    inner: Arc<tokio::sync::Mutex<HostClient>>,
+    recorder: HttpRecorder,
+}
+
+impl Service<ClientRequest> for RecordingClient {
+    type Response = ClientResult;
+
+    type Error = HttpClientError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The inner client is readied when a request is made
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: ClientRequest) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let recorded = RecordedRequest::from(&request);
+            let response = {
+                let mut inner = this.inner.lock().await;
+                inner.ready().await?.call(request)
+            };
+            let result = response
+                .await
+                .unwrap_or_else(|e| ClientResult::Error(e.into()));
+
+            this.recorder.record(recorded, &result);
+            this.recorder
+                .save()
+                .await
+                .map_err(|e| HttpClientError::ClientError {
+                    cause: format!("failed to record interaction {:?}", e),
+                })?;
+
+            Ok(result)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(uri: &str) -> ClientRequest {
+        ClientRequest {
+            method: Method::Get,
+            headers: vec![],
+            body: vec![],
+            uri: uri.to_string(),
+            options: None,
+        }
+    }
+
+    async fn send(maker: &mut HostClientMaker, uri: &str) -> Result<ClientResult, HttpClientError> {
+        let mut client = maker.ready().await.unwrap().call(()).await.unwrap();
+        client.ready().await?.call(request(uri)).await
+    }
+
+    #[tokio::test]
+    async fn it_replays_responses_in_order() {
+        let ok = |body: &str| RecordedResult::Ok {
+            status: 200,
+            headers: vec![],
+            body: RecordedBytes::Text(body.to_string()),
+        };
+        let mock = MockHttp::default()
+            .on("GET", "https://example.com/", ok("first"))
+            .on("GET", "https://example.com/", ok("second"));
+        let mut maker = mock.client_maker();
+
+        for expected in ["first", "second", "second"] {
+            let result = send(&mut maker, "https://example.com/").await.unwrap();
+            assert!(matches!(result, ClientResult::Ok(r) if r.body == expected.as_bytes()));
+        }
+        assert!(mock.unused_interactions().is_empty());
+        mock.assert_no_unexpected_requests();
+    }
+
+    #[test]
+    fn it_redacts_sensitive_headers_from_output() {
+        let request = RecordedRequest {
+            method: "GET".into(),
+            uri: "https://example.com/".into(),
+            headers: Some(vec![(
+                "Authorization".into(),
+                RecordedBytes::Text("Bearer hunter2".into()),
+            )]),
+            body: None,
+        };
+        let response = RecordedResult::Ok {
+            status: 200,
+            headers: vec![("set-cookie".into(), RecordedBytes::Text("hunter3".into()))],
+            body: empty_body(),
+        };
+
+        let output = format!("{:#?} {:#?}", request, response);
+        assert!(!output.contains("hunter"));
+        assert!(output.contains("example.com"));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_unexpected_requests() {
+        let mock = MockHttp::default();
+        let mut maker = mock.client_maker();
+
+        assert!(send(&mut maker, "https://example.com/").await.is_err());
+        assert_eq!(mock.unexpected_requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_records_interactions_for_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.json");
+        let live = MockHttp::default().on(
+            "GET",
+            "https://example.com/",
+            RecordedResult::Error(RecordedError::ConnectTimeout(100)),
+        );
+        let mut recording = HttpRecorder::new(path.clone())
+            .unwrap()
+            .apply(live.client_maker());
+        send(&mut recording, "https://example.com/").await.unwrap();
+
+        let replay = MockHttp::from_file(&path).unwrap();
+        let result = send(&mut replay.client_maker(), "https://example.com/")
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            ClientResult::Error(ClientError::Timeout(TimeoutError {
+                phase: TimeoutPhase::Connect,
+                limit_ms: 100
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_appends_to_an_existing_fixture() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.json");
+        let ok = RecordedResult::Ok {
+            status: 200,
+            headers: vec![("set-cookie".into(), RecordedBytes::Text("session".into()))],
+            body: empty_body(),
+        };
+        let live = MockHttp::default()
+            .on("GET", "https://example.com/first", ok.clone())
+            .on("GET", "https://example.com/second", ok);
+
+        let mut first = HttpRecorder::new(path.clone())
+            .unwrap()
+            .apply(live.client_maker());
+        send(&mut first, "https://example.com/first").await.unwrap();
+
+        let mut second = HttpRecorder::new(path.clone())
+            .unwrap()
+            .with_headers()
+            .apply(live.client_maker());
+        let mut client = second.ready().await.unwrap().call(()).await.unwrap();
+        let mut request = request("https://example.com/second");
+        request.headers = vec![
+            ("authorization".into(), b"Bearer hunter2".to_vec()),
+            ("accept".into(), b"text/plain".to_vec()),
+        ];
+        client.ready().await.unwrap().call(request).await.unwrap();
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        let fixture = Fixture::load(&path).unwrap();
+
+        assert!(!raw.contains("hunter2"));
+        assert!(!raw.contains("session"));
+        let requests: Vec<_> = fixture.interactions.iter().map(|i| &i.request).collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers, None);
+        assert_eq!(
+            requests[1].headers,
+            Some(vec![(
+                "accept".to_string(),
+                RecordedBytes::Text("text/plain".into())
+            )])
+        );
+    }
+}
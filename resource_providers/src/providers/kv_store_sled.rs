@@ -0,0 +1,448 @@
+//! Provides a [sled](https://crates.io/crates/sled)-backed implementation of a host key value store.
+//! Data is kept in an embedded database on disk, so guests keep their state across
+//! invocations and restarts of the host. Every namespace is a separate sled tree,
+//! giving each component its own keys.
+
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::{future::Future, pin::Pin};
+
+use anyhow::{anyhow, bail};
+use tower::{service_fn, util::BoxService, Service};
+
+use crate::core::is_valid_storage_name;
+use crate::kv::{
+    HostKvStore, HostKvStoreMaker, Key, KvRequest, KvResponse, KvStoreError, StoreMakeError,
+};
+
+/// Longest key a guest may use, in bytes
+pub const MAX_KEY_BYTES: usize = 1024;
+
+/// Largest value a guest may store, in bytes
+pub const MAX_VALUE_BYTES: usize = 1024 * 1024;
+
+/// Most keys returned by a single `ListKeys`, guests page through the rest
+pub const MAX_LIST_KEYS: u32 = 1000;
+
+/// Bytes of keys and values a namespace may hold unless configured otherwise
+pub const DEFAULT_NAMESPACE_QUOTA_BYTES: u64 = 64 * 1024 * 1024;
+
+/// sled's own tree, opening it as a namespace would expose the database's metadata
+const SLED_DEFAULT_TREE: &str = "__sled__default";
+
+/// An open database shared by every namespace
+#[derive(Debug, Clone)]
+pub struct SledKv {
+    db: sled::Db,
+    namespace_quota_bytes: u64,
+    /// Bytes held by each opened namespace, shared by every maker for the same namespace
+    usage: Arc<Mutex<HashMap<String, Arc<Mutex<u64>>>>>,
+}
+
+impl SledKv {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(sled::open(path)?))
+    }
+
+    /// A database which is removed once dropped, for tests
+    pub fn temporary() -> anyhow::Result<Self> {
+        Ok(Self::new(sled::Config::new().temporary(true).open()?))
+    }
+
+    fn new(db: sled::Db) -> Self {
+        Self {
+            db,
+            namespace_quota_bytes: DEFAULT_NAMESPACE_QUOTA_BYTES,
+            usage: Default::default(),
+        }
+    }
+
+    /// Limits the bytes of keys and values each namespace may hold
+    pub fn with_namespace_quota(mut self, bytes: u64) -> Self {
+        self.namespace_quota_bytes = bytes;
+        self
+    }
+
+    /// Keys written in one namespace are never visible from another.
+    ///
+    /// `name` must match `[a-z0-9_-]+` and can't be sled's default tree
+    pub fn namespace(&self, name: &str) -> anyhow::Result<SledKvNamespace> {
+        if !is_valid_storage_name(name) || name == SLED_DEFAULT_TREE {
+            bail!("invalid key value namespace {:?}", name);
+        }
+        let tree = self.db.open_tree(name)?;
+
+        let mut usage = self
+            .usage
+            .lock()
+            .map_err(|_| anyhow!("namespace usage lock poisoned"))?;
+        let usage = match usage.get(name) {
+            Some(used) => used.clone(),
+            None => {
+                let mut used = 0;
+                for entry in tree.iter() {
+                    let (key, value) = entry?;
+                    used += entry_bytes(key.len(), value.len());
+                }
+                let used = Arc::new(Mutex::new(used));
+                usage.insert(name.to_string(), used.clone());
+                used
+            }
+        };
+
+        Ok(SledKvNamespace {
+            tree,
+            quota_bytes: self.namespace_quota_bytes,
+            usage,
+        })
+    }
+}
+
+/// The keys belonging to a single component
+#[derive(Debug, Clone)]
+pub struct SledKvNamespace {
+    tree: sled::Tree,
+    quota_bytes: u64,
+    /// Bytes of keys and values in the tree. Held while writing so the quota can't be raced past
+    usage: Arc<Mutex<u64>>,
+}
+
+impl SledKvNamespace {
+    /// Every store opened by the returned maker shares this namespace
+    pub fn store_maker(&self) -> HostKvStoreMaker {
+        let namespace = self.clone();
+        let maker = service_fn(move |_: ()| {
+            let namespace = namespace.clone();
+            async move { Ok::<HostKvStore, StoreMakeError>(BoxService::new(SledKvStore { namespace })) }
+        });
+        BoxService::new(maker)
+    }
+}
+
+/// A guest's view of a namespace
+pub struct SledKvStore {
+    namespace: SledKvNamespace,
+}
+
+impl Service<KvRequest> for SledKvStore {
+    type Response = KvResponse;
+    type Error = KvStoreError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: KvRequest) -> Self::Future {
+        let namespace = self.namespace.clone();
+        // sled may block on disk io
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || handle(&namespace, req))
+                .await
+                .map_err(|e| KvStoreError::Storage {
+                    cause: e.to_string(),
+                })?
+        })
+    }
+}
+
+fn handle(namespace: &SledKvNamespace, req: KvRequest) -> Result<KvResponse, KvStoreError> {
+    let tree = &namespace.tree;
+    match req {
+        KvRequest::Get { key } => {
+            check_key(&key)?;
+            let value = tree.get(key)?.map(|value| value.to_vec());
+            Ok(KvResponse::Value(value))
+        }
+        KvRequest::Set { key, value } => {
+            check_key(&key)?;
+            check_value(&value)?;
+            let mut used = lock_usage(namespace)?;
+            let current = tree.get(&key)?;
+            *used = namespace.reserve(*used, &key, current.as_deref(), &value)?;
+            tree.insert(key, value)?;
+            Ok(KvResponse::Set)
+        }
+        KvRequest::Delete { key } => {
+            check_key(&key)?;
+            let mut used = lock_usage(namespace)?;
+            let removed = tree.remove(&key)?;
+            if let Some(value) = &removed {
+                *used = used.saturating_sub(entry_bytes(key.len(), value.len()));
+            }
+            Ok(KvResponse::Deleted(removed.is_some()))
+        }
+        KvRequest::ListKeys {
+            prefix,
+            start_after,
+            limit,
+        } => {
+            let start = match start_after {
+                Some(after) if after >= prefix => Bound::Excluded(after.into_bytes()),
+                _ => Bound::Included(prefix.clone().into_bytes()),
+            };
+            let keys = tree
+                .range::<Vec<u8>, _>((start, Bound::Unbounded))
+                .keys()
+                .take_while(|key| {
+                    key.as_ref()
+                        .map_or(true, |key| key.starts_with(prefix.as_bytes()))
+                })
+                .take(limit.min(MAX_LIST_KEYS) as usize)
+                .map(|key| Ok(String::from_utf8_lossy(&key?).to_string()))
+                .collect::<Result<Vec<_>, KvStoreError>>()?;
+            Ok(KvResponse::Keys(keys))
+        }
+        KvRequest::CompareAndSwap { key, expected, new } => {
+            check_key(&key)?;
+            check_value(&new)?;
+            let mut used = lock_usage(namespace)?;
+            let current = tree.get(&key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(KvResponse::Swapped(false));
+            }
+            let reserved = namespace.reserve(*used, &key, current.as_deref(), &new)?;
+            let swapped = tree.compare_and_swap(key, expected, Some(new))?.is_ok();
+            if swapped {
+                *used = reserved;
+            }
+            Ok(KvResponse::Swapped(swapped))
+        }
+    }
+}
+
+impl SledKvNamespace {
+    /// Usage after replacing `current` with `new` at `key`, failing if it would grow past the quota
+    fn reserve(
+        &self,
+        used: u64,
+        key: &Key,
+        current: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<u64, KvStoreError> {
+        let freed = current.map_or(0, |current| entry_bytes(key.len(), current.len()));
+        let needed = entry_bytes(key.len(), new.len());
+        let after = used.saturating_sub(freed) + needed;
+        if needed > freed && after > self.quota_bytes {
+            return Err(KvStoreError::QuotaExceeded {
+                limit: self.quota_bytes,
+            });
+        }
+        Ok(after)
+    }
+}
+
+fn lock_usage(namespace: &SledKvNamespace) -> Result<std::sync::MutexGuard<'_, u64>, KvStoreError> {
+    namespace.usage.lock().map_err(|_| KvStoreError::Storage {
+        cause: "namespace usage lock poisoned".to_string(),
+    })
+}
+
+fn entry_bytes(key_len: usize, value_len: usize) -> u64 {
+    (key_len + value_len) as u64
+}
+
+fn check_key(key: &str) -> Result<(), KvStoreError> {
+    if key.is_empty() {
+        return Err(KvStoreError::InvalidKey {
+            reason: "keys may not be empty".to_string(),
+        });
+    }
+    if key.len() > MAX_KEY_BYTES {
+        return Err(KvStoreError::InvalidKey {
+            reason: format!("keys may be at most {} bytes", MAX_KEY_BYTES),
+        });
+    }
+    Ok(())
+}
+
+fn check_value(value: &[u8]) -> Result<(), KvStoreError> {
+    if value.len() > MAX_VALUE_BYTES {
+        return Err(KvStoreError::ValueTooLarge {
+            limit: MAX_VALUE_BYTES,
+        });
+    }
+    Ok(())
+}
+
+impl From<sled::Error> for KvStoreError {
+    fn from(e: sled::Error) -> Self {
+        KvStoreError::Storage {
+            cause: e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn open(kv: &SledKv, namespace: &str) -> HostKvStore {
+        let mut maker = kv.namespace(namespace).unwrap().store_maker();
+        maker.ready().await.unwrap().call(()).await.unwrap()
+    }
+
+    async fn send(store: &mut HostKvStore, req: KvRequest) -> Result<KvResponse, KvStoreError> {
+        store.ready().await?.call(req).await
+    }
+
+    fn set(key: &str, value: &[u8]) -> KvRequest {
+        KvRequest::Set {
+            key: key.to_string(),
+            value: value.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_stores_values_per_namespace() {
+        let kv = SledKv::temporary().unwrap();
+        let mut store = open(&kv, "counter").await;
+        let mut other = open(&kv, "other").await;
+
+        send(&mut store, set("users/1", b"ada")).await.unwrap();
+        send(&mut store, set("users/2", b"grace")).await.unwrap();
+        send(&mut store, set("visits", b"2")).await.unwrap();
+
+        let get = KvRequest::Get {
+            key: "users/1".to_string(),
+        };
+        let value = send(&mut store, get.clone()).await.unwrap();
+        assert_eq!(value, KvResponse::Value(Some(b"ada".to_vec())));
+        let value = send(&mut other, get).await.unwrap();
+        assert_eq!(value, KvResponse::Value(None));
+
+        let list = KvRequest::ListKeys {
+            prefix: "users/".to_string(),
+            start_after: None,
+            limit: MAX_LIST_KEYS,
+        };
+        let keys = send(&mut store, list).await.unwrap();
+        assert_eq!(
+            keys,
+            KvResponse::Keys(vec!["users/1".to_string(), "users/2".to_string()])
+        );
+
+        let delete = KvRequest::Delete {
+            key: "visits".to_string(),
+        };
+        let deleted = send(&mut store, delete.clone()).await.unwrap();
+        assert_eq!(deleted, KvResponse::Deleted(true));
+        let deleted = send(&mut store, delete).await.unwrap();
+        assert_eq!(deleted, KvResponse::Deleted(false));
+    }
+
+    #[tokio::test]
+    async fn it_compares_and_swaps() {
+        let kv = SledKv::temporary().unwrap();
+        let mut store = open(&kv, "counter").await;
+
+        let swap = |expected: Option<&[u8]>, new: &[u8]| KvRequest::CompareAndSwap {
+            key: "visits".to_string(),
+            expected: expected.map(|v| v.to_vec()),
+            new: new.to_vec(),
+        };
+
+        let swapped = send(&mut store, swap(None, b"1")).await.unwrap();
+        assert_eq!(swapped, KvResponse::Swapped(true));
+        // Absent is no longer true
+        let swapped = send(&mut store, swap(None, b"1")).await.unwrap();
+        assert_eq!(swapped, KvResponse::Swapped(false));
+        let swapped = send(&mut store, swap(Some(b"0"), b"2")).await.unwrap();
+        assert_eq!(swapped, KvResponse::Swapped(false));
+        let swapped = send(&mut store, swap(Some(b"1"), b"2")).await.unwrap();
+        assert_eq!(swapped, KvResponse::Swapped(true));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_invalid_keys_and_values() {
+        let kv = SledKv::temporary().unwrap();
+        let mut store = open(&kv, "counter").await;
+
+        let result = send(&mut store, set("", b"1")).await;
+        assert!(matches!(result, Err(KvStoreError::InvalidKey { .. })));
+
+        let long_key = "k".repeat(MAX_KEY_BYTES + 1);
+        let result = send(&mut store, set(&long_key, b"1")).await;
+        assert!(matches!(result, Err(KvStoreError::InvalidKey { .. })));
+
+        let large_value = vec![0; MAX_VALUE_BYTES + 1];
+        let result = send(&mut store, set("visits", &large_value)).await;
+        assert!(matches!(result, Err(KvStoreError::ValueTooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn it_pages_through_keys() {
+        let kv = SledKv::temporary().unwrap();
+        let mut store = open(&kv, "counter").await;
+        for key in ["a", "users/1", "users/2", "users/3", "z"] {
+            send(&mut store, set(key, b"1")).await.unwrap();
+        }
+
+        let list = |start_after: Option<&str>| KvRequest::ListKeys {
+            prefix: "users/".to_string(),
+            start_after: start_after.map(|key| key.to_string()),
+            limit: 2,
+        };
+        let keys = send(&mut store, list(None)).await.unwrap();
+        assert_eq!(
+            keys,
+            KvResponse::Keys(vec!["users/1".to_string(), "users/2".to_string()])
+        );
+        let keys = send(&mut store, list(Some("users/2"))).await.unwrap();
+        assert_eq!(keys, KvResponse::Keys(vec!["users/3".to_string()]));
+        let keys = send(&mut store, list(Some("users/3"))).await.unwrap();
+        assert_eq!(keys, KvResponse::Keys(vec![]));
+        // A cursor before the prefix starts at the prefix
+        let keys = send(&mut store, list(Some("a"))).await.unwrap();
+        assert_eq!(
+            keys,
+            KvResponse::Keys(vec!["users/1".to_string(), "users/2".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn it_enforces_the_namespace_quota() {
+        let kv = SledKv::temporary().unwrap().with_namespace_quota(10);
+        let mut store = open(&kv, "counter").await;
+        let mut other = open(&kv, "other").await;
+
+        // 1 byte key and 9 byte value fills the namespace
+        send(&mut store, set("a", b"123456789")).await.unwrap();
+        let result = send(&mut store, set("b", b"1")).await;
+        assert!(matches!(result, Err(KvStoreError::QuotaExceeded { .. })));
+        // Other namespaces have their own quota
+        send(&mut other, set("b", b"1")).await.unwrap();
+
+        // Shrinking or replacing values is allowed when full
+        send(&mut store, set("a", b"12345")).await.unwrap();
+        send(&mut store, set("b", b"123")).await.unwrap();
+
+        // Deleting frees space, also for stores opened later
+        let delete = KvRequest::Delete {
+            key: "a".to_string(),
+        };
+        send(&mut store, delete).await.unwrap();
+        let mut reopened = open(&kv, "counter").await;
+        send(&mut reopened, set("c", b"12345")).await.unwrap();
+        let swap = KvRequest::CompareAndSwap {
+            key: "c".to_string(),
+            expected: Some(b"12345".to_vec()),
+            new: b"123456".to_vec(),
+        };
+        let result = send(&mut reopened, swap).await;
+        assert!(matches!(result, Err(KvStoreError::QuotaExceeded { .. })));
+    }
+
+    #[test]
+    fn it_rejects_invalid_namespaces() {
+        let kv = SledKv::temporary().unwrap();
+        for name in ["", "../counter", "Counter", SLED_DEFAULT_TREE] {
+            assert!(kv.namespace(name).is_err(), "{}", name);
+        }
+    }
+}
@@ -1,5 +1,20 @@
 //! Mycelia created resource providers
 //! These should be good default implentations
 
+/// object storage resource provider backed by the local filesystem
+pub mod blob_store_fs;
+
 /// http client resource provider backed by hyper
 pub mod http_client_hyper;
+
+/// http client resource provider replaying recorded responses
+pub mod http_client_mock;
+
+/// key value store resource provider backed by sled
+pub mod kv_store_sled;
+
+/// secrets provider backed by a passphrase encrypted file
+pub mod secrets_file;
+
+/// sql database resource provider backed by sqlite
+pub mod sql_sqlite;
@@ -0,0 +1,313 @@
+//! Provides secrets from a local file encrypted with a passphrase.
+//! Each secret is sealed with AES-256-GCM using a key derived from the passphrase with PBKDF2,
+//! and the secret's name as associated data so values can't be swapped between names.
+//! The file is read whenever a secret is resolved, so secrets can be changed without a restart.
+//!
+//! The file is json holding the salt, a check value used to detect a wrong passphrase
+//! and the sealed secrets. Only names are stored in the clear.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use tower::{service_fn, util::BoxService};
+
+use crate::secrets::{HostSecretProvider, Secret, SecretProviderError};
+
+/// Environment variable the development tools read the passphrase from
+pub const SECRETS_KEY_ENV: &str = "MYCELIA_SECRETS_KEY";
+
+/// PBKDF2 rounds used for new files
+const PBKDF2_ITERATIONS: u32 = 600_000;
+
+const SALT_LEN: usize = 16;
+
+/// Associated data of the check value, no secret can be named this
+const CHECK_NAME: &str = "\0mycelia-secrets-check";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Contents {
+    salt: String,
+    iterations: u32,
+    check: Sealed,
+    secrets: BTreeMap<String, Sealed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// An encrypted secrets file, unlocked with its passphrase
+#[derive(Clone)]
+pub struct SecretsFile {
+    path: PathBuf,
+    key: Arc<LessSafeKey>,
+}
+
+impl fmt::Debug for SecretsFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretsFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecretsFile {
+    /// Unlocks the file at `path`, failing if `passphrase` isn't the one it was created with
+    pub fn open(path: &Path, passphrase: &str) -> anyhow::Result<Self> {
+        let contents = read_contents(path)?;
+        let key = derive_key(passphrase, &decode(&contents.salt)?, contents.iterations)?;
+        open_sealed(&key, CHECK_NAME, &contents.check)
+            .map_err(|_| anyhow::anyhow!("wrong passphrase for {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            key: Arc::new(key),
+        })
+    }
+
+    /// Opens the file at `path`, creating an empty one locked with `passphrase` if it doesn't exist
+    pub fn open_or_create(path: &Path, passphrase: &str) -> anyhow::Result<Self> {
+        if path.exists() {
+            return Self::open(path, passphrase);
+        }
+        let mut salt = [0; SALT_LEN];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| anyhow::anyhow!("failed to generate a salt"))?;
+        let key = derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+        let contents = Contents {
+            salt: STANDARD.encode(salt),
+            iterations: PBKDF2_ITERATIONS,
+            check: seal(&key, CHECK_NAME, b"")?,
+            secrets: BTreeMap::new(),
+        };
+        write_contents(path, &contents)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            key: Arc::new(key),
+        })
+    }
+
+    /// Names of every secret in the file
+    pub fn names(&self) -> anyhow::Result<Vec<String>> {
+        Ok(read_contents(&self.path)?.secrets.into_keys().collect())
+    }
+
+    pub fn get(&self, name: &str) -> Result<Secret, SecretProviderError> {
+        let contents = read_contents(&self.path).map_err(storage)?;
+        let sealed = contents
+            .secrets
+            .get(name)
+            .ok_or_else(|| SecretProviderError::NotFound {
+                name: name.to_string(),
+            })?;
+        let value = open_sealed(&self.key, name, sealed).map_err(storage)?;
+        let value = String::from_utf8(value).map_err(storage)?;
+        Ok(Secret::new(value))
+    }
+
+    /// Adds or replaces the secret `name`
+    pub fn set(&self, name: &str, value: &Secret) -> anyhow::Result<()> {
+        if name.is_empty() || name.starts_with('\0') {
+            anyhow::bail!("invalid secret name {:?}", name);
+        }
+        let mut contents = read_contents(&self.path)?;
+        let sealed = seal(&self.key, name, value.expose().as_bytes())?;
+        contents.secrets.insert(name.to_string(), sealed);
+        write_contents(&self.path, &contents)
+    }
+
+    /// Returns whether the secret existed
+    pub fn remove(&self, name: &str) -> anyhow::Result<bool> {
+        let mut contents = read_contents(&self.path)?;
+        let existed = contents.secrets.remove(name).is_some();
+        write_contents(&self.path, &contents)?;
+        Ok(existed)
+    }
+
+    /// Every secret resolved by the returned provider is read from this file
+    pub fn provider(&self) -> HostSecretProvider {
+        let file = self.clone();
+        let provider = service_fn(move |name: String| {
+            let file = file.clone();
+            async move {
+                tokio::task::spawn_blocking(move || file.get(&name))
+                    .await
+                    .map_err(storage)?
+            }
+        });
+        BoxService::new(provider)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> anyhow::Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations)
+        .ok_or_else(|| anyhow::anyhow!("secrets file has an invalid iteration count"))?;
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let key = UnboundKey::new(&AES_256_GCM, &key)
+        .map_err(|_| anyhow::anyhow!("failed to create the secrets key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn seal(key: &LessSafeKey, name: &str, value: &[u8]) -> anyhow::Result<Sealed> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("failed to generate a nonce"))?;
+    let mut in_out = value.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(name.as_bytes()),
+        &mut in_out,
+    )
+    .map_err(|_| anyhow::anyhow!("failed to encrypt secret {}", name))?;
+    Ok(Sealed {
+        nonce: STANDARD.encode(nonce),
+        ciphertext: STANDARD.encode(in_out),
+    })
+}
+
+fn open_sealed(key: &LessSafeKey, name: &str, sealed: &Sealed) -> anyhow::Result<Vec<u8>> {
+    let nonce = Nonce::try_assume_unique_for_key(&decode(&sealed.nonce)?)
+        .map_err(|_| anyhow::anyhow!("secret {} has an invalid nonce", name))?;
+    let mut in_out = decode(&sealed.ciphertext)?;
+    let value = key
+        .open_in_place(nonce, Aad::from(name.as_bytes()), &mut in_out)
+        .map_err(|_| anyhow::anyhow!("failed to decrypt secret {}", name))?;
+    Ok(value.to_vec())
+}
+
+fn decode(value: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(STANDARD.decode(value)?)
+}
+
+fn read_contents(path: &Path) -> anyhow::Result<Contents> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+/// Writes to a temporary file first so a failed write can't lose every secret.
+/// The file is only readable by its owner, names are stored in the clear
+fn write_contents(path: &Path, contents: &Contents) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let temp = path.with_extension("tmp");
+    // A leftover temporary file could have been created by anyone, never write through it
+    let _ = std::fs::remove_file(&temp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temp)?;
+    file.write_all(&serde_json::to_vec_pretty(contents)?)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn storage(e: impl ToString) -> SecretProviderError {
+    SecretProviderError::Storage {
+        cause: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tower::{Service, ServiceExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn it_resolves_secrets_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        let file = SecretsFile::open_or_create(&path, "correct horse").unwrap();
+        file.set("api_key", &Secret::new("hunter2".to_string()))
+            .unwrap();
+
+        // Only the names are stored in the clear
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(raw.contains("api_key"));
+        assert!(!raw.contains("hunter2"));
+
+        let mut provider = SecretsFile::open(&path, "correct horse")
+            .unwrap()
+            .provider();
+        let secret = provider
+            .ready()
+            .await
+            .unwrap()
+            .call("api_key".to_string())
+            .await
+            .unwrap();
+        assert_eq!(secret.expose(), "hunter2");
+
+        assert!(file.remove("api_key").unwrap());
+        let result = provider
+            .ready()
+            .await
+            .unwrap()
+            .call("api_key".to_string())
+            .await;
+        assert!(matches!(result, Err(SecretProviderError::NotFound { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_keeps_the_file_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        let file = SecretsFile::open_or_create(&path, "correct horse").unwrap();
+        file.set("api_key", &Secret::new("hunter2".to_string()))
+            .unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn it_rejects_a_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        SecretsFile::open_or_create(&path, "correct horse").unwrap();
+        assert!(SecretsFile::open(&path, "battery staple").is_err());
+    }
+
+    #[test]
+    fn it_binds_values_to_their_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        let file = SecretsFile::open_or_create(&path, "correct horse").unwrap();
+        file.set("a", &Secret::new("first".to_string())).unwrap();
+        file.set("b", &Secret::new("second".to_string())).unwrap();
+
+        // Moving a sealed value to another name doesn't decrypt
+        let mut contents = read_contents(&path).unwrap();
+        let a = contents.secrets.remove("a").unwrap();
+        contents.secrets.insert("b".to_string(), a);
+        write_contents(&path, &contents).unwrap();
+
+        let result = file.get("b");
+        assert!(matches!(result, Err(SecretProviderError::Storage { .. })));
+    }
+}
@@ -0,0 +1,497 @@
+//! Provides a [SQLite](https://crates.io/crates/rusqlite)-backed implementation of a host sql database.
+//! Every component gets its own database file, so components can't read each other's tables.
+//! Each guest connection is a separate SQLite connection, giving it its own transactions.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{future::Future, pin::Pin};
+
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::limits::Limit;
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, ErrorCode};
+use tower::{service_fn, util::BoxService, Service};
+
+use crate::core::is_valid_storage_name;
+use crate::sql::{
+    ConnectionMakeError, DatabaseError, ExecuteResult, HostSqlConnection, HostSqlConnectionMaker,
+    QueryResult, SqlRequest, SqlResponse, SqlValue,
+};
+
+/// Most rows a single query may return to a guest
+pub const MAX_QUERY_ROWS: usize = 10_000;
+
+/// How long a statement waits for another connection's lock before failing as busy
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Virtual machine instructions between checks for a dropped request
+const CANCEL_CHECK_INSTRUCTIONS: i32 = 1000;
+
+/// A directory holding one database file per component
+#[derive(Debug, Clone)]
+pub struct SqliteDatabases {
+    dir: PathBuf,
+}
+
+impl SqliteDatabases {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The database belonging to the component `name`, created on first use.
+    /// `name` must match `[a-z0-9_-]+`
+    pub fn database(&self, name: &str) -> anyhow::Result<SqliteDatabase> {
+        if !is_valid_storage_name(name) {
+            anyhow::bail!("invalid database name {:?}", name);
+        }
+        std::fs::create_dir_all(&self.dir)?;
+        Ok(SqliteDatabase::new(
+            self.dir.join(format!("{}.sqlite3", name)),
+        ))
+    }
+}
+
+/// A single database file
+#[derive(Debug, Clone)]
+pub struct SqliteDatabase {
+    path: PathBuf,
+}
+
+impl SqliteDatabase {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Every connection opened by the returned maker is to this database
+    pub fn connection_maker(&self) -> HostSqlConnectionMaker {
+        let path = self.path.clone();
+        let maker = service_fn(move |_: ()| {
+            let path = path.clone();
+            async move {
+                let connection = tokio::task::spawn_blocking(move || SqliteConnection::open(&path))
+                    .await
+                    .map_err(|e| ConnectionMakeError::Storage {
+                        cause: e.to_string(),
+                    })??;
+                Ok::<HostSqlConnection, ConnectionMakeError>(BoxService::new(connection))
+            }
+        });
+        BoxService::new(maker)
+    }
+}
+
+/// A guest's connection to its database
+pub struct SqliteConnection {
+    conn: Arc<Mutex<rusqlite::Connection>>,
+    /// Cancellation flag of the request currently running on the connection
+    running: Arc<Mutex<Arc<AtomicBool>>>,
+}
+
+impl SqliteConnection {
+    fn open(path: &Path) -> Result<Self, ConnectionMakeError> {
+        let running: Arc<Mutex<Arc<AtomicBool>>> = Default::default();
+        let open = || {
+            let conn = rusqlite::Connection::open(path)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update(None, "foreign_keys", true)?;
+            // Attaching would let a guest open any file the host can, including other
+            // components' databases. VACUUM attaches its target too, so it's refused as well
+            conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+            conn.authorizer(Some(authorize));
+            // Aborts the running statement once its request is dropped, e.g. the guest timed out
+            let cancelled = running.clone();
+            conn.progress_handler(
+                CANCEL_CHECK_INSTRUCTIONS,
+                Some(move || {
+                    cancelled
+                        .lock()
+                        .map_or(true, |running| running.load(Ordering::Relaxed))
+                }),
+            );
+            Ok::<_, rusqlite::Error>(conn)
+        };
+        let conn = open().map_err(|e| ConnectionMakeError::Storage {
+            cause: e.to_string(),
+        })?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            running,
+        })
+    }
+}
+
+fn authorize(context: AuthContext<'_>) -> Authorization {
+    match context.action {
+        AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+        _ => Authorization::Allow,
+    }
+}
+
+/// Cancels the request it was created for when dropped.
+/// Harmless once the request completed, the next request brings its own flag
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Service<SqlRequest> for SqliteConnection {
+    type Response = SqlResponse;
+    type Error = DatabaseError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: SqlRequest) -> Self::Future {
+        let conn = self.conn.clone();
+        let running = self.running.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancel = CancelOnDrop(cancelled.clone());
+        // sqlite blocks on disk io and on other connections' locks
+        Box::pin(async move {
+            let _cancel = cancel;
+            tokio::task::spawn_blocking(move || {
+                let poisoned = || DatabaseError::Storage {
+                    cause: "connection poisoned by an earlier panic".to_string(),
+                };
+                let conn = conn.lock().map_err(|_| poisoned())?;
+                *running.lock().map_err(|_| poisoned())? = cancelled.clone();
+                // Dropped while waiting for the connection
+                if cancelled.load(Ordering::Relaxed) {
+                    return Err(DatabaseError::Storage {
+                        cause: "request was cancelled".to_string(),
+                    });
+                }
+                handle(&conn, req)
+            })
+            .await
+            .map_err(|e| DatabaseError::Storage {
+                cause: e.to_string(),
+            })?
+        })
+    }
+}
+
+fn handle(conn: &rusqlite::Connection, req: SqlRequest) -> Result<SqlResponse, DatabaseError> {
+    match req {
+        SqlRequest::Prepare { sql } => {
+            conn.prepare_cached(&sql).map_err(syntax_error)?;
+            Ok(SqlResponse::Done)
+        }
+        SqlRequest::Query { sql, params } => {
+            let mut statement = conn.prepare_cached(&sql).map_err(syntax_error)?;
+            let columns: Vec<String> = statement
+                .column_names()
+                .into_iter()
+                .map(String::from)
+                .collect();
+
+            let mut rows = statement.query(params_from_iter(params.into_iter().map(to_value)))?;
+            let mut result = vec![];
+            while let Some(row) = rows.next()? {
+                if result.len() == MAX_QUERY_ROWS {
+                    return Err(DatabaseError::TooManyRows {
+                        limit: MAX_QUERY_ROWS,
+                    });
+                }
+                let values = (0..columns.len())
+                    .map(|i| row.get::<_, Value>(i).map(from_value))
+                    .collect::<Result<Vec<_>, _>>()?;
+                result.push(values);
+            }
+
+            Ok(SqlResponse::Rows(QueryResult {
+                columns,
+                rows: result,
+            }))
+        }
+        SqlRequest::Execute { sql, params } => {
+            let mut statement = conn.prepare_cached(&sql).map_err(syntax_error)?;
+            let rows_affected =
+                statement.execute(params_from_iter(params.into_iter().map(to_value)))?;
+            Ok(SqlResponse::Executed(ExecuteResult {
+                rows_affected: rows_affected as u64,
+                last_insert_id: conn.last_insert_rowid(),
+            }))
+        }
+        SqlRequest::ExecuteBatch { sql } => {
+            conn.execute_batch(&sql)?;
+            Ok(SqlResponse::Done)
+        }
+        SqlRequest::Begin => {
+            conn.execute_batch("BEGIN")?;
+            Ok(SqlResponse::Done)
+        }
+        SqlRequest::Commit => {
+            conn.execute_batch("COMMIT")?;
+            Ok(SqlResponse::Done)
+        }
+        SqlRequest::Rollback => {
+            conn.execute_batch("ROLLBACK")?;
+            Ok(SqlResponse::Done)
+        }
+    }
+}
+
+fn to_value(value: SqlValue) -> Value {
+    match value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(v) => Value::Integer(v),
+        SqlValue::Real(v) => Value::Real(v),
+        SqlValue::Text(v) => Value::Text(v),
+        SqlValue::Blob(v) => Value::Blob(v),
+    }
+}
+
+fn from_value(value: Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Integer(v) => SqlValue::Integer(v),
+        Value::Real(v) => SqlValue::Real(v),
+        Value::Text(v) => SqlValue::Text(v),
+        Value::Blob(v) => SqlValue::Blob(v),
+    }
+}
+
+/// Failing to prepare is almost always a problem with the sql itself
+fn syntax_error(e: rusqlite::Error) -> DatabaseError {
+    match DatabaseError::from(e) {
+        DatabaseError::Storage { cause } => DatabaseError::Syntax { cause },
+        e => e,
+    }
+}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(e: rusqlite::Error) -> Self {
+        let cause = e.to_string();
+        match e {
+            rusqlite::Error::SqliteFailure(failure, _) => match failure.code {
+                ErrorCode::ConstraintViolation => DatabaseError::Constraint { cause },
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => DatabaseError::Busy,
+                ErrorCode::ApiMisuse | ErrorCode::AuthorizationForStatementDenied => {
+                    DatabaseError::Misuse { cause }
+                }
+                _ => DatabaseError::Storage { cause },
+            },
+            rusqlite::Error::InvalidParameterCount(..)
+            | rusqlite::Error::ExecuteReturnedResults
+            | rusqlite::Error::MultipleStatement
+            | rusqlite::Error::InvalidColumnType(..) => DatabaseError::Misuse { cause },
+            _ => DatabaseError::Storage { cause },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Connects to the database of a component kept in `dir`
+    async fn connect(dir: &Path) -> HostSqlConnection {
+        let database = SqliteDatabases::new(dir.to_path_buf())
+            .database("component")
+            .unwrap();
+        let mut maker = database.connection_maker();
+        maker.ready().await.unwrap().call(()).await.unwrap()
+    }
+
+    async fn send(
+        conn: &mut HostSqlConnection,
+        req: SqlRequest,
+    ) -> Result<SqlResponse, DatabaseError> {
+        conn.ready().await?.call(req).await
+    }
+
+    fn execute(sql: &str, params: Vec<SqlValue>) -> SqlRequest {
+        SqlRequest::Execute {
+            sql: sql.to_string(),
+            params,
+        }
+    }
+
+    fn query(sql: &str) -> SqlRequest {
+        SqlRequest::Query {
+            sql: sql.to_string(),
+            params: vec![],
+        }
+    }
+
+    fn batch(sql: &str) -> SqlRequest {
+        SqlRequest::ExecuteBatch {
+            sql: sql.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_binds_parameters_and_returns_typed_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = connect(dir.path()).await;
+
+        send(
+            &mut conn,
+            batch("create table notes (id integer primary key, body text, score real, raw blob)"),
+        )
+        .await
+        .unwrap();
+
+        let insert = "insert into notes (body, score, raw) values (?, ?, ?)";
+        let params = vec![
+            SqlValue::Text("hello".to_string()),
+            SqlValue::Real(1.5),
+            SqlValue::Blob(vec![0, 255]),
+        ];
+        let result = send(&mut conn, execute(insert, params)).await.unwrap();
+        assert!(matches!(
+            result,
+            SqlResponse::Executed(ExecuteResult {
+                rows_affected: 1,
+                last_insert_id: 1
+            })
+        ));
+        let result = send(&mut conn, execute(insert, vec![SqlValue::Null; 3]))
+            .await
+            .unwrap();
+        assert!(matches!(result, SqlResponse::Executed(_)));
+
+        let SqlResponse::Rows(rows) = send(&mut conn, query("select * from notes order by id"))
+            .await
+            .unwrap()
+        else {
+            panic!("expected rows");
+        };
+        assert_eq!(rows.columns, vec!["id", "body", "score", "raw"]);
+        assert!(matches!(
+            &rows.rows[0][..],
+            [
+                SqlValue::Integer(1),
+                SqlValue::Text(body),
+                SqlValue::Real(score),
+                SqlValue::Blob(raw)
+            ] if body == "hello" && *score == 1.5 && raw[..] == [0, 255]
+        ));
+        assert!(matches!(
+            &rows.rows[1][..],
+            [
+                SqlValue::Integer(2),
+                SqlValue::Null,
+                SqlValue::Null,
+                SqlValue::Null
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_rolls_back_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = connect(dir.path()).await;
+        let mut other = connect(dir.path()).await;
+
+        send(&mut conn, batch("create table visits (n integer)"))
+            .await
+            .unwrap();
+        send(&mut conn, SqlRequest::Begin).await.unwrap();
+        send(&mut conn, execute("insert into visits values (1)", vec![]))
+            .await
+            .unwrap();
+        send(&mut conn, SqlRequest::Rollback).await.unwrap();
+
+        let SqlResponse::Rows(rows) = send(&mut other, query("select n from visits"))
+            .await
+            .unwrap()
+        else {
+            panic!("expected rows");
+        };
+        assert!(rows.rows.is_empty());
+
+        // Dropping a connection mid transaction rolls it back too
+        send(&mut conn, SqlRequest::Begin).await.unwrap();
+        send(&mut conn, execute("insert into visits values (2)", vec![]))
+            .await
+            .unwrap();
+        drop(conn);
+        let SqlResponse::Rows(rows) = send(&mut other, query("select n from visits"))
+            .await
+            .unwrap()
+        else {
+            panic!("expected rows");
+        };
+        assert!(rows.rows.is_empty());
+    }
+
+    #[tokio::test]
+    async fn it_classifies_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = connect(dir.path()).await;
+
+        let result = send(
+            &mut conn,
+            SqlRequest::Prepare {
+                sql: "selec 1".to_string(),
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(DatabaseError::Syntax { .. })));
+
+        send(&mut conn, batch("create table users (email text unique)"))
+            .await
+            .unwrap();
+        let insert = "insert into users values (?)";
+        let email = || vec![SqlValue::Text("ada@example.com".to_string())];
+        send(&mut conn, execute(insert, email())).await.unwrap();
+        let result = send(&mut conn, execute(insert, email())).await;
+        assert!(matches!(result, Err(DatabaseError::Constraint { .. })));
+
+        let result = send(&mut conn, execute(insert, vec![])).await;
+        assert!(matches!(result, Err(DatabaseError::Misuse { .. })));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_attaching_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = connect(dir.path()).await;
+        let other = dir.path().join("other.sqlite3");
+
+        let attach = format!("attach database '{}' as other", other.display());
+        let result = send(&mut conn, batch(&attach)).await;
+        assert!(matches!(result, Err(DatabaseError::Misuse { .. })));
+        let result = send(&mut conn, execute(&attach, vec![])).await;
+        assert!(matches!(result, Err(DatabaseError::Misuse { .. })));
+
+        let vacuum = format!("vacuum into '{}'", other.display());
+        assert!(send(&mut conn, batch(&vacuum)).await.is_err());
+        let result = send(&mut conn, batch("detach database main")).await;
+        assert!(matches!(result, Err(DatabaseError::Misuse { .. })));
+
+        assert!(!other.exists());
+    }
+
+    #[tokio::test]
+    async fn it_interrupts_dropped_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = connect(dir.path()).await;
+
+        let endless = query(
+            "with recursive counter(n) as (select 1 union all select n + 1 from counter) \
+             select count(*) from counter",
+        );
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), send(&mut conn, endless)).await;
+        assert!(result.is_err());
+
+        // The connection is only free again once the endless query was interrupted
+        let result =
+            tokio::time::timeout(Duration::from_secs(5), send(&mut conn, query("select 1")))
+                .await
+                .expect("the dropped query kept running");
+        assert!(matches!(result, Ok(SqlResponse::Rows(_))));
+    }
+}
@@ -0,0 +1,178 @@
+//! Host side implementation for providing wasm guests secrets.
+//! Secrets are kept apart from plain configuration: each is resolved by a Tower service
+//! only when a guest asks for it, and only if it was granted to the guest's component.
+//!
+//! # Usage
+//! Link the interface with `add_to_linker` and give every store a `HostSecrets`
+//! with a provider, see `providers::secrets_file` for the default encrypted file,
+//! and the names granted to the component being run.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tower::{service_fn, util::BoxService, Service, ServiceExt};
+use wasmtime::component::Linker;
+
+use self::bindgen::Command;
+
+pub use self::bindgen::mycelia_alpha::secrets::types::SecretsError;
+
+mod bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../guest_crates/mycelia_secrets/wit",
+      world: "command",
+      async: true
+    });
+}
+
+/// A secret value.
+/// Its `Debug` output is redacted so a secret can't end up in logs by accident
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_exposed(self) -> String {
+        self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret([redacted])")
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when the host resolves a secret.
+/// These never include the secret's value
+pub enum SecretProviderError {
+    #[error("no secret named {name}")]
+    NotFound { name: String },
+    #[error("secret {name} wasn't granted to this component")]
+    NotGranted { name: String },
+    #[error("no secrets are available to this guest")]
+    Unavailable,
+    #[error("failed to read secrets - {cause}")]
+    Storage { cause: String },
+}
+
+impl From<SecretProviderError> for SecretsError {
+    /// Lowers host failures into the error variant returned to guests
+    fn from(e: SecretProviderError) -> Self {
+        match e {
+            SecretProviderError::NotFound { name } => SecretsError::NotFound(name),
+            SecretProviderError::NotGranted { name } => SecretsError::NotGranted(name),
+            SecretProviderError::Unavailable | SecretProviderError::Storage { .. } => {
+                SecretsError::Unavailable(e.to_string())
+            }
+        }
+    }
+}
+
+/// Abstract service type resolving secrets by name,
+/// for example see `providers::secrets_file::SecretsFile`
+pub type HostSecretProvider = BoxService<String, Secret, SecretProviderError>;
+
+/// A provider for guests which aren't given secrets
+pub fn unavailable_secret_provider() -> HostSecretProvider {
+    BoxService::new(service_fn(|_: String| async {
+        Err::<Secret, _>(SecretProviderError::Unavailable)
+    }))
+}
+
+/// A guest's view of its secrets
+pub struct HostSecrets {
+    pub provider: HostSecretProvider,
+    /// Names of the secrets the guest may read, checked before the provider is asked
+    pub granted: Arc<BTreeSet<String>>,
+}
+
+impl HostSecrets {
+    pub fn new(provider: HostSecretProvider, granted: Arc<BTreeSet<String>>) -> Self {
+        Self { provider, granted }
+    }
+}
+
+impl Default for HostSecrets {
+    fn default() -> Self {
+        Self::new(unavailable_secret_provider(), Default::default())
+    }
+}
+
+#[async_trait]
+impl bindgen::mycelia_alpha::secrets::interfaces::Host for HostSecrets {
+    async fn get(&mut self, name: String) -> anyhow::Result<Result<String, SecretsError>> {
+        if !self.granted.contains(&name) {
+            return Ok(Err(SecretProviderError::NotGranted { name }.into()));
+        }
+        let secret = match self.provider.ready().await {
+            Ok(provider) => provider.call(name).await,
+            Err(e) => Err(e),
+        };
+        Ok(secret.map(Secret::into_exposed).map_err(Into::into))
+    }
+}
+
+impl bindgen::mycelia_alpha::secrets::types::Host for HostSecrets {}
+
+/// Implemented by store data which provides guests secrets
+pub trait HostSecretsMaker {
+    fn secrets(&mut self) -> &mut HostSecrets;
+}
+
+/// Tells the linker how to provide guests their secrets
+pub fn add_to_linker<T: HostSecretsMaker + Send>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostSecrets>(linker, |v| v.secrets())
+}
+
+#[cfg(test)]
+mod test {
+    use super::bindgen::mycelia_alpha::secrets::interfaces::Host;
+    use super::*;
+
+    /// Resolves every secret to its name reversed
+    fn reversing_provider() -> HostSecretProvider {
+        BoxService::new(service_fn(|name: String| async move {
+            Ok::<_, SecretProviderError>(Secret::new(name.chars().rev().collect()))
+        }))
+    }
+
+    #[tokio::test]
+    async fn it_only_resolves_granted_secrets() {
+        let granted = Arc::new(["api_key".to_string()].into_iter().collect());
+        let mut secrets = HostSecrets::new(reversing_provider(), granted);
+
+        let value = secrets.get("api_key".into()).await.unwrap().unwrap();
+        assert_eq!(value, "yek_ipa");
+
+        let result = secrets.get("db_password".into()).await.unwrap();
+        assert!(matches!(result, Err(SecretsError::NotGranted(name)) if name == "db_password"));
+
+        let mut secrets = HostSecrets {
+            granted: secrets.granted.clone(),
+            ..Default::default()
+        };
+        let result = secrets.get("api_key".into()).await.unwrap();
+        assert!(matches!(result, Err(SecretsError::Unavailable(_))));
+    }
+
+    #[test]
+    fn it_redacts_secrets_in_debug_output() {
+        let secret = Secret::new("hunter2".to_string());
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+        assert!(!format!("{:?}", Some(&secret)).contains("hunter2"));
+    }
+}
@@ -0,0 +1,408 @@
+//! Host side implementations for providing wasm guests a sql database.
+//! Guests open a `connection` resource, prepare `statement`s on it and run them with
+//! bound parameters. Each operation is a request to a Tower service, one service per connection.
+//!
+//! # Usage
+//! Link the interface with `add_to_linker` and give every store a `HostSqlResource`
+//! whose maker connects to the database of the component being run,
+//! see `providers::sql_sqlite` for the default SQLite database.
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tower::util::BoxService;
+use wasmtime::component::{Linker, Resource};
+
+use crate::core::{
+    unavailable_maker, HostResourceIdProvider, HostResourceMaker, ResourceHost, ResourceIdPool,
+    ResourceTable, ResourceTableError,
+};
+
+use self::bindgen::mycelia_alpha::sql::interfaces::{
+    Connection, HostConnection, HostStatement, Statement,
+};
+use self::bindgen::Command;
+
+pub use self::bindgen::mycelia_alpha::sql::types::{
+    ExecuteResult, QueryResult, Row, SqlError, SqlValue,
+};
+
+mod bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../guest_crates/mycelia_sql/wit",
+      world: "command",
+      async: true
+    });
+}
+
+/// An operation a guest performs on a connection
+#[derive(Debug, Clone)]
+pub enum SqlRequest {
+    /// Checks `sql` is a single valid statement
+    Prepare {
+        sql: String,
+    },
+    Query {
+        sql: String,
+        params: Vec<SqlValue>,
+    },
+    Execute {
+        sql: String,
+        params: Vec<SqlValue>,
+    },
+    ExecuteBatch {
+        sql: String,
+    },
+    Begin,
+    Commit,
+    Rollback,
+}
+
+/// The result of a `SqlRequest`
+#[derive(Debug, Clone)]
+pub enum SqlResponse {
+    Rows(QueryResult),
+    Executed(ExecuteResult),
+    Done,
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when connecting a guest to its database
+pub enum ConnectionMakeError {
+    #[error("no database is available to this guest")]
+    Unavailable,
+    #[error("failed to open the database - {cause}")]
+    Storage { cause: String },
+}
+
+#[derive(Error, Debug)]
+/// Errors which might occur when the host runs sql on behalf of a guest
+pub enum DatabaseError {
+    #[error("connection isn't ready. wait and try again.")]
+    NotReady,
+    #[error("invalid sql - {cause}")]
+    Syntax { cause: String },
+    #[error("constraint failed - {cause}")]
+    Constraint { cause: String },
+    #[error("database is locked. wait and try again.")]
+    Busy,
+    #[error("misuse - {cause}")]
+    Misuse { cause: String },
+    #[error("query returned more than {limit} rows")]
+    TooManyRows { limit: usize },
+    #[error("storage failure - {cause}")]
+    Storage { cause: String },
+    /// The connection answered with a response for a different kind of request
+    #[error("connection produced an unexpected response")]
+    UnexpectedResponse,
+    #[error("guest tried to use a connection or statement which does not exist")]
+    HostResourceNotFound,
+}
+
+impl From<ResourceTableError> for DatabaseError {
+    fn from(_: ResourceTableError) -> Self {
+        DatabaseError::HostResourceNotFound
+    }
+}
+
+impl From<DatabaseError> for SqlError {
+    fn from(e: DatabaseError) -> Self {
+        match e {
+            DatabaseError::NotReady | DatabaseError::Busy => SqlError::Busy,
+            DatabaseError::Syntax { cause } => SqlError::Syntax(cause),
+            DatabaseError::Constraint { cause } => SqlError::Constraint(cause),
+            DatabaseError::Misuse { cause } => SqlError::Misuse(cause),
+            DatabaseError::TooManyRows { .. } => SqlError::Misuse(e.to_string()),
+            DatabaseError::Storage { cause } => SqlError::Other(cause),
+            DatabaseError::UnexpectedResponse | DatabaseError::HostResourceNotFound => {
+                SqlError::Other(e.to_string())
+            }
+        }
+    }
+}
+
+/// Abstract service type defining a database connection,
+/// for example see `providers::sql_sqlite::SqliteConnection`
+pub type HostSqlConnection = BoxService<SqlRequest, SqlResponse, DatabaseError>;
+
+/// Abstract service type for a thing which opens new HostSqlConnections
+pub type HostSqlConnectionMaker = BoxService<(), HostSqlConnection, ConnectionMakeError>;
+
+/// A maker for guests which aren't given a database. Connecting traps the guest
+pub fn unavailable_connection_maker() -> HostSqlConnectionMaker {
+    unavailable_maker(|| ConnectionMakeError::Unavailable)
+}
+
+/// Host state behind a guest `statement` resource
+pub struct PreparedStatement {
+    /// The connection the statement was prepared on
+    pub connection: u32,
+    pub sql: String,
+}
+
+/// The connections a guest opened and the statements prepared on them, see `ResourceHost`.
+/// Statements draw their ids from the same pool as connections
+pub struct HostSqlResource {
+    pub connections: ResourceHost<HostSqlConnectionMaker, HostSqlConnection>,
+    pub statements: ResourceTable<PreparedStatement>,
+}
+
+impl HostSqlResource {
+    pub fn new(
+        connection_maker: HostSqlConnectionMaker,
+        resource_id_provider: HostResourceIdProvider,
+    ) -> Self {
+        Self {
+            connections: ResourceHost::new(connection_maker, resource_id_provider),
+            statements: Default::default(),
+        }
+    }
+
+    /// Creates a resource which draws its ids from `id_pool`, capping the connections
+    /// and statements a guest may hold open
+    pub fn with_id_pool(connection_maker: HostSqlConnectionMaker, id_pool: ResourceIdPool) -> Self {
+        Self {
+            connections: ResourceHost::with_id_pool(connection_maker, id_pool),
+            statements: Default::default(),
+        }
+    }
+
+    /// Sends a request expecting `SqlResponse::Done`
+    async fn call_done(&mut self, id: u32, req: SqlRequest) -> Result<(), DatabaseError> {
+        match self.connections.call(id, req).await? {
+            SqlResponse::Done => Ok(()),
+            _ => Err(DatabaseError::UnexpectedResponse),
+        }
+    }
+
+    /// Sends a request built from the sql behind statement `id` to its connection
+    async fn call_statement(
+        &mut self,
+        id: u32,
+        req: impl FnOnce(String) -> SqlRequest,
+    ) -> Result<SqlResponse, DatabaseError> {
+        let statement = self.statements.get(id)?;
+        let connection = statement.connection;
+        let req = req(statement.sql.clone());
+        self.connections.call(connection, req).await
+    }
+}
+
+#[async_trait]
+impl HostConnection for HostSqlResource {
+    /// Connects to the guest's database and returns the resource to the guest.
+    /// Failing to connect traps the guest instance
+    async fn new(&mut self) -> anyhow::Result<Resource<Connection>> {
+        Ok(Resource::new_own(self.connections.open(()).await?))
+    }
+
+    async fn prepare(
+        &mut self,
+        guest_self: Resource<Connection>,
+        sql: String,
+    ) -> anyhow::Result<Result<Resource<Statement>, SqlError>> {
+        let connection = guest_self.rep();
+        let req = SqlRequest::Prepare { sql: sql.clone() };
+        if let Err(e) = self.call_done(connection, req).await {
+            return Ok(Err(e.into()));
+        }
+
+        let Ok(statement_id) = self.connections.next_id().await else {
+            return Ok(Err(DatabaseError::NotReady.into()));
+        };
+        // A duplicate id is indicative of a bug in the upstream id provider
+        self.statements
+            .insert(statement_id, PreparedStatement { connection, sql })?;
+
+        Ok(Ok(Resource::new_own(statement_id)))
+    }
+
+    async fn execute_batch(
+        &mut self,
+        guest_self: Resource<Connection>,
+        sql: String,
+    ) -> anyhow::Result<Result<(), SqlError>> {
+        let req = SqlRequest::ExecuteBatch { sql };
+        Ok(self
+            .call_done(guest_self.rep(), req)
+            .await
+            .map_err(Into::into))
+    }
+
+    async fn begin(
+        &mut self,
+        guest_self: Resource<Connection>,
+    ) -> anyhow::Result<Result<(), SqlError>> {
+        let req = SqlRequest::Begin;
+        Ok(self
+            .call_done(guest_self.rep(), req)
+            .await
+            .map_err(Into::into))
+    }
+
+    async fn commit(
+        &mut self,
+        guest_self: Resource<Connection>,
+    ) -> anyhow::Result<Result<(), SqlError>> {
+        let req = SqlRequest::Commit;
+        Ok(self
+            .call_done(guest_self.rep(), req)
+            .await
+            .map_err(Into::into))
+    }
+
+    async fn rollback(
+        &mut self,
+        guest_self: Resource<Connection>,
+    ) -> anyhow::Result<Result<(), SqlError>> {
+        let req = SqlRequest::Rollback;
+        Ok(self
+            .call_done(guest_self.rep(), req)
+            .await
+            .map_err(Into::into))
+    }
+
+    /// Called when a connection is released by a guest. Statements prepared on it stop working.
+    /// Releasing a connection which does not exist traps the guest instance
+    fn drop(&mut self, val: Resource<Connection>) -> anyhow::Result<()> {
+        self.connections.remove(val.rep())?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HostStatement for HostSqlResource {
+    async fn query(
+        &mut self,
+        guest_self: Resource<Statement>,
+        params: Vec<SqlValue>,
+    ) -> anyhow::Result<Result<QueryResult, SqlError>> {
+        let req = |sql| SqlRequest::Query { sql, params };
+        Ok(match self.call_statement(guest_self.rep(), req).await {
+            Ok(SqlResponse::Rows(rows)) => Ok(rows),
+            Ok(_) => Err(DatabaseError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    async fn execute(
+        &mut self,
+        guest_self: Resource<Statement>,
+        params: Vec<SqlValue>,
+    ) -> anyhow::Result<Result<ExecuteResult, SqlError>> {
+        let req = |sql| SqlRequest::Execute { sql, params };
+        Ok(match self.call_statement(guest_self.rep(), req).await {
+            Ok(SqlResponse::Executed(result)) => Ok(result),
+            Ok(_) => Err(DatabaseError::UnexpectedResponse.into()),
+            Err(e) => Err(e.into()),
+        })
+    }
+
+    /// Called when a statement is released by a guest
+    fn drop(&mut self, val: Resource<Statement>) -> anyhow::Result<()> {
+        self.statements.remove(val.rep())?;
+        self.connections.release_id(val.rep());
+        Ok(())
+    }
+}
+
+impl bindgen::mycelia_alpha::sql::types::Host for HostSqlResource {}
+impl bindgen::mycelia_alpha::sql::interfaces::Host for HostSqlResource {}
+
+/// Tells the linker how to provide guests access to their database
+pub fn add_to_linker<T: HostResourceMaker<HostSqlResource> + Send>(
+    linker: &mut Linker<T>,
+) -> anyhow::Result<()> {
+    Command::add_to_linker::<T, HostSqlResource>(linker, |v| v.host_resource())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tower::service_fn;
+
+    use super::*;
+
+    /// Records the requests sent to every connection it opens, answering queries with one row
+    fn recording_connection_maker(sent: Arc<Mutex<Vec<SqlRequest>>>) -> HostSqlConnectionMaker {
+        BoxService::new(service_fn(move |_: ()| {
+            let sent = sent.clone();
+            async move {
+                let connection = service_fn(move |req: SqlRequest| {
+                    sent.lock().unwrap().push(req.clone());
+                    let response = match req {
+                        SqlRequest::Prepare { sql } if sql.is_empty() => {
+                            Err(DatabaseError::Syntax {
+                                cause: "empty statement".to_string(),
+                            })
+                        }
+                        SqlRequest::Query { params, .. } => Ok(SqlResponse::Rows(QueryResult {
+                            columns: vec!["param".to_string()],
+                            rows: vec![params],
+                        })),
+                        _ => Ok(SqlResponse::Done),
+                    };
+                    async move { response }
+                });
+                Ok::<HostSqlConnection, ConnectionMakeError>(BoxService::new(connection))
+            }
+        }))
+    }
+
+    #[tokio::test]
+    async fn it_runs_statements_on_their_connection() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut resource = HostSqlResource::with_id_pool(
+            recording_connection_maker(sent.clone()),
+            ResourceIdPool::new(4),
+        );
+
+        let connection = HostConnection::new(&mut resource).await.unwrap();
+        let connection_id = connection.rep();
+        let statement = resource
+            .prepare(connection, "select ?".into())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let rows = resource
+            .query(statement, vec![SqlValue::Integer(7)])
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(rows.rows[..], [ref row] if matches!(row[..], [SqlValue::Integer(7)])));
+
+        let result = resource
+            .prepare(Resource::new_own(connection_id), "".into())
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(SqlError::Syntax(_))));
+        assert_eq!(sent.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn it_rejects_statements_after_their_connection_is_dropped() {
+        let id_pool = ResourceIdPool::new(4);
+        let sent = Arc::new(Mutex::new(vec![]));
+        let mut resource =
+            HostSqlResource::with_id_pool(recording_connection_maker(sent), id_pool.clone());
+
+        let connection = HostConnection::new(&mut resource).await.unwrap();
+        let connection_id = connection.rep();
+        let statement = resource
+            .prepare(connection, "select 1".into())
+            .await
+            .unwrap()
+            .unwrap();
+        let statement_id = statement.rep();
+
+        HostConnection::drop(&mut resource, Resource::new_own(connection_id)).unwrap();
+        let result = resource.execute(statement, vec![]).await.unwrap();
+        assert!(matches!(result, Err(SqlError::Other(_))));
+
+        HostStatement::drop(&mut resource, Resource::new_own(statement_id)).unwrap();
+        assert_eq!(id_pool.live(), 0);
+    }
+}
@@ -0,0 +1,449 @@
+//! Host side implementation of `wasi:http/outgoing-handler`.
+//!
+//! Guests built with standard wasi-http tooling make outbound calls through the same
+//! `HostClientMaker` stack as guests using the `mycelia-alpha:http` client resource,
+//! so both are subject to the same policies.
+//!
+//! # Notes
+//! - Request bodies are buffered before being handed to the `HostClient`, matching `ClientRequest`.
+//! Bodies over the store's limit fail the request rather than being buffered.
+//! - A single `HostClient` is made per store on first use and shared by every request it sends.
+//! - wasi-http only has a handful of error codes. Each `ClientError` maps to the closest one,
+//! with the specific failure kept in its message.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use tokio::sync::Mutex;
+use tower::{Service, ServiceExt};
+use wasmtime::component::Resource;
+use wasmtime_wasi::preview2::Table;
+use wasmtime_wasi_http::bindings::http::types::Error as WasiHttpError;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{
+    HostFutureIncomingResponse, IncomingResponseInternal, OutgoingRequest,
+};
+
+use crate::http::{
+    map_headers, ClientError, ClientRequest, ClientResponse, ClientResult, HostClient,
+    HostClientMaker, Method, Options,
+};
+
+/// Sends wasi-http outgoing requests on behalf of a single guest store
+pub struct OutgoingHttp {
+    client_maker: Arc<Mutex<HostClientMaker>>,
+    client: Arc<Mutex<Option<HostClient>>>,
+    max_body_bytes: usize,
+}
+
+impl OutgoingHttp {
+    /// Requests are sent with clients from `client_maker`.
+    /// Request bodies over `max_body_bytes` fail the request
+    pub fn new(client_maker: HostClientMaker, max_body_bytes: usize) -> Self {
+        Self {
+            client_maker: Arc::new(Mutex::new(client_maker)),
+            client: Default::default(),
+            max_body_bytes,
+        }
+    }
+
+    /// Sends `request` with the host client. The response is delivered through the
+    /// returned `future-incoming-response` resource
+    pub fn send_request(
+        &self,
+        table: &mut Table,
+        request: OutgoingRequest,
+    ) -> wasmtime::Result<Resource<HostFutureIncomingResponse>> {
+        let client_maker = self.client_maker.clone();
+        let client = self.client.clone();
+        let max_body_bytes = self.max_body_bytes;
+
+        let handle = wasmtime_wasi::preview2::spawn(async move {
+            Ok(send(client_maker, client, request, max_body_bytes).await)
+        });
+
+        Ok(table.push_resource(HostFutureIncomingResponse::new(handle))?)
+    }
+}
+
+async fn send(
+    client_maker: Arc<Mutex<HostClientMaker>>,
+    client: Arc<Mutex<Option<HostClient>>>,
+    request: OutgoingRequest,
+    max_body_bytes: usize,
+) -> Result<IncomingResponseInternal, WasiHttpError> {
+    let between_bytes_timeout = request.between_bytes_timeout;
+    let request = map_request(request, max_body_bytes).await?;
+
+    let response = send_with_client(client_maker, client, request).await?;
+
+    Ok(IncomingResponseInternal {
+        resp: map_response(response)?,
+        worker: Arc::new(wasmtime_wasi::preview2::spawn(async {})),
+        between_bytes_timeout,
+    })
+}
+
+/// Sends `request` with the store's client, making it first if this is the first request
+async fn send_with_client(
+    client_maker: Arc<Mutex<HostClientMaker>>,
+    client: Arc<Mutex<Option<HostClient>>>,
+    request: ClientRequest,
+) -> Result<ClientResponse, WasiHttpError> {
+    let response = {
+        let mut client = client.lock().await;
+        if client.is_none() {
+            let mut client_maker = client_maker.lock().await;
+            let new_client = client_maker
+                .ready()
+                .await
+                .map_err(|e| WasiHttpError::UnexpectedError(e.to_string()))?
+                .call(())
+                .await
+                .map_err(|e| WasiHttpError::UnexpectedError(e.to_string()))?;
+            *client = Some(new_client);
+        }
+
+        let Some(client) = client.as_mut() else {
+            return Err(WasiHttpError::UnexpectedError(
+                "host client unavailable".to_string(),
+            ));
+        };
+
+        // Only wait for the client to be ready while holding the lock,
+        // the request itself runs concurrently with any others.
+        client
+            .ready()
+            .await
+            .map_err(|e| map_error(e.into()))?
+            .call(request)
+    };
+
+    let response = response.await.map_err(|e| map_error(e.into()))?;
+
+    match response {
+        ClientResult::Ok(response) => Ok(response),
+        ClientResult::Error(e) => Err(map_error(e)),
+    }
+}
+
+/// Requests which couldn't be sent as given are invalid, failures reaching or talking to
+/// the remote host are protocol errors and requests the host refused are unexpected
+fn map_error(error: ClientError) -> WasiHttpError {
+    match error {
+        ClientError::Timeout(e) => WasiHttpError::TimeoutError(format!(
+            "request timed out during {:?} after {}ms",
+            e.phase, e.limit_ms
+        )),
+        ClientError::BadRequest(cause) => WasiHttpError::InvalidUrl(cause),
+        ClientError::DnsFailure(cause) => {
+            WasiHttpError::ProtocolError(format!("dns lookup failed - {}", cause))
+        }
+        ClientError::ConnectRefused(cause) => {
+            WasiHttpError::ProtocolError(format!("connection refused - {}", cause))
+        }
+        ClientError::TlsError(cause) => {
+            WasiHttpError::ProtocolError(format!("tls failure - {}", cause))
+        }
+        ClientError::BodyTooLarge(limit) => WasiHttpError::ProtocolError(format!(
+            "response body exceeded the limit of {} bytes",
+            limit
+        )),
+        ClientError::PolicyDenied(reason) => {
+            WasiHttpError::UnexpectedError(format!("request denied by policy - {}", reason))
+        }
+        ClientError::RateLimited => {
+            WasiHttpError::UnexpectedError("too many requests. wait and try again.".to_string())
+        }
+        ClientError::Other(cause) => WasiHttpError::UnexpectedError(cause),
+    }
+}
+
+async fn map_request(
+    request: OutgoingRequest,
+    max_body_bytes: usize,
+) -> Result<ClientRequest, WasiHttpError> {
+    let scheme = if request.use_tls { "https" } else { "http" };
+    // The total timeout is left to the host default, wasi-http only bounds individual phases
+    let options = Options {
+        timeout_ms: None,
+        connect_timeout_ms: request.connect_timeout.as_millis().try_into().ok(),
+    };
+    let (parts, body) = request.request.into_parts();
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let uri = format!("{}://{}{}", scheme, request.authority, path);
+
+    let headers = map_headers(&parts.headers);
+
+    let body = read_body(body, max_body_bytes).await?;
+
+    Ok(ClientRequest {
+        method: map_method(&parts.method),
+        headers,
+        body,
+        uri,
+        options: Some(options),
+    })
+}
+
+/// Buffers the guest's request body, failing once it's over `limit` bytes
+async fn read_body(mut body: HyperOutgoingBody, limit: usize) -> Result<Vec<u8>, WasiHttpError> {
+    let mut buffered = vec![];
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| WasiHttpError::UnexpectedError(format!("{:?}", e)))?;
+        // Trailers aren't supported yet
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+        if buffered.len() + data.len() > limit {
+            return Err(WasiHttpError::ProtocolError(format!(
+                "request body exceeded the limit of {} bytes",
+                limit
+            )));
+        }
+        buffered.extend_from_slice(&data);
+    }
+    Ok(buffered)
+}
+
+fn map_method(method: &http::Method) -> Method {
+    match method.as_str() {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "POST" => Method::Post,
+        "PUT" => Method::Put,
+        "DELETE" => Method::Delete,
+        "CONNECT" => Method::Connect,
+        "OPTIONS" => Method::Options,
+        "TRACE" => Method::Trace,
+        "PATCH" => Method::Patch,
+        other => Method::Other(other.to_string()),
+    }
+}
+
+fn map_response(
+    response: ClientResponse,
+) -> Result<http::Response<wasmtime_wasi_http::body::HyperIncomingBody>, WasiHttpError> {
+    let mut builder = http::Response::builder().status(response.status);
+    for (k, v) in response.headers {
+        builder = builder.header(k, v);
+    }
+
+    let body = Full::new(Bytes::from(response.body))
+        .map_err(|never| match never {})
+        .boxed();
+
+    builder
+        .body(body)
+        .map_err(|e| WasiHttpError::ProtocolError(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use tokio::sync::Mutex;
+    use tower::{service_fn, util::BoxService};
+    use wasmtime_wasi_http::bindings::http::types::Error as WasiHttpError;
+    use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+    use super::{read_body, send_with_client};
+    use crate::http::{
+        with_egress_policy, ClientMakeError, ClientRequest, ClientResponse, ClientResult,
+        EgressPolicy, HostClient, HostClientMaker, HostPattern, HttpClientError, Method,
+        TimeoutPhase,
+    };
+
+    /// Makes clients which answer every request with `answer`
+    fn client_maker(
+        answer: impl Fn() -> Result<ClientResult, HttpClientError> + Clone + Send + 'static,
+    ) -> HostClientMaker {
+        BoxService::new(service_fn(move |_: ()| {
+            let answer = answer.clone();
+            async move {
+                let client: HostClient = BoxService::new(service_fn(move |_: ClientRequest| {
+                    let answer = answer();
+                    async move { answer }
+                }));
+                Ok::<_, ClientMakeError>(client)
+            }
+        }))
+    }
+
+    fn request(uri: &str) -> ClientRequest {
+        ClientRequest {
+            method: Method::Get,
+            headers: vec![],
+            body: vec![],
+            uri: uri.to_string(),
+            options: None,
+        }
+    }
+
+    async fn send(
+        client_maker: HostClientMaker,
+        request: ClientRequest,
+    ) -> Result<ClientResponse, WasiHttpError> {
+        send_with_client(
+            Arc::new(Mutex::new(client_maker)),
+            Default::default(),
+            request,
+        )
+        .await
+    }
+
+    fn body(bytes: &[u8]) -> HyperOutgoingBody {
+        Full::new(Bytes::from(bytes.to_vec()))
+            .map_err(|never| match never {})
+            .boxed()
+    }
+
+    #[tokio::test]
+    async fn it_sends_requests_with_the_host_client() {
+        let client_maker = client_maker(|| {
+            Ok(ClientResult::Ok(ClientResponse {
+                status: 201,
+                headers: vec![],
+                body: b"created".to_vec(),
+            }))
+        });
+
+        let response = send(client_maker, request("https://example.com/"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"created");
+    }
+
+    #[tokio::test]
+    async fn it_maps_each_client_error_to_a_wasi_http_error() {
+        let cases: Vec<(HttpClientError, fn(&WasiHttpError) -> bool)> = vec![
+            (HttpClientError::BadRequest, |e| {
+                matches!(e, WasiHttpError::InvalidUrl(_))
+            }),
+            (
+                HttpClientError::Timeout {
+                    phase: TimeoutPhase::Connect,
+                    limit_ms: 100,
+                },
+                |e| matches!(e, WasiHttpError::TimeoutError(m) if m.contains("100ms")),
+            ),
+            (
+                HttpClientError::DnsFailure {
+                    cause: "no such host".into(),
+                },
+                |e| matches!(e, WasiHttpError::ProtocolError(m) if m.starts_with("dns lookup failed")),
+            ),
+            (
+                HttpClientError::ConnectRefused {
+                    cause: "refused".into(),
+                },
+                |e| matches!(e, WasiHttpError::ProtocolError(m) if m.starts_with("connection refused")),
+            ),
+            (
+                HttpClientError::TlsError {
+                    cause: "bad certificate".into(),
+                },
+                |e| matches!(e, WasiHttpError::ProtocolError(m) if m.starts_with("tls failure")),
+            ),
+            (
+                HttpClientError::BodyTooLarge { limit: 10 },
+                |e| matches!(e, WasiHttpError::ProtocolError(m) if m.contains("10 bytes")),
+            ),
+            (
+                HttpClientError::PolicyDenied {
+                    reason: "denied".into(),
+                },
+                |e| matches!(e, WasiHttpError::UnexpectedError(m) if m.starts_with("request denied by policy")),
+            ),
+            (
+                HttpClientError::RateLimited,
+                |e| matches!(e, WasiHttpError::UnexpectedError(m) if m.starts_with("too many requests")),
+            ),
+            (
+                HttpClientError::NotReady,
+                |e| matches!(e, WasiHttpError::UnexpectedError(m) if m.starts_with("too many requests")),
+            ),
+            (HttpClientError::Unknown, |e| {
+                matches!(e, WasiHttpError::UnexpectedError(_))
+            }),
+        ];
+
+        for (error, is_expected) in cases {
+            let description = error.to_string();
+            let error = Arc::new(std::sync::Mutex::new(Some(error)));
+            let client_maker = client_maker(move || {
+                Err(error
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .expect("one request per client"))
+            });
+
+            let mapped = send(client_maker, request("https://example.com/"))
+                .await
+                .unwrap_err();
+
+            assert!(
+                is_expected(&mapped),
+                "{} mapped to {:?}",
+                description,
+                mapped
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn it_maps_errors_returned_to_the_guest() {
+        let client_maker = client_maker(|| {
+            Ok(ClientResult::Error(crate::http::ClientError::DnsFailure(
+                "no such host".into(),
+            )))
+        });
+
+        let mapped = send(client_maker, request("https://example.com/"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(mapped, WasiHttpError::ProtocolError(m) if m.contains("no such host")));
+    }
+
+    #[tokio::test]
+    async fn it_applies_the_egress_policy() {
+        let policy = EgressPolicy {
+            deny: vec!["example.com".parse::<HostPattern>().unwrap()],
+            ..Default::default()
+        };
+        let client_maker = with_egress_policy(
+            client_maker(|| unreachable!("denied requests aren't sent")),
+            policy,
+        );
+
+        let mapped = send(client_maker, request("https://example.com/"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            mapped,
+            WasiHttpError::UnexpectedError(m) if m.starts_with("request denied by policy")
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_limits_request_bodies() {
+        assert_eq!(read_body(body(b"12345"), 5).await.unwrap(), b"12345");
+        assert!(matches!(
+            read_body(body(b"123456"), 5).await,
+            Err(WasiHttpError::ProtocolError(m)) if m.contains("5 bytes")
+        ));
+    }
+}
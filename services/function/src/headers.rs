@@ -0,0 +1,96 @@
+//! Conversions between the header lists guests see and `http::HeaderMap`.
+//! Header maps are listed for guests with `resource_providers::http::map_headers`.
+//!
+//! Values are passed as the raw bytes received, so headers which aren't valid utf-8
+//! reach the guest and the caller unchanged rather than being replaced or dropped.
+
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use thiserror::Error;
+
+pub type Headers = crate::bindgen::mycelia::execution::types::Headers;
+
+/// Identifies a request in guest logs, see `request_id`
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request id accepted from a client
+const MAX_REQUEST_ID_BYTES: usize = 64;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum InvalidHeader {
+    #[error("invalid header name {0:?}")]
+    Name(String),
+    #[error("invalid value for header {0}")]
+    Value(String),
+}
+
+/// Fails on names which aren't tokens and values containing control characters
+pub fn to_header_map(headers: Headers) -> Result<HeaderMap, InvalidHeader> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| InvalidHeader::Name(name.clone()))?;
+        let value =
+            HeaderValue::from_bytes(&value).map_err(|_| InvalidHeader::Value(name.to_string()))?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// The request's `x-request-id`, if it's short and printable enough to be logged
+pub fn request_id(headers: &Headers) -> Option<String> {
+    let (_, value) = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(REQUEST_ID_HEADER))?;
+    let valid = !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_BYTES
+        && value.iter().all(u8::is_ascii_graphic);
+    valid.then(|| String::from_utf8_lossy(value).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+    use resource_providers::http::map_headers;
+    use resource_providers::http::strategies::headers;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn it_preserves_arbitrary_headers(headers in headers()) {
+            let map = to_header_map(headers.clone()).unwrap();
+            let mapped = map_headers(&map);
+
+            prop_assert_eq!(mapped.len(), headers.len());
+            for (name, _) in &headers {
+                let values = |headers: &Headers| -> Vec<Vec<u8>> {
+                    headers.iter().filter(|(n, _)| n == name).map(|(_, v)| v.clone()).collect()
+                };
+                prop_assert_eq!(values(&mapped), values(&headers));
+            }
+            prop_assert_eq!(to_header_map(mapped).unwrap(), map);
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_headers() {
+        assert_eq!(
+            to_header_map(vec![("bad name".into(), b"v".to_vec())]),
+            Err(InvalidHeader::Name("bad name".into()))
+        );
+        assert_eq!(
+            to_header_map(vec![("x-split".into(), b"a\r\nb: c".to_vec())]),
+            Err(InvalidHeader::Value("x-split".into()))
+        );
+    }
+
+    #[test]
+    fn it_reads_valid_request_ids() {
+        let id = |value: &[u8]| request_id(&vec![("X-Request-Id".into(), value.to_vec())]);
+        assert_eq!(id(b"abc-123").as_deref(), Some("abc-123"));
+        assert_eq!(id(b""), None);
+        assert_eq!(id(b"a b"), None);
+        assert_eq!(id(&[b'a'; MAX_REQUEST_ID_BYTES + 1]), None);
+        assert_eq!(request_id(&vec![]), None);
+    }
+}
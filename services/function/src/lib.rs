@@ -8,54 +8,259 @@ mod bindgen {
     });
 }
 
+mod streaming_bindgen {
+    use wasmtime::component::*;
+
+    bindgen!({
+      path: "../../guests/mycelia_guest_function/wit",
+      world: "streaming-function-world",
+      async: true,
+      with: {
+        "mycelia:execution/types": crate::bindgen::mycelia::execution::types,
+      }
+    });
+}
+
+pub mod headers;
+pub mod pool;
+pub mod streams;
+mod wasi_http;
+
 pub mod types {
     pub type HttpRequest = crate::bindgen::mycelia::execution::types::HttpRequest;
     pub type HttpResponse = crate::bindgen::mycelia::execution::types::HttpResponse;
     pub type Method = crate::bindgen::mycelia::execution::types::Method;
+    pub type RequestHead = crate::bindgen::mycelia::execution::types::RequestHead;
     pub type FunctionWorld = crate::bindgen::FunctionWorld;
+    pub type StreamingFunctionWorld = crate::streaming_bindgen::StreamingFunctionWorld;
+    pub use crate::streams::{FunctionRequest, FunctionResponse};
 }
 
 ///! Notes
-///! Engine::precompile_component is the same as precompile_module
-///! instantiate_pre -> https://docs.rs/wasmtime/12.0.1/wasmtime/component/struct.Linker.html#method.instantiate_pre
+///! Components are linked once per deploy using `instantiate_pre`
+///! -> https://docs.rs/wasmtime/12.0.1/wasmtime/component/struct.Linker.html#method.instantiate_pre
+///! Compiled artifacts are cached on disk by `wasmtime_components::component_cache`
+///! Bodies are streamed, see `streams` for how components using the buffered world are served
 ///!
 
 pub mod service {
 
-    use std::sync::Arc;
+    use std::task::{ready, Context, Poll};
+    use std::time::Duration;
     use std::{future::Future, pin::Pin};
+    use thiserror::Error;
     use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-    use tokio::sync::{oneshot, Mutex};
+    use tokio::sync::oneshot;
     use tokio::task::JoinHandle;
     use tower::util::BoxCloneService;
     use tower::{service_fn, ServiceExt};
     use tower::{util::BoxService, BoxError, Service};
-    use wasmtime::component::{Component, Linker};
+    use wasmtime::component::{Component, Instance, InstancePre};
     use wasmtime::Store;
+    use wasmtime_components::limits::LimitExceeded;
     use wasmtime_components::runtime::new_linker;
     use wasmtime_components::runtime_view::RuntimeView;
+    use wasmtime_wasi::preview2::WasiView;
+    use wasmtime_wasi_http::proxy::Proxy;
 
+    use crate::headers::request_id;
+    use crate::pool::{InstancePool, InstanceProducer, PoolConfig, PooledService};
+    use crate::streams::{collect_body, finish_resources, push_resources};
     use crate::types::*;
-    pub type WasmComponent = Component;
+
+    /// The world a function component was built against
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ComponentWorld {
+        /// `mycelia:execution/function-world`
+        Function,
+        /// `mycelia:execution/streaming-function-world`
+        StreamingFunction,
+        /// Any component exporting `wasi:http/incoming-handler`
+        WasiHttp,
+    }
+
+    impl ComponentWorld {
+        /// Detects the world a component targets from the exports of its binary
+        pub fn detect(bytes: &[u8]) -> anyhow::Result<Self> {
+            let exports = top_level_exports(bytes)?;
+
+            let world = if exports
+                .iter()
+                .any(|name| name.starts_with("wasi:http/incoming-handler"))
+            {
+                Self::WasiHttp
+            } else if exports
+                .iter()
+                .any(|name| name == "handle-streaming-request")
+            {
+                Self::StreamingFunction
+            } else if exports.iter().any(|name| name == "handle-request") {
+                Self::Function
+            } else {
+                return Err(anyhow::anyhow!(
+                    "component doesn't export `handle-request`, `handle-streaming-request` or `wasi:http/incoming-handler`"
+                ));
+            };
+
+            Ok(world)
+        }
+    }
+
+    impl std::fmt::Display for ComponentWorld {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Function => write!(f, "mycelia:execution/function-world"),
+                Self::StreamingFunction => write!(f, "mycelia:execution/streaming-function-world"),
+                Self::WasiHttp => write!(f, "wasi:http/incoming-handler"),
+            }
+        }
+    }
+
+    /// Names exported by the outer most component, ignoring nested modules and components
+    fn top_level_exports(bytes: &[u8]) -> anyhow::Result<Vec<String>> {
+        use wasmparser::{Parser, Payload};
+
+        let mut exports = vec![];
+        let mut depth = 0usize;
+        for payload in Parser::new(0).parse_all(bytes) {
+            match payload? {
+                Payload::ModuleSection { .. } | Payload::ComponentSection { .. } => depth += 1,
+                Payload::End(_) => depth = depth.saturating_sub(1),
+                Payload::ComponentExportSection(section) if depth == 0 => {
+                    for export in section {
+                        exports.push(export?.name.as_str().to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(exports)
+    }
+
+    /// A compiled component and the world it was built against
+    #[derive(Clone)]
+    pub struct WasmComponent {
+        component: Component,
+        world: ComponentWorld,
+    }
+
+    impl WasmComponent {
+        /// Compiles `bytes`, detecting the world the component targets
+        pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+            let world = ComponentWorld::detect(bytes)?;
+            let component = wasmtime_components::runtime::new_component_from_bytes(bytes)?;
+            Ok(Self { component, world })
+        }
+
+        pub fn from_path(path: &std::path::Path) -> anyhow::Result<Self> {
+            Self::from_bytes(&std::fs::read(path)?)
+        }
+
+        pub fn world(&self) -> ComponentWorld {
+            self.world
+        }
+    }
 
     // Note, we're not using a BoxCloneService here
     // its unclear how FunctionWorld & Instance behave when cloned.
     // Good place to dive in on that -> https://docs.rs/wasmtime/12.0.1/wasmtime/component/struct.Instance.html
     //                               -> https://docs.rs/wasmtime/latest/src/wasmtime/instance.rs.html#33
 
-    pub type FunctionComponentService = BoxService<HttpRequest, HttpResponse, BoxError>;
+    pub type FunctionComponentService = BoxService<FunctionRequest, FunctionResponse, BoxError>;
+
+    /// Limits applied to every invocation of a deployed component
+    #[derive(Debug, Clone)]
+    pub struct InvocationLimits {
+        /// Wall clock budget for a single `handle-request` call. `None` is unlimited
+        pub timeout: Option<Duration>,
+        /// Largest request body buffered for components using `function-world`
+        pub max_buffered_body_bytes: usize,
+    }
+
+    impl Default for InvocationLimits {
+        fn default() -> Self {
+            Self {
+                timeout: None,
+                max_buffered_body_bytes: 5 * 1024 * 1024,
+            }
+        }
+    }
+
+    #[derive(Error, Debug)]
+    /// Errors produced by the host while invoking a guest.
+    ///
+    /// These are returned as the `BoxError` of a `FunctionComponentService`
+    /// and can be recovered with `downcast_ref`
+    pub enum InvocationError {
+        #[error("invocation exceeded its time limit of {limit:?}")]
+        Timeout { limit: Duration },
+        #[error("invocation exceeded a resource limit - {0}")]
+        ResourceLimit(#[from] LimitExceeded),
+        #[error("request body exceeded the buffered body limit of {limit} bytes")]
+        BodyTooLarge { limit: usize },
+        #[error("function returned without starting a response")]
+        MissingResponse,
+    }
+
+    impl InvocationError {
+        /// Lifts known host failures out of a guest call error so callers can
+        /// downcast them from the `BoxError`
+        pub(crate) fn from_call_error(e: anyhow::Error) -> BoxError {
+            match e.downcast_ref::<LimitExceeded>() {
+                Some(limit) => InvocationError::from(limit.clone()).into(),
+                None => e.into(),
+            }
+        }
+    }
+
+    /// Bindings for the world a component was built against
+    enum FunctionBindings {
+        Buffered(FunctionWorld),
+        Streaming(StreamingFunctionWorld),
+        WasiHttp(Proxy),
+    }
+
+    impl FunctionBindings {
+        fn new(
+            world: ComponentWorld,
+            store: &mut Store<RuntimeView>,
+            instance: &Instance,
+        ) -> anyhow::Result<Self> {
+            let bindings = match world {
+                ComponentWorld::Function => Self::Buffered(FunctionWorld::new(store, instance)?),
+                ComponentWorld::StreamingFunction => {
+                    Self::Streaming(StreamingFunctionWorld::new(store, instance)?)
+                }
+                ComponentWorld::WasiHttp => Self::WasiHttp(Proxy::new(store, instance)?),
+            };
+            Ok(bindings)
+        }
+    }
+
+    impl From<FunctionWorld> for FunctionBindings {
+        fn from(bindings: FunctionWorld) -> Self {
+            Self::Buffered(bindings)
+        }
+    }
 
-    // The request to execute and a channel to respond on
-    // with the response
-    type InnerRequest = (HttpRequest, oneshot::Sender<InnerResponse>);
-    type InnerResponse = Result<HttpResponse, BoxError>;
+    // The request to execute, a channel to respond on with the response
+    // and one told whether the instance is still healthy once the invocation has finished
+    type InnerRequest = (
+        FunctionRequest,
+        oneshot::Sender<InnerResponse>,
+        oneshot::Sender<bool>,
+    );
+    type InnerResponse = Result<FunctionResponse, BoxError>;
 
     type RequestSink = Sender<InnerRequest>;
     type RequestSource = Receiver<InnerRequest>;
 
     struct InnerService {
         request_sink: RequestSink,
+        // Resolves once the last invocation has finished, which may be well after
+        // its response was returned if the body is streamed
+        in_flight: Option<oneshot::Receiver<bool>>,
         _handle: JoinHandle<()>,
     }
 
@@ -65,73 +270,189 @@ pub mod service {
         }
     }
 
-    async fn run_inner_service_loop<T: wasmtime::AsContextMut>(
-        bindings: FunctionWorld,
-        _instance: wasmtime::component::Instance,
-        mut store: T,
-        mut rx: RequestSource,
-    ) where
-        <T as wasmtime::AsContext>::Data: Send,
-    {
-        while let Some((request, reply)) = rx.recv().await {
-            let response = bindings
-                .call_handle_request(&mut store, &request)
+    pub(crate) async fn with_timeout<F: Future>(
+        timeout: Option<Duration>,
+        invocation: F,
+    ) -> Result<F::Output, InvocationError> {
+        match timeout {
+            Some(limit) => tokio::time::timeout(limit, invocation)
                 .await
-                .map_err(BoxError::from);
-            let _ = reply.send(response);
+                .map_err(|_| InvocationError::Timeout { limit }),
+            None => Ok(invocation.await),
+        }
+    }
+
+    /// Invokes a component built against `function-world`.
+    /// Returns false if the instance can no longer be trusted to serve requests,
+    /// failures before the guest is entered leave it healthy
+    async fn invoke_buffered(
+        bindings: &FunctionWorld,
+        store: &mut Store<RuntimeView>,
+        request: FunctionRequest,
+        reply: oneshot::Sender<InnerResponse>,
+        limits: &InvocationLimits,
+    ) -> bool {
+        let FunctionRequest { head, mut body } = request;
+
+        let body = match collect_body(&mut body, limits.max_buffered_body_bytes).await {
+            Ok(body) => body,
+            Err(e) => {
+                let _ = reply.send(Err(e));
+                return true;
+            }
+        };
+
+        let request = HttpRequest {
+            method: head.method,
+            headers: head.headers,
+            body,
+            uri: head.uri,
+        };
+
+        let invocation = bindings.call_handle_request(&mut *store, &request);
+
+        match with_timeout(limits.timeout, invocation).await {
+            Ok(response) => {
+                // A trap may leave the guest in an unknown state
+                let healthy = response.is_ok();
+                let response = response
+                    .map(FunctionResponse::from)
+                    .map_err(InvocationError::from_call_error);
+                let _ = reply.send(response);
+                healthy
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e.into()));
+                false
+            }
+        }
+    }
+
+    /// Invokes a component built against `streaming-function-world`.
+    /// Returns false if the instance can no longer be trusted to serve requests
+    async fn invoke_streaming(
+        bindings: &StreamingFunctionWorld,
+        store: &mut Store<RuntimeView>,
+        request: FunctionRequest,
+        reply: oneshot::Sender<InnerResponse>,
+        limits: &InvocationLimits,
+    ) -> bool {
+        let FunctionRequest { head, body } = request;
+
+        let (body, response) = match push_resources(store.data_mut().table_mut(), body, reply) {
+            Ok(resources) => resources,
+            // The reply is dropped along with the failed resources
+            Err(_) => return true,
+        };
+        let (body_rep, response_rep) = (body.rep(), response.rep());
+
+        let invocation = bindings.call_handle_streaming_request(&mut *store, &head, body, response);
+
+        let (error, healthy) = match with_timeout(limits.timeout, invocation).await {
+            Ok(Ok(())) => (None, true),
+            // A trap may leave the guest in an unknown state
+            Ok(Err(e)) => (Some(InvocationError::from_call_error(e)), false),
+            Err(e) => (Some(e.into()), false),
+        };
+
+        finish_resources(store.data_mut().table_mut(), body_rep, response_rep, error);
+        healthy
+    }
+
+    async fn run_inner_service_loop(
+        bindings: FunctionBindings,
+        _instance: Instance,
+        mut store: Store<RuntimeView>,
+        mut rx: RequestSource,
+        limits: InvocationLimits,
+    ) {
+        while let Some((request, reply, done)) = rx.recv().await {
+            store
+                .data()
+                .set_request_id(request_id(&request.head.headers));
+            let healthy = match &bindings {
+                FunctionBindings::Buffered(bindings) => {
+                    invoke_buffered(bindings, &mut store, request, reply, &limits).await
+                }
+                FunctionBindings::Streaming(bindings) => {
+                    invoke_streaming(bindings, &mut store, request, reply, &limits).await
+                }
+                FunctionBindings::WasiHttp(bindings) => {
+                    crate::wasi_http::invoke(bindings, &mut store, request, reply, limits.timeout)
+                        .await
+                }
+            };
+            let _ = done.send(healthy);
+
+            if !healthy {
+                // The guest was interrupted or trapped mid call, its state can't be trusted anymore.
+                // Stop serving requests so the instance is dropped.
+                break;
+            }
         }
     }
 
     impl InnerService {
-        pub fn new<T: wasmtime::AsContextMut + Send + 'static>(
-            bindings: FunctionWorld,
-            instance: wasmtime::component::Instance,
-            store: T,
+        fn new(
+            bindings: impl Into<FunctionBindings>,
+            instance: Instance,
+            store: Store<RuntimeView>,
             buffer_size: usize,
-        ) -> Self
-        where
-            <T as wasmtime::AsContext>::Data: Send,
-        {
+            limits: InvocationLimits,
+        ) -> Self {
             let (request_sink, request_source) = channel(buffer_size);
 
             let handle = tokio::spawn(run_inner_service_loop(
-                bindings,
+                bindings.into(),
                 instance,
                 store,
                 request_source,
+                limits,
             ));
 
             Self {
                 request_sink,
+                in_flight: None,
                 _handle: handle,
             }
         }
     }
 
-    impl Service<HttpRequest> for InnerService {
-        type Response = HttpResponse;
+    impl Service<FunctionRequest> for InnerService {
+        type Response = FunctionResponse;
 
         type Error = BoxError;
 
         type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-        fn poll_ready(
-            &mut self,
-            _cx: &mut std::task::Context<'_>,
-        ) -> std::task::Poll<Result<(), Self::Error>> {
-            // We can slow down the rate of invocation here if needed in the future
-            std::task::Poll::Ready(Ok(()))
+        /// Ready once the previous invocation has finished,
+        /// failing if it left the instance unable to serve another request
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            if let Some(in_flight) = &mut self.in_flight {
+                // The loop is gone if the sender was dropped
+                let healthy = ready!(Pin::new(in_flight).poll(cx)).unwrap_or(false);
+                self.in_flight = None;
+                if !healthy {
+                    return Poll::Ready(Err("instance can no longer serve requests".into()));
+                }
+            }
+            Poll::Ready(Ok(()))
         }
 
-        fn call(&mut self, req: HttpRequest) -> Self::Future {
+        fn call(&mut self, req: FunctionRequest) -> Self::Future {
             let pipe = self.request_sink.clone();
+            let (done_tx, done_rx) = oneshot::channel();
+            self.in_flight = Some(done_rx);
             Box::pin(async move {
                 let pipe = pipe;
                 let req = req;
 
                 let (reply_tx, reply_rx) = oneshot::channel::<InnerResponse>();
 
-                let _ = pipe.send((req, reply_tx)).await.map_err(BoxError::from)?;
+                let _ = pipe
+                    .send((req, reply_tx, done_tx))
+                    .await
+                    .map_err(BoxError::from)?;
 
                 reply_rx.await?.map_err(BoxError::from)
             })
@@ -139,13 +460,27 @@ pub mod service {
     }
 
     async fn new_function_component_svc(
-        base_component: &Component,
+        instance_pre: &InstancePre<RuntimeView>,
+        world: ComponentWorld,
         mut store: Store<RuntimeView>,
-        linker: &Linker<RuntimeView>,
+        limits: &InvocationLimits,
     ) -> Result<FunctionComponentService, BoxError> {
-        let (bindings, instance) =
-            FunctionWorld::instantiate_async(&mut store, base_component, &linker).await?;
-        Ok(InnerService::new(bindings, instance, store, 100).into())
+        let instance = instance_pre.instantiate_async(&mut store).await?;
+        let bindings = FunctionBindings::new(world, &mut store, &instance)?;
+        // Pooled instances only ever handle a single request at a time
+        Ok(InnerService::new(bindings, instance, store, 1, limits.clone()).into())
+    }
+
+    /// Links `component` against everything the host provides to function components,
+    /// including the streaming body resources and wasi-http
+    fn new_instance_pre(component: &Component) -> anyhow::Result<InstancePre<RuntimeView>> {
+        let mut linker = new_linker();
+        StreamingFunctionWorld::add_to_linker(&mut linker, |view| view)?;
+        wasmtime_wasi_http::bindings::http::types::add_to_linker(&mut linker, |view| view)?;
+        wasmtime_wasi_http::bindings::http::outgoing_handler::add_to_linker(&mut linker, |view| {
+            view
+        })?;
+        linker.instantiate_pre(component)
     }
 
     // Notes
@@ -155,10 +490,12 @@ pub mod service {
 
     // Produces a FunctionComponentServicer maker for a specific `base_component`.
     // `base_component` is a unique instance of a **guest code implmentation**.
+    //
+    // Every service produced by the maker shares a single `InstancePool`, instances are
+    // created by the pool as needed rather than once per produced service.
 
     // Notes:
-    // 1. We might need to use a precompiled `Component`. Not sure if creating the `Component`
-    // produces a precompiled on out of the box.
+    // 1. `base_component` is linked once here, failing early if its imports can't be satisfied.
     // 2. Use caution when moving the created instance around. Inproper sharing will
     // lead to downsteam errors in guest code and leaking VM resources.
 
@@ -168,28 +505,42 @@ pub mod service {
         BoxCloneService<(), FunctionComponentService, BoxError>;
 
     pub fn new_function_service_maker(
-        base_component: Component,
+        base_component: WasmComponent,
         store_producer: wasmtime_components::runtime::StoreProducer,
-    ) -> FunctionComponentServiceMaker {
-        let linker = Arc::new(Mutex::new(new_linker()));
+        pool_config: PoolConfig,
+        limits: InvocationLimits,
+    ) -> anyhow::Result<FunctionComponentServiceMaker> {
+        let instance_pre = new_instance_pre(&base_component.component)?;
+        let instance_producer =
+            new_instance_producer(instance_pre, base_component.world, store_producer, limits);
+        let pool = InstancePool::new(instance_producer, pool_config);
+
+        let svc = service_fn(move |_v: ()| {
+            let pool = pool.clone();
+            async move { Ok::<_, BoxError>(PooledService::new(pool).into()) }
+        });
+
+        Ok(BoxCloneService::new(svc))
+    }
 
+    /// Produces a new dedicated instance of the pre-linked component per call
+    fn new_instance_producer(
+        instance_pre: InstancePre<RuntimeView>,
+        world: ComponentWorld,
+        store_producer: wasmtime_components::runtime::StoreProducer,
+        limits: InvocationLimits,
+    ) -> InstanceProducer {
         let future_producer = move |_v: ()| {
-            let linker = linker.clone();
-            let base_component = base_component.clone();
-            let store_maker = store_producer.clone();
+            let instance_pre = instance_pre.clone();
+            let mut store_maker = store_producer.clone();
+            let limits = limits.clone();
 
             async move {
-                let linker = linker.clone();
-                let mut linker = linker.lock().await;
-
-                let base_component = base_component.clone();
-                let mut store_maker = store_maker.clone();
-
                 let ready_store_maker = store_maker.ready().await?;
 
                 let store = ready_store_maker.call(()).await?;
 
-                new_function_component_svc(&base_component, store, &mut linker).await
+                new_function_component_svc(&instance_pre, world, store, &limits).await
             }
         };
 
@@ -198,37 +549,126 @@ pub mod service {
         return BoxCloneService::new(svc);
     }
 
-    pub fn empty_base_function_component() -> Component {
+    pub fn empty_base_function_component() -> WasmComponent {
         let bytes = include_bytes!("../../../components/mycelia_guest_function-component.wasm");
 
-        wasmtime_components::runtime::new_component_from_bytes(bytes).expect(
+        WasmComponent::from_bytes(bytes).expect(
             "base function component is corrupted. did you specify the correct componnent path?",
         )
     }
 
     #[cfg(test)]
     mod test {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
         use crate::{
-            bindgen::HttpRequest,
-            types::{FunctionWorld, Method},
+            pool::PoolConfig,
+            streams::{collect_body, full_body},
+            types::{FunctionRequest, FunctionWorld, Method, RequestHead},
         };
-        use tower::Service;
+        use resource_providers::http::{EgressPolicy, OutboundLimiter, OutboundLimits};
+        use resource_providers::providers::http_client_mock::MockHttp;
+        use tower::util::BoxCloneService;
+        use tower::{Service, ServiceExt};
         use wasmtime::{
             component::{Component, Linker},
             Store,
         };
+        use wasmtime_components::limits::{GuestLimits, LimitExceeded};
+        use wasmtime_components::runtime::{
+            make_store_producer, GuestStorage, OutboundHttp, StoreProducer,
+        };
+        use wasmtime_components::runtime_view::{GuestEnvironment, RuntimeView};
 
-        use super::InnerService;
+        use super::{
+            new_function_service_maker, ComponentWorld, InnerService, InvocationError,
+            InvocationLimits, WasmComponent,
+        };
 
         use wasmtime_wasi::preview2::command::add_to_linker;
 
+        /// A `function-world` component whose `handle-request` runs `body`,
+        /// core wasm instructions leaving the address of the response on the stack
+        fn function_component(body: &str) -> WasmComponent {
+            let wat = format!(
+                r#"
+                (component
+                    (core module $m
+                        (memory (export "memory") 1)
+                        (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+                            i32.const 1024)
+                        (func (export "handle-request")
+                            (param i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)
+                            {body})
+                    )
+                    (core instance $i (instantiate $m))
+                    (type $method-def (variant
+                        (case "get") (case "head") (case "post") (case "put") (case "delete")
+                        (case "connect") (case "options") (case "trace") (case "patch")
+                        (case "other" string)))
+                    (export $method "method" (type $method-def))
+                    (type $headers (list (tuple string (list u8))))
+                    (type $request-def (record
+                        (field "method" $method)
+                        (field "headers" $headers)
+                        (field "body" (list u8))
+                        (field "uri" string)))
+                    (export $request "http-request" (type $request-def))
+                    (type $response-def (record
+                        (field "status" u16)
+                        (field "headers" $headers)
+                        (field "body" (list u8))))
+                    (export $response "http-response" (type $response-def))
+                    (func $handle-request (param "req" $request) (result $response)
+                        (canon lift (core func $i "handle-request")
+                            (memory $i "memory")
+                            (realloc (func $i "realloc"))))
+                    (export "handle-request" (func $handle-request))
+                )
+                "#
+            );
+            WasmComponent::from_bytes(&wat::parse_str(wat).unwrap()).unwrap()
+        }
+
+        /// Stores as they're produced for deployed components, without outbound access
+        fn store_producer(limits: GuestLimits) -> StoreProducer {
+            make_store_producer(
+                limits,
+                OutboundHttp::Replay(MockHttp::default()),
+                EgressPolicy::default(),
+                OutboundLimiter::new(&OutboundLimits::default()),
+                GuestStorage::default(),
+                GuestEnvironment::default(),
+            )
+        }
+
+        /// Counts the stores `producer` makes, one per instance
+        fn counting(producer: StoreProducer, stores: Arc<AtomicUsize>) -> StoreProducer {
+            BoxCloneService::new(producer.map_response(move |store| {
+                stores.fetch_add(1, Ordering::SeqCst);
+                store
+            }))
+        }
+
+        fn request() -> FunctionRequest {
+            FunctionRequest {
+                head: RequestHead {
+                    method: Method::Get,
+                    headers: vec![],
+                    uri: "/".into(),
+                },
+                body: full_body(vec![]),
+            }
+        }
+
         #[tokio::test]
         async fn it_creates_and_invokes_a_function_component_service() {
             let engine = crate::test::new_engine().unwrap();
             let mut linker = Linker::new(&engine);
 
-            let view = crate::test::ServerWasiView::new();
-            let mut store = Store::new(&engine, view);
+            let mut store = Store::new(&engine, RuntimeView::new());
 
             let _ = add_to_linker(&mut linker).unwrap();
 
@@ -243,35 +683,124 @@ pub mod service {
                     .await
                     .unwrap();
 
-            let mut service = InnerService::new(bindings, instance, store, 10);
+            let mut service =
+                InnerService::new(bindings, instance, store, 10, InvocationLimits::default());
 
-            let should_echo = HttpRequest {
-                method: Method::Get,
-                headers: vec![],
-                body: vec![2, 4, 6],
-                uri: "foo".into(),
+            let should_echo = FunctionRequest {
+                head: RequestHead {
+                    method: Method::Get,
+                    headers: vec![],
+                    uri: "foo".into(),
+                },
+                body: full_body(vec![2, 4, 6]),
             };
 
             let future = service.call(should_echo);
 
-            let result = future.await.unwrap();
+            let mut result = future.await.unwrap();
 
             assert_eq!(result.status, 200u16);
-            assert_eq!(result.body, vec![2, 4, 6]);
+            assert_eq!(
+                collect_body(&mut result.body, usize::MAX).await.unwrap(),
+                vec![2, 4, 6]
+            );
+        }
+
+        #[test]
+        fn it_detects_the_component_world() {
+            let bytes =
+                std::fs::read("../../components/mycelia_guest_function-component.wasm").unwrap();
+            assert_eq!(
+                ComponentWorld::detect(&bytes).unwrap(),
+                ComponentWorld::Function
+            );
+            assert!(ComponentWorld::detect(&[]).is_err());
+        }
+
+        #[tokio::test]
+        async fn it_times_out_spinning_guests() {
+            // The guest never returns, it's interrupted at the next epoch once the timeout passes
+            let component = function_component("(loop $spin (br $spin)) unreachable");
+            let limits = InvocationLimits {
+                timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            };
+            let mut maker = new_function_service_maker(
+                component,
+                store_producer(GuestLimits::default()),
+                PoolConfig::default(),
+                limits,
+            )
+            .unwrap();
+            let mut service = maker.ready().await.unwrap().call(()).await.unwrap();
+
+            let error = tokio::time::timeout(
+                Duration::from_secs(10),
+                service.ready().await.unwrap().call(request()),
+            )
+            .await
+            .expect("the spinning guest wasn't interrupted")
+            .unwrap_err();
+
+            assert!(matches!(
+                error.downcast_ref::<InvocationError>(),
+                Some(InvocationError::Timeout { limit }) if *limit == Duration::from_millis(100)
+            ));
+        }
+
+        #[tokio::test]
+        async fn it_drops_instances_which_exceed_a_resource_limit() {
+            // Grows memory by 64MiB
+            let component = function_component("(drop (memory.grow (i32.const 1024))) i32.const 0");
+            let limits = GuestLimits {
+                max_memory_bytes: 1024 * 1024,
+                ..Default::default()
+            };
+            let stores = Arc::new(AtomicUsize::new(0));
+            let pool_config = PoolConfig {
+                min_instances: 0,
+                ..Default::default()
+            };
+            let mut maker = new_function_service_maker(
+                component,
+                counting(store_producer(limits), stores.clone()),
+                pool_config,
+                InvocationLimits::default(),
+            )
+            .unwrap();
+
+            for _ in 0..2 {
+                let mut service = maker.ready().await.unwrap().call(()).await.unwrap();
+                let error = service
+                    .ready()
+                    .await
+                    .unwrap()
+                    .call(request())
+                    .await
+                    .unwrap_err();
+                assert!(matches!(
+                    error.downcast_ref::<InvocationError>(),
+                    Some(InvocationError::ResourceLimit(LimitExceeded::Memory { limit, .. }))
+                        if *limit == 1024 * 1024
+                ));
+            }
+            // The trapped instance isn't reused
+            assert_eq!(stores.load(Ordering::SeqCst), 2);
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::path::Path;
     use std::time::Instant;
 
     // TODO need to inject the mycelia http client provider codes here
     use super::types::*;
-    use resource_providers::core::IdProductionError;
+    use resource_providers::core::ResourceIdPool;
     use resource_providers::http::{HostClientResource, HostClientResourceMaker};
+    use resource_providers::providers::http_client_mock::MockHttp;
     use tower::util::BoxService;
-    use tower::{service_fn, ServiceBuilder, ServiceExt};
     use wasmtime::component::{Component, Linker};
     use wasmtime::{Config, Engine, Store};
     use wasmtime_wasi::preview2::{
@@ -311,6 +840,7 @@ mod test {
         pub(crate) table: Table,
         pub(crate) ctx: WasiCtx,
         pub(crate) host_client_resource: HostClientResource,
+        pub(crate) http: MockHttp,
     }
 
     impl ServerWasiView {
@@ -321,31 +851,30 @@ mod test {
                 .build(&mut table)
                 .unwrap();
 
-            async fn service_fn(v: ()) -> Result<u32, IdProductionError> {
-                Ok(100u32)
-            }
-
             // This would be reused by multiple resources
-            let resource_id_provider = ServiceBuilder::new().service_fn(service_fn).boxed();
+            let id_pool = ResourceIdPool::new(16);
 
-            // An instance of an actual maker
-            let http_client_maker =
-                resource_providers::providers::http_client_hyper::new_client_maker();
+            // Outbound requests made by the guest are answered from its fixture
+            let http = MockHttp::from_file(Path::new(
+                "../../guests/mycelia_guest_function/fixtures/http.json",
+            ))
+            .unwrap();
 
-            // Our resource with the actual maker and id provider
+            // Our resource with the mock maker and id provider
             let host_client_resource =
-                HostClientResource::new(http_client_maker, resource_id_provider);
+                HostClientResource::with_id_pool(http.client_maker(), id_pool);
             Self {
                 table,
                 ctx,
                 host_client_resource,
+                http,
             }
         }
     }
 
     impl HostClientResourceMaker for ServerWasiView {
-        fn new(&mut self) -> anyhow::Result<&mut resource_providers::http::HostClientResource> {
-            Ok(&mut self.host_client_resource)
+        fn http_client(&mut self) -> &mut HostClientResource {
+            &mut self.host_client_resource
         }
     }
 
@@ -385,6 +914,7 @@ mod test {
 
         assert_eq!(result.status, 200u16);
         assert_eq!(result.body, vec![2, 4, 6]);
+        store.data().http.assert_no_unexpected_requests();
 
         Ok(())
     }
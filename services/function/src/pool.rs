@@ -0,0 +1,584 @@
+//! Pool of pre-instantiated function component instances.
+//!
+//! Instantiating a component is comparatively expensive. Rather than paying that cost for
+//! every incoming connection the pool keeps a set of warm instances around and hands them out
+//! to requests. Each instance handles one request at a time, so a slow request only ties up
+//! the instance serving it.
+//!
+//! # Notes
+//! - Instances which fail a request are dropped rather than returned to the pool. A trap
+//! may leave the guest in an unknown state. Requests rejected before the guest is entered,
+//! such as an oversized body, don't count as failures.
+//! - `max_instances` bounds the number of concurrent invocations. Requests beyond that wait
+//! for an instance to free up.
+//! - An instance is only returned once its invocation has finished, a guest streaming its
+//! response body keeps the instance checked out after the response has been returned.
+//! - Instances which are recycled or dropped are replaced in the background, so the pool
+//! stays warm with `min_instances`.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use std::{future::Future, pin::Pin};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tower::util::BoxCloneService;
+use tower::{BoxError, Service, ServiceExt};
+
+use crate::service::FunctionComponentService;
+use crate::types::{FunctionRequest, FunctionResponse};
+
+/// Produces new, dedicated, component instances for the pool
+pub type InstanceProducer = BoxCloneService<(), FunctionComponentService, BoxError>;
+
+/// Controls the size and lifetime of pooled instances
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Instances the pool keeps warm, even when idle. At most `max_instances`
+    pub min_instances: usize,
+    /// Upper bound on live instances and therefore concurrent invocations
+    pub max_instances: usize,
+    /// Idle instances above `min_instances` are evicted after this long
+    pub idle_timeout: Duration,
+    /// Recycle an instance after it has served this many requests
+    pub max_requests_per_instance: Option<u64>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            min_instances: 1,
+            max_instances: 16,
+            idle_timeout: Duration::from_secs(60),
+            max_requests_per_instance: None,
+        }
+    }
+}
+
+struct PooledInstance {
+    service: FunctionComponentService,
+    requests: u64,
+    last_used: Instant,
+    _live: LiveInstance,
+}
+
+impl PooledInstance {
+    fn new(service: FunctionComponentService, live: &Arc<AtomicUsize>) -> Self {
+        live.fetch_add(1, Ordering::SeqCst);
+        Self {
+            service,
+            requests: 0,
+            last_used: Instant::now(),
+            _live: LiveInstance(live.clone()),
+        }
+    }
+
+    fn is_exhausted(&self, config: &PoolConfig) -> bool {
+        config
+            .max_requests_per_instance
+            .is_some_and(|max| self.requests >= max)
+    }
+}
+
+/// Counts an instance as live until it's dropped
+struct LiveInstance(Arc<AtomicUsize>);
+
+impl Drop for LiveInstance {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A pool of instances for a single component
+pub struct InstancePool {
+    config: PoolConfig,
+    producer: Mutex<InstanceProducer>,
+    idle: Mutex<VecDeque<PooledInstance>>,
+    permits: Arc<Semaphore>,
+    /// Instances which exist, idle or checked out
+    live: Arc<AtomicUsize>,
+    /// Whether a task is warming the pool up to `min_instances`
+    warming: AtomicBool,
+}
+
+impl InstancePool {
+    /// Creates a new pool and spawns tasks to warm it up to `min_instances`
+    /// and evict idle instances. Must be called from within a tokio runtime.
+    ///
+    /// `max_instances` is at least 1 and `min_instances` is clamped to it
+    pub fn new(producer: InstanceProducer, mut config: PoolConfig) -> Arc<Self> {
+        config.max_instances = config.max_instances.max(1);
+        config.min_instances = config.min_instances.min(config.max_instances);
+        let pool = Arc::new(Self {
+            permits: Arc::new(Semaphore::new(config.max_instances)),
+            config,
+            producer: Mutex::new(producer),
+            idle: Default::default(),
+            live: Default::default(),
+            warming: AtomicBool::new(false),
+        });
+
+        pool.replenish();
+        tokio::spawn(run_eviction_loop(Arc::downgrade(&pool)));
+
+        pool
+    }
+
+    /// Number of idle instances currently held by the pool
+    pub fn idle_instances(&self) -> usize {
+        self.idle.lock().expect("pool lock poisoned").len()
+    }
+
+    /// Number of instances, idle or checked out
+    pub fn live_instances(&self) -> usize {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    async fn instantiate(&self) -> Result<PooledInstance, BoxError> {
+        let mut producer = self.producer.lock().expect("pool lock poisoned").clone();
+        let service = producer.ready().await?.call(()).await?;
+        Ok(PooledInstance::new(service, &self.live))
+    }
+
+    /// Warms the pool back up to `min_instances` in the background,
+    /// unless that's already underway
+    fn replenish(self: &Arc<Self>) {
+        if self.live_instances() < self.config.min_instances
+            && !self.warming.swap(true, Ordering::SeqCst)
+        {
+            tokio::spawn(warm_up(Arc::downgrade(self)));
+        }
+    }
+
+    fn take_idle(&self) -> Option<PooledInstance> {
+        self.idle.lock().expect("pool lock poisoned").pop_back()
+    }
+
+    fn release(self: &Arc<Self>, mut instance: PooledInstance) {
+        instance.last_used = Instant::now();
+        if instance.is_exhausted(&self.config) {
+            drop(instance);
+            self.replenish();
+            return;
+        }
+        self.idle
+            .lock()
+            .expect("pool lock poisoned")
+            .push_back(instance);
+    }
+
+    /// Invoke the component using a pooled instance, instantiating a new one if none are idle
+    pub async fn call(
+        self: &Arc<Self>,
+        req: FunctionRequest,
+    ) -> Result<FunctionResponse, BoxError> {
+        let permit = self.permits.clone().acquire_owned().await?;
+
+        let mut instance = match self.take_idle() {
+            Some(instance) => instance,
+            None => self.instantiate().await?,
+        };
+
+        instance.requests += 1;
+        let response = match instance.service.ready().await {
+            Ok(service) => service.call(req).await,
+            Err(err) => {
+                drop(instance);
+                self.replenish();
+                return Err(err);
+            }
+        };
+
+        self.check_in(instance, permit).await;
+
+        response
+    }
+
+    /// Returns `instance` to the pool once its invocation has finished, if it's still healthy.
+    /// Until then the instance stays checked out and holds its permit
+    async fn check_in(
+        self: &Arc<Self>,
+        mut instance: PooledInstance,
+        permit: OwnedSemaphorePermit,
+    ) {
+        // An instance is ready again once its invocation has finished,
+        // which is usually the case by the time the response is returned
+        let finished = tokio::select! {
+            biased;
+            ready = instance.service.ready() => Some(ready.is_ok()),
+            _ = std::future::ready(()) => None,
+        };
+        match finished {
+            Some(true) => self.release(instance),
+            Some(false) => {
+                drop(instance);
+                self.replenish();
+            }
+            // The guest is still streaming its response body, wait on it apart from the request
+            None => {
+                let pool = Arc::downgrade(self);
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let healthy = instance.service.ready().await.is_ok();
+                    let Some(pool) = pool.upgrade() else {
+                        return;
+                    };
+                    if healthy {
+                        pool.release(instance);
+                    } else {
+                        drop(instance);
+                        pool.replenish();
+                    }
+                });
+            }
+        }
+    }
+
+    /// Drops idle instances past their timeout, keeping `min_instances` around
+    fn evict_idle(&self) {
+        let mut idle = self.idle.lock().expect("pool lock poisoned");
+        // The most recently used instances are at the back
+        while idle.len() > self.config.min_instances {
+            match idle.front() {
+                Some(instance) if instance.last_used.elapsed() >= self.config.idle_timeout => {
+                    idle.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+async fn warm_up(pool: Weak<InstancePool>) {
+    loop {
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        if pool.live_instances() >= pool.config.min_instances {
+            pool.warming.store(false, Ordering::SeqCst);
+            // An instance dropped while finishing up would otherwise go unreplaced
+            if pool.live_instances() >= pool.config.min_instances
+                || pool.warming.swap(true, Ordering::SeqCst)
+            {
+                return;
+            }
+            continue;
+        }
+        match pool.instantiate().await {
+            Ok(instance) => pool.release(instance),
+            // Retried the next time an instance is dropped
+            Err(_) => {
+                pool.warming.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+}
+
+async fn run_eviction_loop(pool: Weak<InstancePool>) {
+    let period = match pool.upgrade() {
+        Some(pool) => pool.config.idle_timeout.max(Duration::from_secs(1)),
+        None => return,
+    };
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        match pool.upgrade() {
+            Some(pool) => pool.evict_idle(),
+            None => return,
+        }
+    }
+}
+
+/// Handle to a shared pool. Many of these can exist at once, one per connection.
+pub(crate) struct PooledService {
+    pool: Arc<InstancePool>,
+}
+
+impl PooledService {
+    pub(crate) fn new(pool: Arc<InstancePool>) -> Self {
+        Self { pool }
+    }
+}
+
+impl Into<FunctionComponentService> for PooledService {
+    fn into(self) -> FunctionComponentService {
+        FunctionComponentService::new(self)
+    }
+}
+
+impl Service<FunctionRequest> for PooledService {
+    type Response = FunctionResponse;
+
+    type Error = BoxError;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        // Backpressure is applied by the pool's semaphore when the request is made
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: FunctionRequest) -> Self::Future {
+        let pool = self.pool.clone();
+        Box::pin(async move { pool.call(req).await })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{ready, Context, Poll};
+    use std::time::Duration;
+
+    use tokio::sync::{mpsc, oneshot};
+    use tower::util::{BoxCloneService, BoxService};
+    use tower::{service_fn, BoxError, Service};
+
+    use super::{InstancePool, PoolConfig};
+    use crate::streams::{collect_body, full_body};
+    use crate::types::{FunctionRequest, FunctionResponse, Method, RequestHead};
+
+    fn counting_producer(instantiations: Arc<AtomicUsize>) -> super::InstanceProducer {
+        BoxCloneService::new(service_fn(move |_: ()| {
+            let instantiations = instantiations.clone();
+            async move {
+                instantiations.fetch_add(1, Ordering::SeqCst);
+                let instance = service_fn(|req: FunctionRequest| async move {
+                    Ok::<_, BoxError>(FunctionResponse {
+                        status: 200,
+                        headers: vec![],
+                        body: req.body,
+                    })
+                });
+                Ok::<_, BoxError>(BoxService::new(instance))
+            }
+        }))
+    }
+
+    /// An instance whose invocations carry on after their response is returned,
+    /// like a guest streaming its body, until they're finished through `finishers`
+    struct StreamingInstance {
+        in_flight: Option<oneshot::Receiver<bool>>,
+        finishers: mpsc::UnboundedSender<oneshot::Sender<bool>>,
+    }
+
+    impl Service<FunctionRequest> for StreamingInstance {
+        type Response = FunctionResponse;
+        type Error = BoxError;
+        type Future = std::future::Ready<Result<FunctionResponse, BoxError>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            if let Some(in_flight) = &mut self.in_flight {
+                let healthy = ready!(Pin::new(in_flight).poll(cx)).unwrap_or(false);
+                self.in_flight = None;
+                if !healthy {
+                    return Poll::Ready(Err("unhealthy".into()));
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: FunctionRequest) -> Self::Future {
+            let (finish, in_flight) = oneshot::channel();
+            self.in_flight = Some(in_flight);
+            let _ = self.finishers.send(finish);
+            std::future::ready(Ok(FunctionResponse {
+                status: 200,
+                headers: vec![],
+                body: req.body,
+            }))
+        }
+    }
+
+    /// Waits for the pool's background warm up to settle on `idle` instances
+    async fn wait_for_idle(pool: &InstancePool, idle: usize) {
+        for _ in 0..100 {
+            if pool.idle_instances() == idle {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "expected {} idle instances, found {}",
+            idle,
+            pool.idle_instances()
+        );
+    }
+
+    fn request() -> FunctionRequest {
+        FunctionRequest {
+            head: RequestHead {
+                method: Method::Get,
+                headers: vec![],
+                uri: "foo".into(),
+            },
+            body: full_body(vec![1, 2, 3]),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_reuses_pooled_instances() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let config = PoolConfig {
+            min_instances: 0,
+            ..Default::default()
+        };
+        let pool = InstancePool::new(counting_producer(instantiations.clone()), config);
+
+        for _ in 0..5 {
+            let mut response = pool.call(request()).await.unwrap();
+            let body = collect_body(&mut response.body, usize::MAX).await.unwrap();
+            assert_eq!(body, vec![1, 2, 3]);
+        }
+
+        assert_eq!(instantiations.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_instances(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_recycles_instances_after_max_requests() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let config = PoolConfig {
+            min_instances: 0,
+            max_requests_per_instance: Some(2),
+            ..Default::default()
+        };
+        let pool = InstancePool::new(counting_producer(instantiations.clone()), config);
+
+        for _ in 0..4 {
+            pool.call(request()).await.unwrap();
+        }
+
+        assert_eq!(instantiations.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_evicts_idle_instances_above_min() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let config = PoolConfig {
+            min_instances: 0,
+            idle_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let pool = InstancePool::new(counting_producer(instantiations.clone()), config);
+
+        pool.call(request()).await.unwrap();
+        pool.evict_idle();
+
+        assert_eq!(instantiations.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.idle_instances(), 0);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_instances_checked_out_until_invocations_finish() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let (finishers_tx, mut finishers) = mpsc::unbounded_channel();
+        let producer = {
+            let instantiations = instantiations.clone();
+            BoxCloneService::new(service_fn(move |_: ()| {
+                instantiations.fetch_add(1, Ordering::SeqCst);
+                let instance = StreamingInstance {
+                    in_flight: None,
+                    finishers: finishers_tx.clone(),
+                };
+                async move { Ok::<_, BoxError>(BoxService::new(instance)) }
+            }))
+        };
+        let config = PoolConfig {
+            min_instances: 0,
+            max_instances: 1,
+            ..Default::default()
+        };
+        let pool = InstancePool::new(producer, config);
+
+        // The response is returned while the invocation carries on
+        pool.call(request()).await.unwrap();
+        let first = finishers.recv().await.unwrap();
+        assert_eq!(pool.idle_instances(), 0);
+
+        // So the next request waits for it rather than exceeding `max_instances`
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.call(request()).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+
+        first.send(true).unwrap();
+        second.await.unwrap().unwrap();
+        assert_eq!(instantiations.load(Ordering::SeqCst), 1);
+
+        // An instance which finished unhealthy isn't reused
+        finishers.recv().await.unwrap().send(false).unwrap();
+        pool.call(request()).await.unwrap();
+        assert_eq!(instantiations.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_instances_which_reject_a_request() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let producer = {
+            let instantiations = instantiations.clone();
+            BoxCloneService::new(service_fn(move |_: ()| {
+                instantiations.fetch_add(1, Ordering::SeqCst);
+                let instance = service_fn(|_: FunctionRequest| async move {
+                    Err::<FunctionResponse, BoxError>("request body too large".into())
+                });
+                async move { Ok::<_, BoxError>(BoxService::new(instance)) }
+            }))
+        };
+        let config = PoolConfig {
+            min_instances: 0,
+            ..Default::default()
+        };
+        let pool = InstancePool::new(producer, config);
+
+        // A request rejected by a still healthy instance doesn't cost the instance
+        for _ in 0..3 {
+            assert!(pool.call(request()).await.is_err());
+        }
+        assert_eq!(instantiations.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn it_replaces_dropped_instances_up_to_min() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let config = PoolConfig {
+            min_instances: 1,
+            max_requests_per_instance: Some(1),
+            ..Default::default()
+        };
+        let pool = InstancePool::new(counting_producer(instantiations.clone()), config);
+        wait_for_idle(&pool, 1).await;
+
+        // The warm instance is recycled after a single request and replaced
+        pool.call(request()).await.unwrap();
+        wait_for_idle(&pool, 1).await;
+        assert_eq!(instantiations.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.live_instances(), 1);
+    }
+
+    #[tokio::test]
+    async fn it_clamps_min_instances_to_max() {
+        let instantiations = Arc::new(AtomicUsize::new(0));
+        let config = PoolConfig {
+            min_instances: 4,
+            max_instances: 2,
+            ..Default::default()
+        };
+        let pool = InstancePool::new(counting_producer(instantiations.clone()), config);
+        wait_for_idle(&pool, 2).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(instantiations.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.live_instances(), 2);
+    }
+}
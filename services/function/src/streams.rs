@@ -0,0 +1,268 @@
+//! Streamed request and response bodies.
+//!
+//! Bodies move between the host and a function component as a channel of chunks rather than a
+//! single buffered `list<u8>`. Components built against `streaming-function-world` read and
+//! write those chunks directly through the `incoming-body` and `response-outparam` resources.
+//! Components built against `function-world` still receive a buffered body, collected by the host
+//! up to `InvocationLimits::max_buffered_body_bytes`.
+//!
+//! # Notes
+//! - Body channels are bounded. A slow client applies backpressure all the way into the guest's
+//! `write` call.
+//! - An `Err` chunk marks a body which was cut short, hosts should abort the connection rather
+//! than ending the body cleanly.
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+use tower::BoxError;
+use wasmtime::component::Resource;
+use wasmtime_components::runtime_view::RuntimeView;
+use wasmtime_wasi::preview2::{Table, WasiView};
+
+use crate::service::InvocationError;
+use crate::streaming_bindgen::mycelia::execution::streams::{
+    self, Headers, IncomingBody, ResponseOutparam, Status,
+};
+use crate::types::{HttpResponse, RequestHead};
+
+/// Chunks buffered between a body producer and its consumer
+const BODY_CHANNEL_CAPACITY: usize = 16;
+
+pub type BodyChunk = Result<Vec<u8>, BoxError>;
+pub type BodySender = mpsc::Sender<BodyChunk>;
+pub type BodyReceiver = mpsc::Receiver<BodyChunk>;
+
+pub fn body_channel() -> (BodySender, BodyReceiver) {
+    mpsc::channel(BODY_CHANNEL_CAPACITY)
+}
+
+/// A body made of a single, already buffered, chunk
+pub fn full_body(body: Vec<u8>) -> BodyReceiver {
+    let (tx, rx) = body_channel();
+    if !body.is_empty() {
+        // The channel is empty, this can't fail
+        let _ = tx.try_send(Ok(body));
+    }
+    rx
+}
+
+/// Buffers an entire body, failing with `InvocationError::BodyTooLarge` past `limit` bytes
+pub async fn collect_body(body: &mut BodyReceiver, limit: usize) -> Result<Vec<u8>, BoxError> {
+    let mut buffer = vec![];
+    while let Some(chunk) = body.recv().await {
+        let chunk = chunk?;
+        if buffer.len() + chunk.len() > limit {
+            return Err(InvocationError::BodyTooLarge { limit }.into());
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(buffer)
+}
+
+/// A request handed to a function component
+pub struct FunctionRequest {
+    pub head: RequestHead,
+    pub body: BodyReceiver,
+}
+
+/// A response produced by a function component.
+/// The body may still be streaming when this is returned
+pub struct FunctionResponse {
+    pub status: Status,
+    pub headers: Headers,
+    pub body: BodyReceiver,
+}
+
+impl From<HttpResponse> for FunctionResponse {
+    fn from(response: HttpResponse) -> Self {
+        Self {
+            status: response.status,
+            headers: response.headers,
+            body: full_body(response.body),
+        }
+    }
+}
+
+pub(crate) type ResponseSender = oneshot::Sender<Result<FunctionResponse, BoxError>>;
+
+/// Host side state of an `incoming-body` resource
+struct IncomingBodyState {
+    body: BodyReceiver,
+    // Left over from a chunk larger than the guest asked for
+    pending: Vec<u8>,
+}
+
+/// Host side state of a `response-outparam` resource
+struct ResponseOutparamState {
+    // Taken once the guest starts the response
+    reply: Option<ResponseSender>,
+    body: Option<BodySender>,
+}
+
+impl ResponseOutparamState {
+    /// Completes the response. `error` is reported to the caller if the response hasn't
+    /// started yet, otherwise it is pushed into the body so the connection is aborted.
+    fn finish(self, error: Option<BoxError>) {
+        match (self.reply, self.body, error) {
+            (Some(reply), _, error) => {
+                let error = error.unwrap_or_else(|| InvocationError::MissingResponse.into());
+                let _ = reply.send(Err(error));
+            }
+            (None, Some(body), Some(error)) => {
+                // The body may be full, don't hold up the instance waiting on the client
+                tokio::spawn(async move {
+                    let _ = body.send(Err(error)).await;
+                });
+            }
+            // Dropping the sender ends the body
+            _ => {}
+        }
+    }
+}
+
+/// Moves a request body and the response channel into the store's resource table
+pub(crate) fn push_resources(
+    table: &mut Table,
+    body: BodyReceiver,
+    reply: ResponseSender,
+) -> anyhow::Result<(Resource<IncomingBody>, Resource<ResponseOutparam>)> {
+    let body = table.push(Box::new(IncomingBodyState {
+        body,
+        pending: vec![],
+    }))?;
+    let response = table.push(Box::new(ResponseOutparamState {
+        reply: Some(reply),
+        body: None,
+    }))?;
+    Ok((Resource::new_own(body), Resource::new_own(response)))
+}
+
+/// Releases any resources the guest didn't drop itself and completes the response
+pub(crate) fn finish_resources(
+    table: &mut Table,
+    body: u32,
+    response: u32,
+    error: Option<BoxError>,
+) {
+    let _ = table.delete::<IncomingBodyState>(body);
+    // Otherwise the guest already dropped the response and it is complete
+    if let Ok(state) = table.delete::<ResponseOutparamState>(response) {
+        state.finish(error);
+    }
+}
+
+#[async_trait]
+impl streams::HostIncomingBody for RuntimeView {
+    async fn read(
+        &mut self,
+        guest_self: Resource<IncomingBody>,
+        max_bytes: u32,
+    ) -> anyhow::Result<Result<Option<Vec<u8>>, String>> {
+        let state = self
+            .table_mut()
+            .get_mut::<IncomingBodyState>(guest_self.rep())?;
+
+        if state.pending.is_empty() {
+            match state.body.recv().await {
+                Some(Ok(chunk)) => state.pending = chunk,
+                Some(Err(e)) => return Ok(Err(e.to_string())),
+                None => return Ok(Ok(None)),
+            }
+        }
+
+        let len = state.pending.len().min(max_bytes.max(1) as usize);
+        let rest = state.pending.split_off(len);
+        let chunk = std::mem::replace(&mut state.pending, rest);
+
+        Ok(Ok(Some(chunk)))
+    }
+
+    fn drop(&mut self, val: Resource<IncomingBody>) -> anyhow::Result<()> {
+        self.table_mut().delete::<IncomingBodyState>(val.rep())?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl streams::HostResponseOutparam for RuntimeView {
+    async fn start(
+        &mut self,
+        guest_self: Resource<ResponseOutparam>,
+        status: Status,
+        headers: Headers,
+    ) -> anyhow::Result<Result<(), String>> {
+        let state = self
+            .table_mut()
+            .get_mut::<ResponseOutparamState>(guest_self.rep())?;
+
+        let Some(reply) = state.reply.take() else {
+            return Ok(Err("response already started".to_string()));
+        };
+
+        let (tx, rx) = body_channel();
+        state.body = Some(tx);
+
+        let response = FunctionResponse {
+            status,
+            headers,
+            body: rx,
+        };
+
+        match reply.send(Ok(response)) {
+            Ok(_) => Ok(Ok(())),
+            Err(_) => Ok(Err("client disconnected".to_string())),
+        }
+    }
+
+    async fn write(
+        &mut self,
+        guest_self: Resource<ResponseOutparam>,
+        chunk: Vec<u8>,
+    ) -> anyhow::Result<Result<(), String>> {
+        let state = self
+            .table_mut()
+            .get_mut::<ResponseOutparamState>(guest_self.rep())?;
+
+        let Some(body) = state.body.clone() else {
+            return Ok(Err("response not started".to_string()));
+        };
+
+        match body.send(Ok(chunk)).await {
+            Ok(_) => Ok(Ok(())),
+            Err(_) => Ok(Err("client disconnected".to_string())),
+        }
+    }
+
+    fn drop(&mut self, val: Resource<ResponseOutparam>) -> anyhow::Result<()> {
+        self.table_mut()
+            .delete::<ResponseOutparamState>(val.rep())?
+            .finish(None);
+        Ok(())
+    }
+}
+
+impl streams::Host for RuntimeView {}
+impl crate::bindgen::mycelia::execution::types::Host for RuntimeView {}
+
+#[cfg(test)]
+mod test {
+    use super::{body_channel, collect_body, full_body};
+    use crate::service::InvocationError;
+
+    #[tokio::test]
+    async fn it_collects_bodies_up_to_the_limit() {
+        let mut body = full_body(vec![1, 2, 3]);
+        assert_eq!(collect_body(&mut body, 3).await.unwrap(), vec![1, 2, 3]);
+
+        let (tx, mut body) = body_channel();
+        tx.send(Ok(vec![1, 2])).await.unwrap();
+        tx.send(Ok(vec![3, 4])).await.unwrap();
+        drop(tx);
+
+        let e = collect_body(&mut body, 3).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<InvocationError>(),
+            Some(InvocationError::BodyTooLarge { limit: 3 })
+        ));
+    }
+}
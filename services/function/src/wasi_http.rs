@@ -0,0 +1,188 @@
+//! Support for components exporting `wasi:http/incoming-handler`.
+//!
+//! Requests and responses are mapped between the host's `FunctionRequest` / `FunctionResponse`
+//! and the hyper types `wasmtime_wasi_http` hands to the guest, so off the shelf wasi-http
+//! components are served exactly like components built against `function-world`.
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http_body::{Body, Frame};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use tokio::sync::oneshot;
+use tower::BoxError;
+use wasmtime::Store;
+use wasmtime_components::runtime_view::RuntimeView;
+use wasmtime_wasi_http::body::{HyperIncomingBody, HyperOutgoingBody};
+use wasmtime_wasi_http::proxy::Proxy;
+use wasmtime_wasi_http::WasiHttpView;
+
+use resource_providers::http::map_headers;
+
+use crate::headers::to_header_map;
+use crate::service::InvocationError;
+use crate::streams::{
+    body_channel, BodyReceiver, FunctionRequest, FunctionResponse, ResponseSender,
+};
+use crate::types::{Method, RequestHead};
+
+/// Adapts a host body channel to the body type wasi-http expects
+struct ChannelBody(BodyReceiver);
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = anyhow::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.0.poll_recv(cx).map(|chunk| {
+            chunk.map(|chunk| {
+                chunk
+                    .map(|chunk| Frame::data(chunk.into()))
+                    .map_err(|e| anyhow::anyhow!(e))
+            })
+        })
+    }
+}
+
+fn map_method(method: Method) -> Result<http::Method, BoxError> {
+    let method = match method {
+        Method::Get => http::Method::GET,
+        Method::Head => http::Method::HEAD,
+        Method::Post => http::Method::POST,
+        Method::Put => http::Method::PUT,
+        Method::Delete => http::Method::DELETE,
+        Method::Connect => http::Method::CONNECT,
+        Method::Options => http::Method::OPTIONS,
+        Method::Trace => http::Method::TRACE,
+        Method::Patch => http::Method::PATCH,
+        Method::Other(method) => http::Method::from_bytes(method.as_bytes())?,
+    };
+    Ok(method)
+}
+
+fn map_request(
+    head: RequestHead,
+    body: BodyReceiver,
+) -> Result<http::Request<HyperIncomingBody>, BoxError> {
+    let mut request = http::Request::builder()
+        .method(map_method(head.method)?)
+        .uri(head.uri)
+        .body(BoxBody::new(ChannelBody(body)))?;
+    *request.headers_mut() = to_header_map(head.headers)?;
+
+    Ok(request)
+}
+
+/// Forwards the guest's response to the caller, streaming its body as it is written
+async fn forward_response(response: http::Response<HyperOutgoingBody>, reply: ResponseSender) {
+    let (parts, mut body) = response.into_parts();
+    let headers = map_headers(&parts.headers);
+
+    let (tx, rx) = body_channel();
+    let response = FunctionResponse {
+        status: parts.status.as_u16(),
+        headers,
+        body: rx,
+    };
+
+    if reply.send(Ok(response)).is_err() {
+        return;
+    }
+
+    while let Some(frame) = body.frame().await {
+        let chunk = match frame {
+            // Trailers aren't supported yet
+            Ok(frame) => match frame.into_data() {
+                Ok(data) => Ok(data.to_vec()),
+                Err(_) => continue,
+            },
+            Err(e) => Err(BoxError::from(format!("{:?}", e))),
+        };
+        let failed = chunk.is_err();
+        // The client went away
+        if tx.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Invokes a component exporting `wasi:http/incoming-handler`.
+/// Returns false if the instance can no longer be trusted to serve requests
+pub(crate) async fn invoke(
+    bindings: &Proxy,
+    store: &mut Store<RuntimeView>,
+    request: FunctionRequest,
+    reply: ResponseSender,
+    timeout: Option<std::time::Duration>,
+) -> bool {
+    let FunctionRequest { head, body } = request;
+
+    let request = match map_request(head, body) {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = reply.send(Err(e));
+            return true;
+        }
+    };
+
+    // The guest may set its response at any point during the call, or not at all.
+    // Whichever side finishes first answers the caller.
+    let reply = Arc::new(Mutex::new(Some(reply)));
+    let (response_tx, response_rx) = oneshot::channel();
+    let forwarder_reply = reply.clone();
+    tokio::spawn(async move {
+        let response = response_rx.await;
+        let Some(reply) = forwarder_reply.lock().expect("reply lock poisoned").take() else {
+            return;
+        };
+        match response {
+            Ok(Ok(response)) => forward_response(response, reply).await,
+            Ok(Err(e)) => {
+                let _ = reply.send(Err(BoxError::from(format!("{:?}", e))));
+            }
+            Err(_) => {
+                let _ = reply.send(Err(InvocationError::MissingResponse.into()));
+            }
+        }
+    });
+
+    let resources = store
+        .data_mut()
+        .new_incoming_request(request)
+        .and_then(|request| {
+            let response = store.data_mut().new_response_outparam(response_tx)?;
+            Ok((request, response))
+        });
+
+    let error: Option<BoxError> = match resources {
+        Ok((request, response)) => {
+            let invocation =
+                bindings
+                    .wasi_http_incoming_handler()
+                    .call_handle(&mut *store, request, response);
+            match crate::service::with_timeout(timeout, invocation).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(InvocationError::from_call_error(e)),
+                Err(e) => Some(e.into()),
+            }
+        }
+        Err(e) => Some(e.into()),
+    };
+
+    match error {
+        Some(e) => {
+            if let Some(reply) = reply.lock().expect("reply lock poisoned").take() {
+                let _ = reply.send(Err(e));
+            }
+            // The response outparam may still be held by the store, drop the instance
+            // so the forwarder is released.
+            false
+        }
+        None => true,
+    }
+}
@@ -1,22 +1,327 @@
+pub mod limits {
+    //! Resource limits applied to guest stores.
+    //!
+    //! Without a limiter a guest can grow its linear memory until the host runs out.
+    //! Growth past a limit traps the guest with a `LimitExceeded` error which can be
+    //! recovered from the returned `anyhow::Error` using `downcast_ref`.
+
+    use thiserror::Error;
+    use wasmtime::ResourceLimiter;
+
+    /// Limits for a single guest store
+    #[derive(Debug, Clone)]
+    pub struct GuestLimits {
+        /// Max size in bytes of any single linear memory
+        pub max_memory_bytes: usize,
+        /// Max elements of any single table
+        pub max_table_elements: u32,
+        /// Max core instances. Note a single component instantiates several core instances
+        pub max_instances: usize,
+        pub max_tables: usize,
+        pub max_memories: usize,
+        /// Largest `wasi:http` request body the host buffers for a guest, larger ones fail to send
+        pub max_outgoing_body_bytes: usize,
+    }
+
+    impl Default for GuestLimits {
+        fn default() -> Self {
+            Self {
+                max_memory_bytes: 256 * 1024 * 1024,
+                max_table_elements: 100_000,
+                max_instances: 1_000,
+                max_tables: 1_000,
+                max_memories: 100,
+                max_outgoing_body_bytes: 5 * 1024 * 1024,
+            }
+        }
+    }
+
+    #[derive(Error, Debug, Clone)]
+    /// A guest attempted to grow past one of its `GuestLimits`
+    pub enum LimitExceeded {
+        #[error("guest attempted to grow memory to {desired} bytes. limit {limit}")]
+        Memory { desired: usize, limit: usize },
+        #[error("guest attempted to grow a table to {desired} elements. limit {limit}")]
+        Table { desired: u32, limit: u32 },
+    }
+
+    /// `ResourceLimiter` enforcing `GuestLimits` on a store
+    pub struct GuestLimiter {
+        limits: GuestLimits,
+    }
+
+    impl GuestLimiter {
+        pub fn new(limits: GuestLimits) -> Self {
+            Self { limits }
+        }
+    }
+
+    impl ResourceLimiter for GuestLimiter {
+        fn memory_growing(
+            &mut self,
+            _current: usize,
+            desired: usize,
+            _maximum: Option<usize>,
+        ) -> anyhow::Result<bool> {
+            if desired > self.limits.max_memory_bytes {
+                return Err(LimitExceeded::Memory {
+                    desired,
+                    limit: self.limits.max_memory_bytes,
+                }
+                .into());
+            }
+            Ok(true)
+        }
+
+        fn table_growing(
+            &mut self,
+            _current: u32,
+            desired: u32,
+            _maximum: Option<u32>,
+        ) -> anyhow::Result<bool> {
+            if desired > self.limits.max_table_elements {
+                return Err(LimitExceeded::Table {
+                    desired,
+                    limit: self.limits.max_table_elements,
+                }
+                .into());
+            }
+            Ok(true)
+        }
+
+        fn instances(&self) -> usize {
+            self.limits.max_instances
+        }
+
+        fn tables(&self) -> usize {
+            self.limits.max_tables
+        }
+
+        fn memories(&self) -> usize {
+            self.limits.max_memories
+        }
+    }
+}
+
 pub mod runtime_view {
-    use wasmtime_wasi::preview2::{Table, WasiCtx, WasiCtxBuilder, WasiView};
+    use std::collections::BTreeSet;
+    use std::sync::Arc;
+
+    use lazy_static::lazy_static;
+    use resource_providers::blob::{
+        unavailable_container_maker, HostBlobContainerMaker, HostBlobResource,
+    };
+    use resource_providers::config::{ConfigValues, HostConfig, HostConfigMaker};
+    use resource_providers::core::{HostResourceMaker, ResourceIdPool};
+    use resource_providers::http::{
+        unavailable_client_maker, HostClientMaker, HostClientResource, HostClientResourceMaker,
+        HostStreamingClientMaker,
+    };
+    use resource_providers::kv::{unavailable_store_maker, HostKvResource, HostKvStoreMaker};
+    use resource_providers::logging::{
+        GuestLogContext, GuestOutput, GuestStream, HostLogging, HostLoggingMaker,
+    };
+    use resource_providers::providers::http_client_hyper::{
+        new_client_maker, new_shared_client, new_streaming_client_maker, HyperClientConfig,
+        SharedHyperClient,
+    };
+    use resource_providers::providers::secrets_file::SecretsFile;
+    use resource_providers::secrets::{HostSecretProvider, HostSecrets, HostSecretsMaker};
+    use resource_providers::sql::{
+        unavailable_connection_maker, HostSqlConnectionMaker, HostSqlResource,
+    };
+    use resource_providers::wasi_http::OutgoingHttp;
+    use wasmtime::component::Resource;
+    use wasmtime_wasi::preview2::pipe::AsyncWriteStream;
+    use wasmtime_wasi::preview2::{IsATTY, Table, WasiCtx, WasiCtxBuilder, WasiView};
+    use wasmtime_wasi_http::types::{HostFutureIncomingResponse, OutgoingRequest};
+    use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
+
+    use crate::limits::{GuestLimiter, GuestLimits};
+
+    /// Key value stores a guest may hold open at once
+    pub const MAX_OPEN_KV_STORES: usize = 16;
+
+    /// Database connections and prepared statements a guest may hold open at once
+    pub const MAX_OPEN_SQL_RESOURCES: usize = 64;
+
+    /// Object storage containers a guest may hold open at once
+    pub const MAX_OPEN_BLOB_CONTAINERS: usize = 16;
+
+    /// Http clients and streamed response bodies a guest may hold open at once
+    pub const MAX_OPEN_HTTP_RESOURCES: usize = 64;
+
+    /// Bytes a guest may write to stdout or stderr before waiting on them to be logged
+    const GUEST_OUTPUT_BUDGET: usize = 64 * 1024;
+
+    /// Deploy time settings a guest can read
+    #[derive(Debug, Clone, Default)]
+    pub struct GuestEnvironment {
+        /// The guest's first argument
+        pub name: String,
+        /// Environment variables the guest sees through wasi
+        pub env: Vec<(String, String)>,
+        /// Values the guest reads with `mycelia-alpha:config`
+        pub config: ConfigValues,
+        /// Where the guest's `mycelia-alpha:secrets` are resolved from
+        pub secrets: Option<SecretsFile>,
+        /// Names of the secrets the guest may read
+        pub granted_secrets: Arc<BTreeSet<String>>,
+    }
+
+    lazy_static! {
+        /// Used by views which aren't given a client, so they share its connections
+        static ref DEFAULT_HTTP_CLIENT: SharedHyperClient =
+            new_shared_client(&HyperClientConfig::default());
+    }
 
     // In the future this will be where we provide guests
     // access to resources.
     pub struct RuntimeView {
         table: Table,
         ctx: WasiCtx,
+        http: WasiHttpCtx,
+        outgoing_http: OutgoingHttp,
+        http_client: HostClientResource,
+        kv: HostKvResource,
+        sql: HostSqlResource,
+        blob: HostBlobResource,
+        config: HostConfig,
+        secrets: HostSecrets,
+        logging: HostLogging,
+        limiter: GuestLimiter,
     }
 
     impl RuntimeView {
         pub fn new() -> Self {
+            Self::with_limits(GuestLimits::default())
+        }
+
+        /// Uses the default http client, see `with_http_client` to configure it
+        pub fn with_limits(limits: GuestLimits) -> Self {
+            let client = DEFAULT_HTTP_CLIENT.clone();
+            Self::with_http_client(limits, new_client_maker(client.clone()))
+                .with_http_clients(new_client_maker(client.clone()))
+                .with_streaming_http_clients(new_streaming_client_maker(client))
+        }
+
+        /// Outbound `wasi:http/outgoing-handler` requests are made with clients
+        /// produced by `client_maker`. See `with_http_clients` for `mycelia-alpha:http`
+        pub fn with_http_client(limits: GuestLimits, client_maker: HostClientMaker) -> Self {
+            Self::with_environment(limits, client_maker, &GuestEnvironment::default())
+        }
+
+        /// Like `with_http_client`, with the guest's arguments, environment variables
+        /// and configuration taken from `environment`
+        pub fn with_environment(
+            limits: GuestLimits,
+            client_maker: HostClientMaker,
+            environment: &GuestEnvironment,
+        ) -> Self {
             let mut table = Table::new();
+            // Printed lines are logged by the host rather than interleaved with its own output
+            let log_context = GuestLogContext::new(&environment.name);
+            let stdout = GuestOutput::new(log_context.clone(), GuestStream::Stdout);
+            let stderr = GuestOutput::new(log_context.clone(), GuestStream::Stderr);
             let ctx = WasiCtxBuilder::new()
-                .inherit_stdio()
+                .stdout(
+                    AsyncWriteStream::new(GUEST_OUTPUT_BUDGET, stdout),
+                    IsATTY::No,
+                )
+                .stderr(
+                    AsyncWriteStream::new(GUEST_OUTPUT_BUDGET, stderr),
+                    IsATTY::No,
+                )
+                .args(&[&environment.name])
+                .envs(&environment.env)
                 .build(&mut table)
                 .unwrap();
 
-            Self { table, ctx }
+            Self {
+                table,
+                ctx,
+                http: WasiHttpCtx {},
+                outgoing_http: OutgoingHttp::new(client_maker, limits.max_outgoing_body_bytes),
+                http_client: HostClientResource::with_id_pool(
+                    unavailable_client_maker(),
+                    ResourceIdPool::new(MAX_OPEN_HTTP_RESOURCES),
+                ),
+                kv: HostKvResource::with_id_pool(
+                    unavailable_store_maker(),
+                    ResourceIdPool::new(MAX_OPEN_KV_STORES),
+                ),
+                sql: HostSqlResource::with_id_pool(
+                    unavailable_connection_maker(),
+                    ResourceIdPool::new(MAX_OPEN_SQL_RESOURCES),
+                ),
+                blob: HostBlobResource::with_id_pool(
+                    unavailable_container_maker(),
+                    ResourceIdPool::new(MAX_OPEN_BLOB_CONTAINERS),
+                ),
+                config: HostConfig::new(environment.config.clone()),
+                secrets: HostSecrets::default(),
+                logging: HostLogging::new(log_context),
+                limiter: GuestLimiter::new(limits),
+            }
+        }
+
+        /// Guests creating a `mycelia-alpha:http` client get one from `client_maker`.
+        /// Without one, creating a client traps the guest
+        pub fn with_http_clients(mut self, client_maker: HostClientMaker) -> Self {
+            self.http_client.clients.maker = client_maker;
+            self
+        }
+
+        /// Responses guests stream with `send-streaming` are sent with clients from
+        /// `client_maker`. Without one, they're buffered first by the regular client
+        pub fn with_streaming_http_clients(
+            mut self,
+            client_maker: HostStreamingClientMaker,
+        ) -> Self {
+            self.http_client.streaming_client_maker = Some(client_maker);
+            self
+        }
+
+        /// Guests opening a `mycelia-alpha:kv` store get one from `store_maker`.
+        /// Without one, opening a store traps the guest
+        pub fn with_kv(mut self, store_maker: HostKvStoreMaker) -> Self {
+            self.kv.maker = store_maker;
+            self
+        }
+
+        /// Guests connecting to a `mycelia-alpha:sql` database are connected with `connection_maker`.
+        /// Without one, connecting traps the guest
+        pub fn with_sql(mut self, connection_maker: HostSqlConnectionMaker) -> Self {
+            self.sql.connections.maker = connection_maker;
+            self
+        }
+
+        /// Guests opening a `mycelia-alpha:blob` container get one from `container_maker`.
+        /// Without one, opening a container traps the guest
+        pub fn with_blob(mut self, container_maker: HostBlobContainerMaker) -> Self {
+            self.blob.maker = container_maker;
+            self
+        }
+
+        /// Guests reading `mycelia-alpha:secrets` get the secrets in `granted` from `provider`.
+        /// Without one, reading a secret returns an error to the guest
+        pub fn with_secrets(
+            mut self,
+            provider: HostSecretProvider,
+            granted: Arc<BTreeSet<String>>,
+        ) -> Self {
+            self.secrets = HostSecrets::new(provider, granted);
+            self
+        }
+
+        /// Guest logs and printed lines from now on are tagged with `request_id`
+        pub fn set_request_id(&self, request_id: Option<String>) {
+            self.logging.context.set_request_id(request_id);
+        }
+
+        /// The limiter for this view. Register it with `Store::limiter`
+        pub fn limiter(&mut self) -> &mut GuestLimiter {
+            &mut self.limiter
         }
     }
 
@@ -37,28 +342,404 @@ pub mod runtime_view {
             &mut self.ctx
         }
     }
+
+    impl HostClientResourceMaker for RuntimeView {
+        fn http_client(&mut self) -> &mut HostClientResource {
+            &mut self.http_client
+        }
+    }
+
+    impl HostResourceMaker<HostKvResource> for RuntimeView {
+        fn host_resource(&mut self) -> &mut HostKvResource {
+            &mut self.kv
+        }
+    }
+
+    impl HostResourceMaker<HostSqlResource> for RuntimeView {
+        fn host_resource(&mut self) -> &mut HostSqlResource {
+            &mut self.sql
+        }
+    }
+
+    impl HostResourceMaker<HostBlobResource> for RuntimeView {
+        fn host_resource(&mut self) -> &mut HostBlobResource {
+            &mut self.blob
+        }
+    }
+
+    impl HostConfigMaker for RuntimeView {
+        fn config(&mut self) -> &mut HostConfig {
+            &mut self.config
+        }
+    }
+
+    impl HostLoggingMaker for RuntimeView {
+        fn logging(&mut self) -> &mut HostLogging {
+            &mut self.logging
+        }
+    }
+
+    impl HostSecretsMaker for RuntimeView {
+        fn secrets(&mut self) -> &mut HostSecrets {
+            &mut self.secrets
+        }
+    }
+
+    impl WasiHttpView for RuntimeView {
+        fn ctx(&mut self) -> &mut WasiHttpCtx {
+            &mut self.http
+        }
+
+        fn table(&mut self) -> &mut Table {
+            &mut self.table
+        }
+
+        fn send_request(
+            &mut self,
+            request: OutgoingRequest,
+        ) -> wasmtime::Result<Resource<HostFutureIncomingResponse>> {
+            self.outgoing_http.send_request(&mut self.table, request)
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::sync::Mutex;
+
+        use resource_providers::logging::GUEST_STDOUT_TARGET;
+        use wasmtime_wasi::preview2::bindings::cli::stdout;
+        use wasmtime_wasi::preview2::bindings::io::streams::HostOutputStream;
+
+        use super::*;
+
+        /// Lines guests printed, from every test in this crate
+        static PRINTED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        struct CapturingLogger;
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                if record.target() == GUEST_STDOUT_TARGET {
+                    PRINTED.lock().unwrap().push(record.args().to_string());
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger;
+
+        /// Writes `text` to the guest's stdout the way a guest would
+        async fn print(view: &mut RuntimeView, text: &str) {
+            let stdout = stdout::Host::get_stdout(view).unwrap();
+            HostOutputStream::blocking_write_and_flush(view, stdout, text.as_bytes().to_vec())
+                .await
+                .unwrap();
+        }
+
+        #[tokio::test]
+        async fn it_tags_printed_lines_with_their_request() {
+            let _ = log::set_logger(&LOGGER);
+            log::set_max_level(log::LevelFilter::Info);
+            let environment = GuestEnvironment {
+                name: "checkout".to_string(),
+                ..Default::default()
+            };
+            let mut view = RuntimeView::with_environment(
+                GuestLimits::default(),
+                unavailable_client_maker(),
+                &environment,
+            );
+
+            view.set_request_id(Some("req-1".to_string()));
+            print(&mut view, "first\nunfinished").await;
+            view.set_request_id(Some("req-2".to_string()));
+            print(&mut view, "second\n").await;
+
+            let printed: Vec<_> = PRINTED
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|line| line.starts_with("[checkout "))
+                .cloned()
+                .collect();
+            // The line left unfinished by the first request isn't joined with the second's
+            assert_eq!(
+                printed,
+                [
+                    "[checkout req-1] first",
+                    "[checkout req-1] unfinished",
+                    "[checkout req-2] second"
+                ]
+            );
+        }
+    }
+}
+
+pub mod component_cache {
+    //! On-disk cache of precompiled components.
+    //!
+    //! Compiling a component is the most expensive part of a deploy. Artifacts are keyed by the
+    //! sha256 of the component bytes and the engine's compatibility hash, so a restarted
+    //! development server or a redeploy of an unchanged component skips compilation.
+    //! Artifacts produced by a differently configured engine or wasmtime version are never reused.
+    //!
+    //! Loading an artifact runs it as native code, so the cache lives in a per-user directory
+    //! only its owner can write to. An artifact is only loaded if it, and every directory
+    //! between it and the cache root, is owned by the current user and writable by no one else.
+
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::path::{Path, PathBuf};
+
+    use sha2::{Digest, Sha256};
+    use wasmtime::{component::Component, Engine};
+
+    /// Overrides the default cache directory
+    pub const CACHE_DIR_ENV: &str = "MYCELIA_COMPONENT_CACHE_DIR";
+
+    pub struct ComponentCache {
+        dir: PathBuf,
+    }
+
+    impl ComponentCache {
+        pub fn new(dir: PathBuf) -> Self {
+            Self { dir }
+        }
+
+        /// Uses `$MYCELIA_COMPONENT_CACHE_DIR` if set, otherwise the current user's cache directory
+        pub fn from_env() -> Self {
+            let dir = std::env::var_os(CACHE_DIR_ENV)
+                .map(PathBuf::from)
+                .or_else(|| dirs::cache_dir().map(|dir| dir.join("mycelia").join("components")))
+                // Without a home directory fall back to a name no other user shares,
+                // it's only trusted if we created it
+                .unwrap_or_else(|| {
+                    std::env::temp_dir().join(format!("mycelia-components-{}", user_id()))
+                });
+            Self::new(dir)
+        }
+
+        fn artifact_path(&self, engine: &Engine, bytes: &[u8]) -> PathBuf {
+            let mut hasher = DefaultHasher::new();
+            engine.precompile_compatibility_hash().hash(&mut hasher);
+            let engine_hash = hasher.finish();
+
+            let component_hash = Sha256::digest(bytes);
+
+            self.dir
+                .join(format!("{:016x}", engine_hash))
+                .join(format!("{:x}.cwasm", component_hash))
+        }
+
+        /// Loads the precompiled artifact for `bytes`, compiling and storing it on a cache miss.
+        ///
+        /// Failing to read or write the cache is never fatal, we fall back to compiling.
+        pub fn load(&self, engine: &Engine, bytes: &[u8]) -> anyhow::Result<Component> {
+            let path = self.artifact_path(engine, bytes);
+
+            if path.is_file() && self.is_trusted(&path) {
+                // Safety: the artifact and the directories holding it can only have been
+                // written by this user, and artifacts are only ever written by `store` below
+                // using an engine with the same compatibility hash.
+                match unsafe { Component::deserialize_file(engine, &path) } {
+                    Ok(component) => return Ok(component),
+                    Err(_) => {
+                        let _ = std::fs::remove_file(&path);
+                    }
+                }
+            }
+
+            let artifact = engine.precompile_component(bytes)?;
+            let _ = self.store(&path, &artifact);
+
+            // Safety: `artifact` was just produced by `engine`
+            unsafe { Component::deserialize(engine, &artifact) }
+        }
+
+        fn store(&self, path: &Path, artifact: &[u8]) -> std::io::Result<()> {
+            let Some(parent) = path.parent() else {
+                return Ok(());
+            };
+            create_private_dir(parent)?;
+            if !self.is_trusted(parent) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("{} is writable by other users", parent.display()),
+                ));
+            }
+            // Write then rename so concurrent readers never see a partial artifact
+            let tmp_path = path.with_extension(format!("cwasm.{}.tmp", std::process::id()));
+            write_private_file(&tmp_path, artifact)?;
+            std::fs::rename(&tmp_path, path)
+        }
+
+        /// Whether `path` and every directory from it up to the cache root
+        /// can only have been written by the current user
+        fn is_trusted(&self, path: &Path) -> bool {
+            let Ok(relative) = path.strip_prefix(&self.dir) else {
+                return false;
+            };
+            let mut checked = self.dir.clone();
+            if !is_private(&checked) {
+                return false;
+            }
+            for part in relative.components() {
+                checked.push(part);
+                if !is_private(&checked) {
+                    return false;
+                }
+            }
+            true
+        }
+    }
+
+    #[cfg(unix)]
+    fn user_id() -> u32 {
+        // Safety: geteuid has no preconditions and can't fail
+        unsafe { libc::geteuid() }
+    }
+
+    #[cfg(not(unix))]
+    fn user_id() -> u32 {
+        0
+    }
+
+    /// Owned by the current user and not writable by anyone else.
+    /// Symlinks aren't followed, a link planted in the cache is never trusted
+    #[cfg(unix)]
+    fn is_private(path: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                !metadata.file_type().is_symlink()
+                    && metadata.uid() == user_id()
+                    && metadata.mode() & 0o022 == 0
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn is_private(path: &Path) -> bool {
+        std::fs::symlink_metadata(path)
+            .map(|metadata| !metadata.file_type().is_symlink())
+            .unwrap_or(false)
+    }
+
+    fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(dir)
+    }
+
+    fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(path)?.write_all(contents)
+    }
+
+    #[cfg(all(test, unix))]
+    mod test {
+        use std::os::unix::fs::PermissionsExt;
+
+        use super::*;
+
+        #[test]
+        fn it_only_trusts_private_directories() {
+            let root = tempfile::tempdir().unwrap();
+            let cache = ComponentCache::new(root.path().join("components"));
+            let engine_dir = root.path().join("components").join("engine");
+            create_private_dir(&engine_dir).unwrap();
+            let artifact = engine_dir.join("artifact.cwasm");
+            write_private_file(&artifact, b"artifact").unwrap();
+            assert!(cache.is_trusted(&artifact));
+
+            // Anyone could have replaced an artifact in a shared directory
+            std::fs::set_permissions(&engine_dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+            assert!(!cache.is_trusted(&artifact));
+
+            std::fs::set_permissions(&engine_dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+            std::fs::set_permissions(&artifact, std::fs::Permissions::from_mode(0o666)).unwrap();
+            assert!(!cache.is_trusted(&artifact));
+
+            // Paths outside of the cache are never trusted
+            assert!(!cache.is_trusted(root.path()));
+        }
+    }
 }
 
 pub mod runtime {
     use std::path::PathBuf;
+    use std::time::Duration;
 
     use lazy_static::lazy_static;
     use tower::{service_fn, util::BoxCloneService, BoxError};
     use wasmtime::{
-        component::{Component, Linker},
+        component::{Component, InstancePre, Linker},
         Config, Engine, Store,
     };
 
-    use crate::runtime_view::RuntimeView;
+    use crate::component_cache::ComponentCache;
+    use crate::limits::GuestLimits;
+    use crate::runtime_view::{GuestEnvironment, RuntimeView};
+    use resource_providers::http::{
+        with_egress_policy, ClientMaker, EgressPolicy, HostClientMaker, HostStreamingClientMaker,
+        OutboundLimiter,
+    };
+    use resource_providers::providers::blob_store_fs::FsBlobRoot;
+    use resource_providers::providers::http_client_hyper::{
+        new_client_maker, new_streaming_client_maker, SharedHyperClient,
+    };
+    use resource_providers::providers::http_client_mock::{HttpRecorder, MockHttp};
+    use resource_providers::providers::kv_store_sled::SledKvNamespace;
+    use resource_providers::providers::sql_sqlite::SqliteDatabase;
     use wasmtime_wasi::preview2::command::add_to_linker;
 
+    /// How often the engine epoch is incremented.
+    ///
+    /// Guests yield back to the async runtime once per tick. This is what lets
+    /// a runaway guest be cancelled by a timeout rather than blocking its task forever.
+    pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
     lazy_static! {
         static ref ENGINE: wasmtime::Engine = {
             let mut cfg = Config::new();
             cfg.wasm_component_model(true);
             cfg.async_support(true);
-            Engine::new(&cfg).expect("Failed to create the wasmtime engine")
+            cfg.epoch_interruption(true);
+            let engine = Engine::new(&cfg).expect("Failed to create the wasmtime engine");
+            start_epoch_ticker(engine.clone());
+            engine
         };
+        static ref COMPONENT_CACHE: ComponentCache = ComponentCache::from_env();
+    }
+
+    fn start_epoch_ticker(engine: Engine) {
+        std::thread::Builder::new()
+            .name("wasmtime-epoch-ticker".into())
+            .spawn(move || loop {
+                std::thread::sleep(EPOCH_TICK);
+                engine.increment_epoch();
+            })
+            .expect("Failed to start the wasmtime epoch ticker");
     }
 
     // Produces new stores for guest components
@@ -66,10 +747,129 @@ pub mod runtime {
 
     pub type StoreProducer = BoxCloneService<(), Store<RuntimeView>, BoxError>;
 
-    pub fn make_store_producer() -> StoreProducer {
-        let maker = |_| async move {
-            let view = RuntimeView::new();
-            Ok(Store::new(&ENGINE, view))
+    /// Where guest outbound requests are sent
+    #[derive(Debug, Clone)]
+    pub enum OutboundHttp {
+        /// Sent with a shared, connection pooled client
+        Live(SharedHyperClient),
+        /// Answered from a fixture, nothing reaches the network
+        Replay(MockHttp),
+        /// Sent with a shared client and recorded to a fixture
+        Record(SharedHyperClient, HttpRecorder),
+    }
+
+    impl OutboundHttp {
+        /// Buffers at most `limit` bytes of each response body. Replayed responses aren't limited
+        pub fn with_max_response_bytes(&self, limit: u64) -> Self {
+            match self {
+                OutboundHttp::Live(client) => {
+                    OutboundHttp::Live(client.with_max_response_bytes(limit))
+                }
+                OutboundHttp::Record(client, recorder) => {
+                    OutboundHttp::Record(client.with_max_response_bytes(limit), recorder.clone())
+                }
+                OutboundHttp::Replay(mock) => OutboundHttp::Replay(mock.clone()),
+            }
+        }
+
+        pub fn client_maker(&self) -> HostClientMaker {
+            match self {
+                OutboundHttp::Live(client) => new_client_maker(client.clone()),
+                OutboundHttp::Replay(mock) => mock.client_maker(),
+                OutboundHttp::Record(client, recorder) => {
+                    recorder.apply(new_client_maker(client.clone()))
+                }
+            }
+        }
+
+        /// Clients which leave response bodies for guests to read as they arrive.
+        /// Replayed and recorded responses are always buffered, so there are none
+        pub fn streaming_client_maker(&self) -> Option<HostStreamingClientMaker> {
+            match self {
+                OutboundHttp::Live(client) => Some(new_streaming_client_maker(client.clone())),
+                OutboundHttp::Replay(_) | OutboundHttp::Record(..) => None,
+            }
+        }
+    }
+
+    /// The persistent storage belonging to a single component.
+    /// Guests using a kind of storage which is `None` get an error
+    #[derive(Debug, Clone, Default)]
+    pub struct GuestStorage {
+        pub kv: Option<SledKvNamespace>,
+        pub sql: Option<SqliteDatabase>,
+        pub blob: Option<FsBlobRoot>,
+    }
+
+    /// Checks requests from clients of `client_maker` against `egress_policy`,
+    /// then limits them with `outbound_limiter`
+    fn guard_outbound<R: 'static>(
+        client_maker: ClientMaker<R>,
+        egress_policy: &EgressPolicy,
+        outbound_limiter: &OutboundLimiter,
+    ) -> ClientMaker<R> {
+        outbound_limiter.apply(with_egress_policy(client_maker, egress_policy.clone()))
+    }
+
+    /// Every store produced shares `outbound_http`, and with it outbound connections.
+    /// Outbound requests are checked against `egress_policy` and limited by `outbound_limiter`,
+    /// which is shared by every store produced.
+    /// Guests are given the key value store, database and containers in `storage`,
+    /// so state outlives a single store, and see the settings in `environment`
+    pub fn make_store_producer(
+        limits: GuestLimits,
+        outbound_http: OutboundHttp,
+        egress_policy: EgressPolicy,
+        outbound_limiter: OutboundLimiter,
+        storage: GuestStorage,
+        environment: GuestEnvironment,
+    ) -> StoreProducer {
+        let maker = move |_| {
+            let limits = limits.clone();
+            let client_maker = guard_outbound(
+                outbound_http.client_maker(),
+                &egress_policy,
+                &outbound_limiter,
+            );
+            // `mycelia-alpha:http` clients share the policy and limits of `wasi:http` requests
+            let guest_client_maker = guard_outbound(
+                outbound_http.client_maker(),
+                &egress_policy,
+                &outbound_limiter,
+            );
+            let guest_streaming_client_maker =
+                outbound_http.streaming_client_maker().map(|client_maker| {
+                    guard_outbound(client_maker, &egress_policy, &outbound_limiter)
+                });
+            let kv_store_maker = storage.kv.as_ref().map(|kv| kv.store_maker());
+            let sql_connection_maker = storage.sql.as_ref().map(|sql| sql.connection_maker());
+            let blob_container_maker = storage.blob.as_ref().map(|blob| blob.container_maker());
+            let environment = environment.clone();
+            let secret_provider = environment.secrets.as_ref().map(|file| file.provider());
+            async move {
+                let mut view = RuntimeView::with_environment(limits, client_maker, &environment)
+                    .with_http_clients(guest_client_maker);
+                if let Some(client_maker) = guest_streaming_client_maker {
+                    view = view.with_streaming_http_clients(client_maker);
+                }
+                if let Some(secret_provider) = secret_provider {
+                    view = view.with_secrets(secret_provider, environment.granted_secrets.clone());
+                }
+                if let Some(kv_store_maker) = kv_store_maker {
+                    view = view.with_kv(kv_store_maker);
+                }
+                if let Some(sql_connection_maker) = sql_connection_maker {
+                    view = view.with_sql(sql_connection_maker);
+                }
+                if let Some(blob_container_maker) = blob_container_maker {
+                    view = view.with_blob(blob_container_maker);
+                }
+                let mut store = Store::new(&ENGINE, view);
+                store.limiter(|view| view.limiter());
+                // yield to the async runtime every epoch tick
+                store.epoch_deadline_async_yield_and_update(1);
+                Ok(store)
+            }
         };
 
         let svc = service_fn(maker);
@@ -80,15 +880,30 @@ pub mod runtime {
     pub fn new_linker() -> Linker<RuntimeView> {
         let mut linker = Linker::new(&ENGINE);
         let _ = add_to_linker(&mut linker).unwrap();
+        resource_providers::http::add_to_linker(&mut linker).unwrap();
+        resource_providers::kv::add_to_linker(&mut linker).unwrap();
+        resource_providers::sql::add_to_linker(&mut linker).unwrap();
+        resource_providers::blob::add_to_linker(&mut linker).unwrap();
+        resource_providers::config::add_to_linker(&mut linker).unwrap();
+        resource_providers::secrets::add_to_linker(&mut linker).unwrap();
+        resource_providers::logging::add_to_linker(&mut linker).unwrap();
         linker
     }
 
+    /// Links `component` once up front. The returned `InstancePre` can be cheaply
+    /// instantiated into many stores without re-resolving imports.
+    pub fn new_instance_pre(component: &Component) -> anyhow::Result<InstancePre<RuntimeView>> {
+        new_linker().instantiate_pre(component)
+    }
+
+    /// Loads a component from disk, reusing a precompiled artifact if one is cached
     pub fn new_component_from_path(path: PathBuf) -> anyhow::Result<Component> {
-        Component::from_file(&ENGINE, path)
+        let bytes = std::fs::read(path)?;
+        new_component_from_bytes(&bytes)
     }
 
     pub fn new_component_from_bytes(b: &[u8]) -> anyhow::Result<Component> {
-        Component::from_binary(&ENGINE, b)
+        COMPONENT_CACHE.load(&ENGINE, b)
     }
 }
 